@@ -0,0 +1,2500 @@
+use chrono;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use futures::future::LocalBoxFuture;
+use futures::stream::{self, LocalBoxStream};
+use futures::{FutureExt, StreamExt, TryFutureExt};
+use linear_map::LinearMap;
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::{thread_rng, Rng};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::db::{
+    activity_window_start, check_debt_limit, day_to_date, fold_for_search, month_end_timestamp,
+    notify_shaft_listener, personal_ledger, project_shaft, settlement, sort_users, split_shaft,
+    transactions_to_csv, validate_currency, validate_limit, validate_shaft, Attachment,
+    BalanceExtremes, BalanceMismatch, Currency, Database, DatabaseError, GithubId,
+    NamedTransaction, NettablePair, Page, PersonalLedger, ShaftListener, ShaftPreview,
+    ShaftRateLimit, SortOrder, SqliteDatabase, SystemStats, Token, TokenInfo, TokenScope,
+    Transaction, TransactionDetail, TransactionDirection, User, UserId, UserSort, UserSummary,
+    DEFAULT_MAX_CLOCK_SKEW_SECS, DEFAULT_MAX_LIMIT, DEFAULT_MAX_REASON_LENGTH,
+    DEFAULT_TOKEN_LIFETIME_SECS, GITHUB_PROVIDER, MAX_ATTACHMENTS_PER_TRANSACTION,
+    MAX_SEARCH_RESULTS, TOKEN_TOUCH_INTERVAL_SECS,
+};
+#[cfg(feature = "serde")]
+use crate::db::{
+    backup_from_json, backup_to_json, Backup, BackupAttachment, BackupIdentity, BackupTeam,
+    BackupToken, BackupTransaction, BackupUser,
+};
+
+/// An implementation of [Database] that keeps everything in memory, for
+/// testing code that uses a database without needing a real one.
+///
+/// This mirrors the semantics of [SqliteDatabase]. Safe to clone as the
+/// state will be shared.
+#[derive(Clone)]
+pub struct InMemoryDatabase {
+    state: Arc<Mutex<State>>,
+    /// How far in the future a new transaction's time may be.
+    max_clock_skew: chrono::Duration,
+    /// How long new access tokens are valid for.
+    token_lifetime: chrono::Duration,
+    /// The largest amount a single shaft may be for, if any.
+    max_amount: Option<i64>,
+    /// The longest reason a shaft may have, in characters.
+    max_reason_length: usize,
+    /// The most rows a query may be asked to return.
+    max_limit: u32,
+    /// Whether display names must be unique.
+    require_unique_display_name: bool,
+    /// How often each user may shaft, if limited.
+    shaft_rate_limit: Option<ShaftRateLimit>,
+    /// The most a user may owe, if limited.
+    max_debt: Option<i64>,
+    /// Called with each newly committed shaft.
+    shaft_listener: Option<ShaftListener>,
+}
+
+/// The tables of an [InMemoryDatabase].
+#[derive(Clone, Default)]
+struct State {
+    /// Map from user ID to user.
+    users: HashMap<String, StoredUser>,
+    /// Map from identity provider and the user's ID with it to user ID.
+    identities: HashMap<(String, String), String>,
+    /// Map from token to its details.
+    tokens: HashMap<String, StoredToken>,
+    /// All transactions, including deleted ones, in ID order.
+    transactions: Vec<StoredTransaction>,
+    /// Map from user ID to team.
+    user_teams: HashMap<String, String>,
+    /// All attachments, in ID order.
+    attachments: Vec<Attachment>,
+    /// The install's currency.
+    currency: Currency,
+}
+
+#[derive(Clone)]
+struct StoredUser {
+    display_name: String,
+    created_at: Option<i64>,
+    display_name_overridden: bool,
+    active: bool,
+    version: i32,
+}
+
+#[derive(Clone)]
+struct StoredToken {
+    user_id: String,
+    scope: TokenScope,
+    created_at: i64,
+    last_used_at: Option<i64>,
+    expires_at: Option<i64>,
+}
+
+#[derive(Clone)]
+struct StoredTransaction {
+    transaction: Transaction,
+    deleted_at: Option<i64>,
+    reversed_transaction_id: Option<i64>,
+}
+
+impl Default for InMemoryDatabase {
+    fn default() -> InMemoryDatabase {
+        InMemoryDatabase::new()
+    }
+}
+
+impl InMemoryDatabase {
+    /// Create a new, empty, instance.
+    pub fn new() -> InMemoryDatabase {
+        InMemoryDatabase {
+            state: Arc::new(Mutex::new(State::default())),
+            max_clock_skew: chrono::Duration::seconds(DEFAULT_MAX_CLOCK_SKEW_SECS),
+            token_lifetime: chrono::Duration::seconds(DEFAULT_TOKEN_LIFETIME_SECS),
+            max_amount: None,
+            max_reason_length: DEFAULT_MAX_REASON_LENGTH,
+            max_limit: DEFAULT_MAX_LIMIT,
+            require_unique_display_name: false,
+            shaft_rate_limit: None,
+            max_debt: None,
+            shaft_listener: None,
+        }
+    }
+
+    /// Set how far in the future a new transaction's time may be. Defaults to
+    /// five minutes.
+    pub fn with_max_clock_skew(mut self, max_clock_skew: chrono::Duration) -> InMemoryDatabase {
+        self.max_clock_skew = max_clock_skew;
+        self
+    }
+
+    /// Set the largest amount a single shaft may be for, c.f.
+    /// [PoolConfig::max_amount](crate::db::PoolConfig::max_amount). Defaults
+    /// to no limit.
+    pub fn with_max_amount(mut self, max_amount: Option<i64>) -> InMemoryDatabase {
+        self.max_amount = max_amount;
+        self
+    }
+
+    /// Set the longest reason a shaft may have, in characters, c.f.
+    /// [PoolConfig::max_reason_length](crate::db::PoolConfig::max_reason_length).
+    /// Defaults to 500.
+    pub fn with_max_reason_length(mut self, max_reason_length: usize) -> InMemoryDatabase {
+        self.max_reason_length = max_reason_length;
+        self
+    }
+
+    /// Set the most rows a query may be asked for, c.f.
+    /// [PoolConfig::max_limit](crate::db::PoolConfig::max_limit). Defaults to
+    /// 1000.
+    pub fn with_max_limit(mut self, max_limit: u32) -> InMemoryDatabase {
+        self.max_limit = max_limit;
+        self
+    }
+
+    /// Set whether display names must be unique, c.f.
+    /// [PoolConfig::require_unique_display_name](crate::db::PoolConfig::require_unique_display_name).
+    /// Defaults to false.
+    pub fn with_require_unique_display_name(mut self, require: bool) -> InMemoryDatabase {
+        self.require_unique_display_name = require;
+        self
+    }
+
+    /// Set how often each user may shaft, c.f.
+    /// [PoolConfig::shaft_rate_limit](crate::db::PoolConfig::shaft_rate_limit).
+    /// Defaults to no limit.
+    pub fn with_shaft_rate_limit(
+        mut self,
+        shaft_rate_limit: Option<ShaftRateLimit>,
+    ) -> InMemoryDatabase {
+        self.shaft_rate_limit = shaft_rate_limit;
+        self
+    }
+
+    /// Set the most a user may owe, c.f.
+    /// [PoolConfig::max_debt](crate::db::PoolConfig::max_debt). Defaults to
+    /// no limit.
+    pub fn with_max_debt(mut self, max_debt: Option<i64>) -> InMemoryDatabase {
+        self.max_debt = max_debt;
+        self
+    }
+
+    /// Set a callback to run with each transaction committed by
+    /// [Database::shaft_user], c.f. [SqliteDatabase::with_shaft_listener].
+    /// Unlike there, the callback runs on the thread that polls the future.
+    pub fn with_shaft_listener(mut self, listener: ShaftListener) -> InMemoryDatabase {
+        self.shaft_listener = Some(listener);
+        self
+    }
+
+    /// Set how long new access tokens are valid for. Defaults to 30 days.
+    pub fn with_token_lifetime(mut self, token_lifetime: chrono::Duration) -> InMemoryDatabase {
+        self.token_lifetime = token_lifetime;
+        self
+    }
+
+    /// Put a user in a team, replacing any previous team. There's no
+    /// [Database] method for this as teams are managed outside the app.
+    pub fn set_user_team(&self, user_id: UserId, team: String) {
+        self.lock().user_teams.insert(user_id.0, team);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("in-memory database lock poisoned")
+    }
+
+    /// Run an operation against the state, returning an already completed
+    /// future.
+    fn run<F, T>(&self, f: F) -> LocalBoxFuture<'static, Result<T, DatabaseError>>
+    where
+        F: FnOnce(&mut State) -> Result<T, DatabaseError>,
+        T: Send + 'static,
+    {
+        let result = f(&mut self.lock());
+        futures::future::ready(result).boxed()
+    }
+}
+
+impl State {
+    /// Iterate over all transactions that haven't been deleted.
+    fn live(&self) -> impl DoubleEndedIterator<Item = &StoredTransaction> {
+        self.transactions
+            .iter()
+            .filter(|stored| stored.deleted_at.is_none())
+    }
+
+    /// Like [State::live], but if `exclude_reversed` is set then also filters
+    /// out both reversals and the transactions they reverse.
+    fn counted(&self, exclude_reversed: bool) -> impl Iterator<Item = &Transaction> {
+        let reversed: HashSet<i64> = if exclude_reversed {
+            self.live()
+                .filter_map(|stored| stored.reversed_transaction_id)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        self.live()
+            .filter(move |stored| {
+                !exclude_reversed
+                    || (stored.reversed_transaction_id.is_none()
+                        && !reversed.contains(&stored.id()))
+            })
+            .map(|stored| &stored.transaction)
+    }
+
+    /// Find a live transaction by ID.
+    fn live_transaction(&self, id: i64) -> Option<&StoredTransaction> {
+        self.live().find(|stored| stored.id() == id)
+    }
+
+    /// Add the display names of the users involved to a transaction,
+    /// falling back to their IDs for users that no longer exist.
+    fn named(&self, transaction: &Transaction) -> NamedTransaction {
+        let display_name = |user_id: &String| {
+            self.users
+                .get(user_id)
+                .map_or_else(|| user_id.clone(), |user| user.display_name.clone())
+        };
+
+        NamedTransaction {
+            shafter_display_name: display_name(&transaction.shafter),
+            shaftee_display_name: display_name(&transaction.shaftee),
+            transaction: transaction.clone(),
+        }
+    }
+
+    /// Get the balance of every user with transactions.
+    fn balances(&self) -> HashMap<String, i64> {
+        let mut balances = HashMap::new();
+        for stored in self.live() {
+            let transaction = &stored.transaction;
+            *balances.entry(transaction.shafter.clone()).or_insert(0) += transaction.amount;
+            *balances.entry(transaction.shaftee.clone()).or_insert(0) -= transaction.amount;
+        }
+        balances
+    }
+
+    /// Get a user's balance counting only transactions up to `at`, if given,
+    /// erroring rather than overflowing like SQLite does.
+    fn balance_of(&self, user_id: &UserId, at: Option<i64>) -> Result<i64, DatabaseError> {
+        let overflow = || DatabaseError::BalanceOverflow {
+            user_id: user_id.to_string(),
+        };
+
+        let (mut credit, mut debit) = (0i64, 0i64);
+        for stored in self.live() {
+            let transaction = &stored.transaction;
+            if at.is_some_and(|at| transaction.datetime.timestamp() > at) {
+                continue;
+            }
+
+            if transaction.shafter == user_id.as_str() {
+                credit = credit
+                    .checked_add(transaction.amount)
+                    .ok_or_else(overflow)?;
+            }
+            if transaction.shaftee == user_id.as_str() {
+                debit = debit.checked_add(transaction.amount).ok_or_else(overflow)?;
+            }
+        }
+
+        credit.checked_sub(debit).ok_or_else(overflow)
+    }
+
+    /// Get all users who haven't been part of a transaction since `cutoff`,
+    /// in ID order. If `count_registration` is set then users with no
+    /// transactions count as active from when they registered, otherwise
+    /// they're always included.
+    fn inactive_users(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+        count_registration: bool,
+    ) -> Vec<User> {
+        let mut last_active: HashMap<&str, i64> = HashMap::new();
+        for stored in self.live() {
+            let transaction = &stored.transaction;
+            let time_sec = transaction.datetime.timestamp();
+            for user_id in &[&transaction.shafter, &transaction.shaftee] {
+                let last = last_active.entry(user_id.as_str()).or_insert(time_sec);
+                *last = (*last).max(time_sec);
+            }
+        }
+
+        let mut users: Vec<User> = self
+            .users_with_balances()
+            .into_iter()
+            .filter(|user| match last_active.get(user.user_id.as_str()) {
+                Some(&active) => active < cutoff.timestamp(),
+                None if count_registration => {
+                    self.users[&user.user_id].created_at.unwrap_or(0) < cutoff.timestamp()
+                }
+                None => true,
+            })
+            .collect();
+        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+        users
+    }
+
+    /// Get all users along with their balances, in no particular order.
+    fn users_with_balances(&self) -> Vec<User> {
+        let balances = self.balances();
+
+        self.users
+            .iter()
+            .map(|(user_id, user)| {
+                User::from(user_id.clone())
+                    .with_display_name(user.display_name.clone())
+                    .with_balance(balances.get(user_id).copied().unwrap_or(0))
+                    .with_created_at(chrono::Utc.timestamp(user.created_at.unwrap_or(0), 0))
+                    .with_version(user.version)
+            })
+            .collect()
+    }
+
+    /// Check that no user other than `user_id` has the given display name,
+    /// ignoring ASCII case like SQLite's `NOCASE`.
+    fn check_display_name_unique(
+        &self,
+        user_id: &str,
+        display_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let taken = self.users.iter().any(|(other_id, user)| {
+            other_id != user_id && user.display_name.eq_ignore_ascii_case(display_name)
+        });
+
+        if taken {
+            return Err(DatabaseError::Conflict {
+                constraint: "users.display_name".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Get the sum of all positive balances, see
+    /// [Database::get_total_outstanding].
+    fn total_outstanding(&self) -> i64 {
+        self.users_with_balances()
+            .iter()
+            .map(|user| user.balance)
+            .filter(|balance| *balance > 0)
+            .sum()
+    }
+
+    /// Like [State::users_with_balances], but excluding deactivated users
+    /// unless `include_inactive` is set.
+    fn users_with_balances_filtered(&self, include_inactive: bool) -> Vec<User> {
+        let mut users = self.users_with_balances();
+        if !include_inactive {
+            users.retain(|user| self.users[&user.user_id].active);
+        }
+        users
+    }
+
+    /// Get the net balance between two users, see
+    /// [Database::get_balance_between].
+    fn balance_between(&self, user_a: &str, user_b: &str) -> Result<i64, DatabaseError> {
+        for user_id in &[user_a, user_b] {
+            if !self.users.contains_key(*user_id) {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: user_id.to_string(),
+                });
+            }
+        }
+
+        Ok(self
+            .live()
+            .map(|stored| &stored.transaction)
+            .map(|transaction| {
+                if transaction.shafter == user_a && transaction.shaftee == user_b {
+                    transaction.amount
+                } else if transaction.shafter == user_b && transaction.shaftee == user_a {
+                    -transaction.amount
+                } else {
+                    0
+                }
+            })
+            .sum())
+    }
+
+    /// Work out the balances committing the transaction would leave its
+    /// parties with, like SQLite's `preview_shaft`.
+    fn preview_shaft(
+        &self,
+        transaction: &Transaction,
+        max_debt: Option<i64>,
+    ) -> Result<ShaftPreview, DatabaseError> {
+        if !self.users.contains_key(&transaction.shaftee) {
+            return Err(DatabaseError::UnknownUser {
+                user_id: transaction.shaftee.clone(),
+            });
+        }
+
+        let resubmitted = transaction.idempotency_key.is_some()
+            && self
+                .transactions
+                .iter()
+                .any(|stored| stored.transaction.idempotency_key == transaction.idempotency_key);
+        let amount = if resubmitted { 0 } else { transaction.amount };
+
+        let shafter_balance = self.balance_of(&UserId::from(transaction.shafter.as_str()), None)?;
+        let shaftee_balance = self.balance_of(&UserId::from(transaction.shaftee.as_str()), None)?;
+
+        let preview = project_shaft(transaction, shafter_balance, shaftee_balance, amount)?;
+        if !resubmitted {
+            check_debt_limit(transaction, preview.shaftee_balance_after, max_debt)?;
+        }
+
+        Ok(preview)
+    }
+
+    /// Check a new shaft against the rate limit, debt limit and for
+    /// overflow, like SQLite's `check_shaft_limits`. Earlier shafts in a
+    /// batch must already be inserted so that they count too.
+    fn check_shaft_limits(
+        &self,
+        transaction: &Transaction,
+        rate_limit: Option<ShaftRateLimit>,
+        max_debt: Option<i64>,
+    ) -> Result<ShaftPreview, DatabaseError> {
+        if let Some(rate_limit) = rate_limit {
+            self.check_rate_limit(&transaction.shafter, rate_limit)?;
+        }
+
+        self.preview_shaft(transaction, max_debt)
+    }
+
+    /// Check that `shafter` hasn't already made the most shafts allowed in
+    /// the trailing window, erroring with [DatabaseError::RateLimited]
+    /// otherwise.
+    fn check_rate_limit(
+        &self,
+        shafter: &str,
+        rate_limit: ShaftRateLimit,
+    ) -> Result<(), DatabaseError> {
+        let window = rate_limit.window.as_secs() as i64;
+        let now = chrono::Utc::now().timestamp();
+
+        let max = rate_limit.max_shafts_per_window as usize;
+        if max == 0 {
+            return Err(DatabaseError::RateLimited {
+                retry_after_secs: window,
+            });
+        }
+
+        let mut times: Vec<i64> = self
+            .transactions
+            .iter()
+            .map(|stored| &stored.transaction)
+            .filter(|transaction| transaction.shafter == shafter)
+            .map(|transaction| transaction.datetime.timestamp())
+            .filter(|time| *time >= now - window)
+            .collect();
+        times.sort_unstable_by(|a, b| b.cmp(a));
+
+        match times.get(max - 1) {
+            Some(oldest) => Err(DatabaseError::RateLimited {
+                retry_after_secs: (oldest + window - now).max(1),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Insert a new transaction, checking that the shaftee exists. Returns
+    /// the new transaction's ID, or `None` if it was a resubmission and so
+    /// skipped.
+    fn insert_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<Option<i64>, DatabaseError> {
+        if !self.users.contains_key(&transaction.shaftee) {
+            return Err(DatabaseError::UnknownUser {
+                user_id: transaction.shaftee,
+            });
+        }
+
+        Ok(self.append_transaction(transaction))
+    }
+
+    /// Store a transaction without checking the shaftee exists, returning
+    /// `None` if it was skipped as a resubmission.
+    fn append_transaction(&mut self, transaction: Transaction) -> Option<i64> {
+        // Like SQLite's unique index, this includes deleted transactions.
+        if let Some(key) = &transaction.idempotency_key {
+            let exists = self
+                .transactions
+                .iter()
+                .any(|stored| stored.transaction.idempotency_key.as_ref() == Some(key));
+            if exists {
+                return None;
+            }
+        }
+
+        let id = self.transactions.last().map_or(1, |stored| stored.id() + 1);
+
+        self.transactions.push(StoredTransaction {
+            transaction: Transaction {
+                id: Some(id),
+                // Times are stored to the second.
+                datetime: chrono::Utc.timestamp(transaction.datetime.timestamp(), 0),
+                ..transaction
+            },
+            deleted_at: None,
+            reversed_transaction_id: None,
+        });
+
+        Some(id)
+    }
+}
+
+impl StoredTransaction {
+    fn id(&self) -> i64 {
+        self.transaction.id.expect("stored transactions have IDs")
+    }
+}
+
+impl Database for InMemoryDatabase {
+    fn get_user_by_github_id(
+        &self,
+        github_user_id: GithubId,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>> {
+        self.get_user_by_identity(GITHUB_PROVIDER.to_string(), github_user_id.0)
+    }
+
+    fn add_user_by_github_id(
+        &self,
+        github_user_id: GithubId,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<UserId, DatabaseError>> {
+        let require_unique_display_name = self.require_unique_display_name;
+
+        self.run(move |state| {
+            // The Github account may already be linked to a user, otherwise
+            // new users' IDs are their Github logins.
+            let identity = (GITHUB_PROVIDER.to_string(), github_user_id.0.clone());
+            let user_id = state
+                .identities
+                .get(&identity)
+                .cloned()
+                .unwrap_or(github_user_id.0);
+
+            // Existing users who've chosen their own name keep it, so there's
+            // nothing to check.
+            let overridden = state
+                .users
+                .get(&user_id)
+                .is_some_and(|user| user.display_name_overridden);
+            if require_unique_display_name && !overridden {
+                state.check_display_name_unique(&user_id, &display_name)?;
+            }
+
+            state.identities.insert(identity, user_id.clone());
+
+            let user = state
+                .users
+                .entry(user_id.clone())
+                .or_insert_with(|| StoredUser {
+                    display_name: display_name.clone(),
+                    created_at: Some(chrono::Utc::now().timestamp()),
+                    display_name_overridden: false,
+                    active: true,
+                    version: 0,
+                });
+
+            if !user.display_name_overridden && user.display_name != display_name {
+                user.display_name = display_name;
+                user.version += 1;
+            }
+
+            Ok(UserId(user_id))
+        })
+    }
+
+    fn create_token_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>> {
+        self.create_token_for_user_with_scope(user_id, TokenScope::Write)
+    }
+
+    fn create_token_for_user_with_scope(
+        &self,
+        user_id: UserId,
+        scope: TokenScope,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>> {
+        let created_at = chrono::Utc::now();
+        let expires_at = created_at + self.token_lifetime;
+
+        self.run(move |state| {
+            let token: String = OsRng
+                .sample_iter(&Alphanumeric)
+                .take(SqliteDatabase::TOKEN_LENGTH)
+                .collect();
+
+            state.tokens.insert(
+                token.clone(),
+                StoredToken {
+                    user_id: user_id.0,
+                    scope,
+                    created_at: created_at.timestamp(),
+                    last_used_at: None,
+                    expires_at: Some(expires_at.timestamp()),
+                },
+            );
+
+            Ok(Token(token))
+        })
+    }
+
+    fn delete_token(&self, token: Token) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            state.tokens.remove(token.as_str());
+            Ok(())
+        })
+    }
+
+    fn get_user_from_token(
+        &self,
+        token: Token,
+    ) -> LocalBoxFuture<'static, Result<Option<(User, TokenScope)>, DatabaseError>> {
+        self.run(move |state| {
+            let now = chrono::Utc::now().timestamp();
+
+            let stored = match state.tokens.get(token.as_str()) {
+                Some(stored) if stored.expires_at.is_none_or(|expires| expires > now) => stored,
+                _ => return Ok(None),
+            };
+
+            let user = match state.users.get(&stored.user_id) {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+
+            let user = User::from(stored.user_id.clone())
+                .with_display_name(user.display_name.clone())
+                .with_balance(state.balances().get(&stored.user_id).copied().unwrap_or(0))
+                .with_created_at(chrono::Utc.timestamp(user.created_at.unwrap_or(0), 0))
+                .with_version(user.version);
+
+            Ok(Some((user, stored.scope)))
+        })
+    }
+
+    fn get_balance_for_user(
+        &self,
+        user: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| {
+            if !state.users.contains_key(user.as_str()) {
+                return Err(DatabaseError::UnknownUser { user_id: user.0 });
+            }
+
+            state.balance_of(&user, None)
+        })
+    }
+
+    fn get_all_users(
+        &self,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        self.get_all_users_ordered(SortOrder::Asc, include_inactive)
+    }
+
+    fn get_all_users_ordered(
+        &self,
+        order: SortOrder,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        let sort = match order {
+            SortOrder::Asc => UserSort::BalanceAsc,
+            SortOrder::Desc => UserSort::BalanceDesc,
+        };
+
+        self.get_all_users_sorted(sort, include_inactive)
+            .map_ok(|users| {
+                users
+                    .into_iter()
+                    .map(|user| (user.user_id.clone(), user))
+                    .collect()
+            })
+            .boxed_local()
+    }
+
+    fn get_all_users_sorted(
+        &self,
+        sort: UserSort,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        self.run(move |state| {
+            let mut users = state.users_with_balances_filtered(include_inactive);
+            sort_users(&mut users, sort);
+
+            Ok(users)
+        })
+    }
+
+    fn shaft_user(
+        &self,
+        transaction: Transaction,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+        let shaft_listener = self.shaft_listener.clone();
+
+        self.run(move |state| {
+            validate_shaft(&transaction, max_clock_skew, max_amount, max_reason_length)?;
+            state.check_shaft_limits(&transaction, shaft_rate_limit, max_debt)?;
+            let id = state.insert_transaction(transaction)?;
+
+            Ok(id
+                .and_then(|id| state.live_transaction(id))
+                .map(|stored| stored.transaction.clone()))
+        })
+        // Notify outside the lock, so the listener can use the database.
+        .map_ok(move |inserted| {
+            if let Some(transaction) = inserted {
+                notify_shaft_listener(shaft_listener.as_ref(), &transaction);
+            }
+        })
+        .boxed_local()
+    }
+
+    fn record_historical_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| state.insert_transaction(transaction).map(|_| ()))
+    }
+
+    fn get_last_transactions(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        self.get_transactions_page(limit, 0)
+    }
+
+    fn get_transactions_for_user(
+        &self,
+        user_id: UserId,
+        direction: TransactionDirection,
+        before: Option<i64>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            let user_id = user_id.as_str();
+
+            let items = state
+                .live()
+                .rev()
+                .filter(|stored| before.is_none_or(|before| stored.id() < before))
+                .map(|stored| &stored.transaction)
+                .filter(|transaction| in_direction(transaction, user_id, direction))
+                .take(limit as usize)
+                .cloned()
+                .collect();
+
+            Ok(Page::from_keyset(items, limit, None))
+        })
+    }
+
+    fn get_transactions_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .rev()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .map(|stored| stored.transaction.clone())
+                .collect())
+        })
+    }
+
+    fn get_transaction_feed(
+        &self,
+        before: Option<i64>,
+        limit: u32,
+        with_total: bool,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            let items: Vec<Transaction> = state
+                .live()
+                .rev()
+                .filter(|stored| before.is_none_or(|before| stored.id() < before))
+                .take(limit as usize)
+                .map(|stored| stored.transaction.clone())
+                .collect();
+
+            let total = if with_total {
+                Some(state.live().count() as i64)
+            } else {
+                None
+            };
+
+            Ok(Page::from_keyset(items, limit, total))
+        })
+    }
+
+    fn get_shaft_counts(
+        &self,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        self.run(move |state| {
+            let mut counts: BTreeMap<String, i64> = BTreeMap::new();
+            for transaction in state.counted(exclude_reversed) {
+                *counts.entry(transaction.shaftee.clone()).or_insert(0) += 1;
+            }
+
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by_key(|(_, count)| Reverse(*count));
+
+            Ok(counts.into_iter().collect())
+        })
+    }
+
+    fn purge_user_data(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            let mut user = match state.users.remove(user_id.as_str()) {
+                Some(user) => user,
+                None => return Err(DatabaseError::UnknownUser { user_id: user_id.0 }),
+            };
+
+            let anon_id: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+            let anon_id = format!("deleted-{}", anon_id);
+
+            user.display_name = "Deleted user".to_string();
+            user.version += 1;
+            state.users.insert(anon_id.clone(), user);
+
+            let user_id = user_id.0;
+
+            state.tokens.retain(|_, token| token.user_id != user_id);
+            state.identities.retain(|_, id| *id != user_id);
+
+            if let Some(team) = state.user_teams.remove(&user_id) {
+                state.user_teams.insert(anon_id.clone(), team);
+            }
+
+            let purged: HashSet<i64> = state
+                .transactions
+                .iter()
+                .filter(|stored| {
+                    stored.transaction.shafter == user_id || stored.transaction.shaftee == user_id
+                })
+                .map(|stored| stored.id())
+                .collect();
+            state
+                .attachments
+                .retain(|attachment| !purged.contains(&attachment.transaction_id));
+
+            for stored in &mut state.transactions {
+                let transaction = &mut stored.transaction;
+                if transaction.shafter == user_id {
+                    transaction.shafter = anon_id.clone();
+                    transaction.reason = None;
+                }
+                if transaction.shaftee == user_id {
+                    transaction.shaftee = anon_id.clone();
+                    transaction.reason = None;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_total_shafted(
+        &self,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .counted(exclude_reversed)
+                .map(|transaction| transaction.amount)
+                .sum())
+        })
+    }
+
+    fn get_user_rank(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<i64>, DatabaseError>> {
+        self.run(move |state| {
+            let users = state.users_with_balances();
+
+            let balance = match users.iter().find(|user| user.user_id == user_id.as_str()) {
+                Some(user) => user.balance,
+                None => return Ok(None),
+            };
+
+            let lower = users.iter().filter(|user| user.balance < balance).count();
+
+            Ok(Some(lower as i64 + 1))
+        })
+    }
+
+    fn get_balances_for_users(
+        &self,
+        user_ids: Vec<UserId>,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        self.run(move |state| {
+            let balances = state.balances();
+
+            Ok(user_ids
+                .into_iter()
+                .filter(|user_id| state.users.contains_key(user_id.as_str()))
+                .map(|user_id| {
+                    let balance = balances.get(user_id.as_str()).copied().unwrap_or(0);
+                    (user_id.0, balance)
+                })
+                .collect())
+        })
+    }
+
+    fn get_inactive_users_since(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        self.run(move |state| Ok(state.inactive_users(cutoff, true)))
+    }
+
+    fn get_inactive_users(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        self.run(move |state| Ok(state.inactive_users(since, false)))
+    }
+
+    fn sync_display_name_from_github(
+        &self,
+        github_user_id: GithubId,
+        new_display_name: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            let key = (GITHUB_PROVIDER.to_string(), github_user_id.0.clone());
+            let user_id = match state.identities.get(&key) {
+                Some(user_id) => user_id,
+                None => {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: github_user_id.0,
+                    })
+                }
+            };
+
+            if let Some(user) = state.users.get_mut(user_id) {
+                if !user.display_name_overridden && user.display_name != new_display_name {
+                    user.display_name = new_display_name;
+                    user.version += 1;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_transaction_detail(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<TransactionDetail>, DatabaseError>> {
+        self.run(move |state| {
+            let transaction = match state.live_transaction(id) {
+                Some(stored) => stored.transaction.clone(),
+                None => return Ok(None),
+            };
+
+            let balances = state.balances();
+            let display_name = |user_id: &String| {
+                state
+                    .users
+                    .get(user_id)
+                    .map_or_else(|| user_id.clone(), |user| user.display_name.clone())
+            };
+
+            Ok(Some(TransactionDetail {
+                shafter_display_name: display_name(&transaction.shafter),
+                shaftee_display_name: display_name(&transaction.shaftee),
+                shafter_balance: balances.get(&transaction.shafter).copied().unwrap_or(0),
+                shaftee_balance: balances.get(&transaction.shaftee).copied().unwrap_or(0),
+                transaction,
+            }))
+        })
+    }
+
+    fn soft_delete_transaction(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            match state
+                .transactions
+                .iter_mut()
+                .find(|stored| stored.id() == id && stored.deleted_at.is_none())
+            {
+                Some(stored) => {
+                    stored.deleted_at = Some(chrono::Utc::now().timestamp());
+                    Ok(())
+                }
+                None => Err(DatabaseError::UnknownTransaction { id }),
+            }
+        })
+    }
+
+    fn restore_transaction(&self, id: i64) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            match state
+                .transactions
+                .iter_mut()
+                .find(|stored| stored.id() == id && stored.deleted_at.is_some())
+            {
+                Some(stored) => {
+                    stored.deleted_at = None;
+                    Ok(())
+                }
+                None => Err(DatabaseError::UnknownTransaction { id }),
+            }
+        })
+    }
+
+    fn get_month_end_balances(
+        &self,
+        year: i32,
+        month: u32,
+        tz: Tz,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        self.run(move |state| {
+            let month_end = month_end_timestamp(year, month, tz)?;
+
+            let mut balances: BTreeMap<String, i64> = state
+                .users
+                .keys()
+                .map(|user_id| (user_id.clone(), 0))
+                .collect();
+
+            for stored in state.live() {
+                let transaction = &stored.transaction;
+                if transaction.datetime.timestamp() >= month_end {
+                    continue;
+                }
+
+                if let Some(balance) = balances.get_mut(&transaction.shafter) {
+                    *balance += transaction.amount;
+                }
+                if let Some(balance) = balances.get_mut(&transaction.shaftee) {
+                    *balance -= transaction.amount;
+                }
+            }
+
+            Ok(balances.into_iter().collect())
+        })
+    }
+
+    fn touch_token(&self, token: Token) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            let now = chrono::Utc::now().timestamp();
+
+            if let Some(stored) = state.tokens.get_mut(token.as_str()) {
+                if stored
+                    .last_used_at
+                    .is_none_or(|last| last <= now - TOKEN_TOUCH_INTERVAL_SECS)
+                {
+                    stored.last_used_at = Some(now);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_first_transaction(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        self.run(move |state| Ok(state.live().next().map(|stored| stored.transaction.clone())))
+    }
+
+    fn add_attachment(
+        &self,
+        transaction_id: i64,
+        url: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| {
+            if url.trim().is_empty() {
+                return Err(DatabaseError::EmptyAttachmentUrl);
+            }
+
+            if state.live_transaction(transaction_id).is_none() {
+                return Err(DatabaseError::UnknownTransaction { id: transaction_id });
+            }
+
+            let count = state
+                .attachments
+                .iter()
+                .filter(|attachment| attachment.transaction_id == transaction_id)
+                .count();
+
+            if count >= MAX_ATTACHMENTS_PER_TRANSACTION {
+                return Err(DatabaseError::TooManyAttachments {
+                    transaction_id,
+                    max: MAX_ATTACHMENTS_PER_TRANSACTION,
+                });
+            }
+
+            let id = state
+                .attachments
+                .last()
+                .map_or(1, |attachment| attachment.id + 1);
+
+            state.attachments.push(Attachment {
+                id,
+                transaction_id,
+                url,
+                uploaded_at: chrono::Utc.timestamp(chrono::Utc::now().timestamp(), 0),
+            });
+
+            Ok(id)
+        })
+    }
+
+    fn get_attachments(
+        &self,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Attachment>, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .attachments
+                .iter()
+                .filter(|attachment| attachment.transaction_id == transaction_id)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn get_most_active_users(
+        &self,
+        limit: u32,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            let mut counts: BTreeMap<String, i64> = BTreeMap::new();
+            for transaction in state.counted(exclude_reversed) {
+                *counts.entry(transaction.shafter.clone()).or_insert(0) += 1;
+                *counts.entry(transaction.shaftee.clone()).or_insert(0) += 1;
+            }
+
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by_key(|(_, count)| Reverse(*count));
+
+            Ok(counts.into_iter().take(limit as usize).collect())
+        })
+    }
+
+    fn get_user_by_display_name(
+        &self,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>> {
+        self.run(move |state| {
+            // Like SQLite's NOCASE, only ASCII case is ignored.
+            Ok(state
+                .users_with_balances()
+                .into_iter()
+                .filter(|user| user.display_name.eq_ignore_ascii_case(&display_name))
+                .min_by(|a, b| a.user_id.cmp(&b.user_id)))
+        })
+    }
+
+    fn get_team_balances(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        self.run(move |state| {
+            let balances = state.balances();
+
+            let mut team_balances: BTreeMap<String, i64> = BTreeMap::new();
+            for (user_id, team) in &state.user_teams {
+                *team_balances.entry(team.clone()).or_insert(0) +=
+                    balances.get(user_id).copied().unwrap_or(0);
+            }
+
+            let mut team_balances: Vec<_> = team_balances.into_iter().collect();
+            team_balances.sort_by_key(|(_, balance)| *balance);
+
+            Ok(team_balances.into_iter().collect())
+        })
+    }
+
+    fn shaft_users(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+
+        self.run(move |state| {
+            for transaction in &transactions {
+                validate_shaft(transaction, max_clock_skew, max_amount, max_reason_length)?;
+            }
+
+            // Work on a copy so that nothing is committed if any fail.
+            let mut staged = state.clone();
+            for transaction in transactions {
+                staged.check_shaft_limits(&transaction, shaft_rate_limit, max_debt)?;
+                staged.insert_transaction(transaction)?;
+            }
+            *state = staged;
+
+            Ok(())
+        })
+    }
+
+    fn try_shaft_users(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> LocalBoxFuture<'static, Result<Vec<Result<(), DatabaseError>>, DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+
+        self.run(move |state| {
+            Ok(transactions
+                .into_iter()
+                .map(|transaction| {
+                    validate_shaft(&transaction, max_clock_skew, max_amount, max_reason_length)?;
+                    state.check_shaft_limits(&transaction, shaft_rate_limit, max_debt)?;
+                    state.insert_transaction(transaction).map(|_| ())
+                })
+                .collect())
+        })
+    }
+
+    fn get_nettable_pairs(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Vec<NettablePair>, DatabaseError>> {
+        self.run(move |state| {
+            let mut directed: BTreeMap<(String, String), i64> = BTreeMap::new();
+            for stored in state.live() {
+                let transaction = &stored.transaction;
+                *directed
+                    .entry((transaction.shafter.clone(), transaction.shaftee.clone()))
+                    .or_insert(0) += transaction.amount;
+            }
+
+            Ok(directed
+                .iter()
+                .filter(|((a, b), _)| a < b)
+                .filter_map(|((a, b), &a_to_b)| {
+                    let b_to_a = directed.get(&(b.clone(), a.clone())).copied()?;
+                    if a_to_b != 0 && b_to_a != 0 {
+                        Some((a.clone(), b.clone(), a_to_b, b_to_a))
+                    } else {
+                        None
+                    }
+                })
+                .collect())
+        })
+    }
+
+    fn get_balance_extremes(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<BalanceExtremes, DatabaseError>> {
+        self.run(move |state| {
+            let users = state.users_with_balances();
+
+            let max = users
+                .iter()
+                .min_by(|a, b| {
+                    b.balance
+                        .cmp(&a.balance)
+                        .then_with(|| a.user_id.cmp(&b.user_id))
+                })
+                .cloned();
+            let min = users
+                .iter()
+                .min_by(|a, b| {
+                    a.balance
+                        .cmp(&b.balance)
+                        .then_with(|| a.user_id.cmp(&b.user_id))
+                })
+                .cloned();
+
+            Ok((max, min))
+        })
+    }
+
+    fn delete_transaction(&self, id: i64) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.soft_delete_transaction(id)
+    }
+
+    fn set_display_name(
+        &self,
+        user_id: UserId,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let require_unique_display_name = self.require_unique_display_name;
+
+        self.run(move |state| {
+            let display_name = display_name.trim().to_string();
+            if display_name.is_empty() {
+                return Err(DatabaseError::InvalidInput {
+                    message: "display name must not be empty".to_string(),
+                });
+            }
+
+            if require_unique_display_name {
+                state.check_display_name_unique(user_id.as_str(), &display_name)?;
+            }
+
+            match state.users.get_mut(user_id.as_str()) {
+                Some(user) => {
+                    user.display_name = display_name;
+                    user.display_name_overridden = true;
+                    user.version += 1;
+                    Ok(())
+                }
+                None => Err(DatabaseError::UnknownUser { user_id: user_id.0 }),
+            }
+        })
+    }
+
+    fn purge_expired_tokens(&self) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        self.run(move |state| {
+            let now = chrono::Utc::now().timestamp();
+
+            let before = state.tokens.len();
+            state
+                .tokens
+                .retain(|_, token| token.expires_at.is_none_or(|expires| expires > now));
+
+            Ok((before - state.tokens.len()) as u64)
+        })
+    }
+
+    fn delete_all_tokens_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        self.run(move |state| {
+            let before = state.tokens.len();
+            state
+                .tokens
+                .retain(|_, token| token.user_id != user_id.as_str());
+
+            Ok((before - state.tokens.len()) as u64)
+        })
+    }
+
+    fn get_leaderboard(
+        &self,
+        limit: u32,
+        ascending: bool,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            let mut users = state.users_with_balances_filtered(include_inactive);
+            users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+            if ascending {
+                users.sort_by_key(|user| user.balance);
+            } else {
+                users.sort_by_key(|user| Reverse(user.balance));
+            }
+            users.truncate(limit as usize);
+
+            Ok(users)
+        })
+    }
+
+    fn get_balance_between(
+        &self,
+        user_a: UserId,
+        user_b: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| state.balance_between(user_a.as_str(), user_b.as_str()))
+    }
+
+    fn get_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .users_with_balances()
+                .into_iter()
+                .find(|user| user.user_id == user_id.as_str()))
+        })
+    }
+
+    fn search_users(
+        &self,
+        query: String,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+        let limit = limit.min(i64::from(MAX_SEARCH_RESULTS));
+
+        self.run(move |state| {
+            let query = fold_for_search(&query);
+
+            let mut users: Vec<User> = state
+                .users_with_balances()
+                .into_iter()
+                .filter(|user| fold_for_search(&user.display_name).contains(&query))
+                .collect();
+            users.sort_by(|a, b| {
+                a.display_name
+                    .to_ascii_lowercase()
+                    .cmp(&b.display_name.to_ascii_lowercase())
+                    .then_with(|| a.user_id.cmp(&b.user_id))
+            });
+            users.truncate(limit as usize);
+
+            Ok(users)
+        })
+    }
+
+    fn export_transactions_csv(&self) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        self.run(move |state| {
+            transactions_to_csv(state.live().map(|stored| stored.transaction.clone()))
+        })
+    }
+
+    fn count_transactions_between(
+        &self,
+        shafter: UserId,
+        shaftee: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .filter(|stored| {
+                    stored.transaction.shafter == shafter.as_str()
+                        && stored.transaction.shaftee == shaftee.as_str()
+                })
+                .count() as i64)
+        })
+    }
+
+    fn get_transactions_in_range(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            if from > to {
+                return Err(DatabaseError::InvalidInput {
+                    message: "range start must not be after its end".to_string(),
+                });
+            }
+
+            // Like SQLite, compare at whole-second precision.
+            let (from, to) = (from.timestamp(), to.timestamp());
+
+            Ok(state
+                .live()
+                .rev()
+                .filter(|stored| {
+                    let time_sec = stored.transaction.datetime.timestamp();
+                    from <= time_sec && time_sec <= to
+                })
+                .take(limit as usize)
+                .map(|stored| stored.transaction.clone())
+                .collect())
+        })
+    }
+
+    fn migrate(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        // There's no schema to migrate.
+        self.run(|_| Ok(()))
+    }
+
+    fn get_summary(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<UserSummary>, DatabaseError>> {
+        self.run(move |state| {
+            if from > to {
+                return Err(DatabaseError::InvalidInput {
+                    message: "range start must not be after its end".to_string(),
+                });
+            }
+
+            // Like SQLite, compare at whole-second precision.
+            let (from, to) = (from.timestamp(), to.timestamp());
+
+            let mut totals: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+            for stored in state.live() {
+                let transaction = &stored.transaction;
+                let time_sec = transaction.datetime.timestamp();
+                if time_sec < from || time_sec > to {
+                    continue;
+                }
+
+                totals.entry(&transaction.shafter).or_default().0 += transaction.amount;
+                totals.entry(&transaction.shaftee).or_default().1 += transaction.amount;
+            }
+
+            Ok(totals
+                .into_iter()
+                .map(|(user_id, (total_shafted, total_received))| UserSummary {
+                    user_id: user_id.to_string(),
+                    display_name: state
+                        .users
+                        .get(user_id)
+                        .map(|user| user.display_name.clone())
+                        .unwrap_or_else(|| user_id.to_string()),
+                    total_shafted,
+                    total_received,
+                    net: total_shafted - total_received,
+                })
+                .collect())
+        })
+    }
+
+    fn get_user_by_identity(
+        &self,
+        provider: String,
+        provider_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .identities
+                .get(&(provider, provider_id))
+                .cloned()
+                .map(UserId))
+        })
+    }
+
+    fn link_identity(
+        &self,
+        user_id: UserId,
+        provider: String,
+        provider_id: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            if !state.users.contains_key(user_id.as_str()) {
+                return Err(DatabaseError::UnknownUser { user_id: user_id.0 });
+            }
+
+            let linked_user_id = state
+                .identities
+                .entry((provider, provider_id))
+                .or_insert_with(|| user_id.0.clone());
+
+            if *linked_user_id != user_id.0 {
+                return Err(DatabaseError::Conflict {
+                    constraint: "identities.provider, identities.provider_id".to_string(),
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    fn merge_users(
+        &self,
+        from_user_id: UserId,
+        into_user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| {
+            if from_user_id == into_user_id {
+                return Err(DatabaseError::InvalidInput {
+                    message: "can't merge a user into themselves".to_string(),
+                });
+            }
+
+            for user_id in &[&from_user_id, &into_user_id] {
+                if !state.users.contains_key(user_id.as_str()) {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: user_id.to_string(),
+                    });
+                }
+            }
+
+            let (from, into) = (from_user_id.as_str(), into_user_id.as_str());
+            let now = chrono::Utc::now().timestamp();
+
+            for stored in &mut state.transactions {
+                let transaction = &stored.transaction;
+                let between = (transaction.shafter == from && transaction.shaftee == into)
+                    || (transaction.shafter == into && transaction.shaftee == from);
+                if between && stored.deleted_at.is_none() {
+                    stored.deleted_at = Some(now);
+                }
+
+                let transaction = &mut stored.transaction;
+                if transaction.shafter == from {
+                    transaction.shafter = into.to_string();
+                }
+                if transaction.shaftee == from {
+                    transaction.shaftee = into.to_string();
+                }
+            }
+
+            for token in state.tokens.values_mut() {
+                if token.user_id == from {
+                    token.user_id = into.to_string();
+                }
+            }
+
+            for user_id in state.identities.values_mut() {
+                if user_id == from {
+                    *user_id = into.to_string();
+                }
+            }
+
+            // Users can only be in one team, so keep `into`'s if it has one.
+            if let Some(team) = state.user_teams.remove(from) {
+                state.user_teams.entry(into.to_string()).or_insert(team);
+            }
+
+            state.users.remove(from);
+
+            Ok(())
+        })
+    }
+
+    fn stream_all_transactions(
+        &self,
+    ) -> LocalBoxStream<'static, Result<Transaction, DatabaseError>> {
+        // Everything is in memory anyway, so just take a snapshot.
+        let transactions: Vec<Transaction> = self
+            .lock()
+            .live()
+            .map(|stored| stored.transaction.clone())
+            .collect();
+
+        stream::iter(transactions.into_iter().map(Ok)).boxed_local()
+    }
+
+    fn get_latest_transaction_between(
+        &self,
+        shafter: UserId,
+        shaftee: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .rev()
+                .find(|stored| {
+                    stored.transaction.shafter == shafter.as_str()
+                        && stored.transaction.shaftee == shaftee.as_str()
+                })
+                .map(|stored| stored.transaction.clone()))
+        })
+    }
+
+    fn get_last_transactions_filtered(
+        &self,
+        limit: u32,
+        min_amount: Option<i64>,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let min_amount = match min_amount {
+            Some(min_amount) => min_amount,
+            None => return self.get_last_transactions(limit),
+        };
+
+        if min_amount < 0 {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: format!("min_amount must be non-negative, got {}", min_amount),
+            })
+            .boxed();
+        }
+
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .rev()
+                .filter(|stored| stored.transaction.amount >= min_amount)
+                .take(limit as usize)
+                .map(|stored| stored.transaction.clone())
+                .collect())
+        })
+    }
+
+    fn settle_between(
+        &self,
+        user_a: UserId,
+        user_b: UserId,
+        reason: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_reason_length = self.max_reason_length;
+
+        self.run(move |state| {
+            let balance = state.balance_between(user_a.as_str(), user_b.as_str())?;
+            if balance == 0 {
+                return Ok(0);
+            }
+
+            let transaction = settlement(user_a.as_str(), user_b.as_str(), balance, reason.clone());
+            validate_shaft(&transaction, max_clock_skew, None, max_reason_length)?;
+            state.insert_transaction(transaction)?;
+
+            Ok(balance)
+        })
+    }
+
+    fn get_total_outstanding(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| Ok(state.total_outstanding()))
+    }
+
+    fn list_tokens_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Vec<TokenInfo>, DatabaseError>> {
+        self.run(move |state| {
+            let now = chrono::Utc::now().timestamp();
+
+            let mut tokens: Vec<_> = state
+                .tokens
+                .values()
+                .filter(|stored| stored.user_id == user_id.as_str())
+                .filter(|stored| stored.expires_at.is_none_or(|expires| expires > now))
+                .collect();
+            tokens.sort_by_key(|stored| stored.created_at);
+
+            Ok(tokens
+                .into_iter()
+                .map(|stored| TokenInfo {
+                    created_at: chrono::Utc.timestamp(stored.created_at, 0),
+                    last_used_at: stored
+                        .last_used_at
+                        .map(|time| chrono::Utc.timestamp(time, 0)),
+                })
+                .collect())
+        })
+    }
+
+    fn shaft_many(
+        &self,
+        shafter: UserId,
+        shaftees: Vec<(UserId, i64)>,
+        reason: String,
+        datetime: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.shaft_users(split_shaft(shafter, shaftees, reason, datetime))
+    }
+
+    fn get_stats(&self) -> LocalBoxFuture<'static, Result<SystemStats, DatabaseError>> {
+        self.run(move |state| {
+            Ok(SystemStats {
+                user_count: state.users.len() as i64,
+                transaction_count: state.live().count() as i64,
+                total_outstanding: state.total_outstanding(),
+            })
+        })
+    }
+
+    fn reverse_transaction(
+        &self,
+        id: i64,
+        reason: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_reason_length = self.max_reason_length;
+
+        self.run(move |state| {
+            let original = match state.live_transaction(id) {
+                Some(stored) => stored.transaction.clone(),
+                None => return Err(DatabaseError::UnknownTransaction { id }),
+            };
+
+            if state
+                .live()
+                .any(|stored| stored.reversed_transaction_id == Some(id))
+            {
+                return Err(DatabaseError::AlreadyReversed { id });
+            }
+
+            let reversal = Transaction {
+                id: None,
+                shafter: original.shaftee,
+                shaftee: original.shafter,
+                amount: original.amount,
+                datetime: chrono::Utc::now(),
+                reason: Some(reason),
+                idempotency_key: None,
+                category: original.category,
+            };
+            validate_shaft(&reversal, max_clock_skew, None, max_reason_length)?;
+            state.insert_transaction(reversal)?;
+
+            let stored = state
+                .transactions
+                .last_mut()
+                .expect("just inserted a transaction");
+            stored.reversed_transaction_id = Some(id);
+
+            Ok(stored.id())
+        })
+    }
+
+    fn set_user_active(
+        &self,
+        user_id: UserId,
+        active: bool,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.run(move |state| match state.users.get_mut(user_id.as_str()) {
+            Some(user) => {
+                user.active = active;
+                Ok(())
+            }
+            None => Err(DatabaseError::UnknownUser { user_id: user_id.0 }),
+        })
+    }
+
+    fn get_balance_at(
+        &self,
+        user: UserId,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| {
+            if !state.users.contains_key(user.as_str()) {
+                return Err(DatabaseError::UnknownUser { user_id: user.0 });
+            }
+
+            // Compare to the second, as times are stored.
+            state.balance_of(&user, Some(at.timestamp()))
+        })
+    }
+
+    fn get_transactions_by_category(
+        &self,
+        category: String,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .rev()
+                .filter(|stored| stored.transaction.category.as_ref() == Some(&category))
+                .take(limit as usize)
+                .map(|stored| stored.transaction.clone())
+                .collect())
+        })
+    }
+
+    fn get_category_totals(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        self.run(|state| {
+            let mut totals: HashMap<String, i64> = HashMap::new();
+            for transaction in state.counted(true) {
+                let category = transaction.category.clone().unwrap_or_default();
+                *totals.entry(category).or_insert(0) += transaction.amount;
+            }
+
+            let mut totals: Vec<_> = totals.into_iter().collect();
+            totals.sort_by(|(a, a_total), (b, b_total)| b_total.cmp(a_total).then(a.cmp(b)));
+
+            Ok(totals.into_iter().collect())
+        })
+    }
+
+    fn import_transactions(
+        &self,
+        transactions: Vec<Transaction>,
+        validate: bool,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+
+        self.run(move |state| {
+            if validate {
+                for transaction in &transactions {
+                    validate_shaft(transaction, max_clock_skew, max_amount, max_reason_length)?;
+
+                    for user_id in &[&transaction.shafter, &transaction.shaftee] {
+                        if !state.users.contains_key(user_id.as_str()) {
+                            return Err(DatabaseError::UnknownUser {
+                                user_id: user_id.to_string(),
+                            });
+                        }
+                    }
+                }
+            }
+
+            let mut inserted = 0;
+            for transaction in transactions {
+                if state.append_transaction(transaction).is_some() {
+                    inserted += 1;
+                }
+            }
+
+            Ok(inserted)
+        })
+    }
+
+    fn get_counterparties(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Vec<(User, i64)>, DatabaseError>> {
+        self.run(move |state| {
+            if !state.users.contains_key(user_id.as_str()) {
+                return Err(DatabaseError::UnknownUser { user_id: user_id.0 });
+            }
+
+            let mut nets: HashMap<&str, i64> = HashMap::new();
+            for stored in state.live() {
+                let transaction = &stored.transaction;
+                if transaction.shafter == transaction.shaftee {
+                    continue;
+                }
+
+                if transaction.shafter == user_id.as_str() {
+                    *nets.entry(&transaction.shaftee).or_insert(0) += transaction.amount;
+                } else if transaction.shaftee == user_id.as_str() {
+                    *nets.entry(&transaction.shafter).or_insert(0) -= transaction.amount;
+                }
+            }
+
+            let mut counterparties: Vec<_> = state
+                .users_with_balances()
+                .into_iter()
+                .filter_map(|user| {
+                    let net = *nets.get(user.user_id.as_str())?;
+                    Some((user, net))
+                })
+                .collect();
+            counterparties.sort_by(|(a, a_net), (b, b_net)| {
+                b_net
+                    .abs()
+                    .cmp(&a_net.abs())
+                    .then_with(|| a.user_id.cmp(&b.user_id))
+            });
+
+            Ok(counterparties)
+        })
+    }
+
+    fn validate_shaft(
+        &self,
+        transaction: &Transaction,
+    ) -> LocalBoxFuture<'static, Result<ShaftPreview, DatabaseError>> {
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+        let transaction = transaction.clone();
+
+        self.run(move |state| {
+            validate_shaft(&transaction, max_clock_skew, max_amount, max_reason_length)?;
+            state.check_shaft_limits(&transaction, shaft_rate_limit, max_debt)
+        })
+    }
+
+    fn get_transaction(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .live_transaction(id)
+                .map(|stored| stored.transaction.clone()))
+        })
+    }
+
+    fn get_personal_ledger(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<PersonalLedger, DatabaseError>> {
+        self.get_counterparties(user_id)
+            .map_ok(personal_ledger)
+            .boxed_local()
+    }
+
+    fn resolve_token(
+        &self,
+        token: Token,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>> {
+        self.run(move |state| {
+            let now = chrono::Utc::now().timestamp();
+
+            Ok(state
+                .tokens
+                .get(token.as_str())
+                .filter(|stored| stored.expires_at.is_none_or(|expires| expires > now))
+                .map(|stored| UserId::from(stored.user_id.as_str())))
+        })
+    }
+
+    fn get_daily_activity(
+        &self,
+        user_id: UserId,
+        days: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<(chrono::NaiveDate, i64)>, DatabaseError>> {
+        let start = activity_window_start(days);
+
+        self.run(move |state| {
+            let mut totals: BTreeMap<i64, i64> = BTreeMap::new();
+            for stored in state.live() {
+                let transaction = &stored.transaction;
+                let time = transaction.datetime.timestamp();
+                if transaction.shafter == user_id.as_str() && time >= start {
+                    *totals.entry(time / 86400).or_insert(0) += transaction.amount;
+                }
+            }
+
+            Ok(totals
+                .into_iter()
+                .map(|(day, total)| (day_to_date(day), total))
+                .collect())
+        })
+    }
+
+    fn count_transactions_for_user(
+        &self,
+        user_id: UserId,
+        direction: TransactionDirection,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .filter(|stored| in_direction(&stored.transaction, user_id.as_str(), direction))
+                .count() as i64)
+        })
+    }
+
+    fn rotate_token(
+        &self,
+        old_token: Token,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>> {
+        let created_at = chrono::Utc::now();
+        let expires_at = created_at + self.token_lifetime;
+
+        self.run(move |state| {
+            let now = created_at.timestamp();
+            let old = match state.tokens.get(old_token.as_str()) {
+                Some(stored) if stored.expires_at.is_none_or(|expires| expires > now) => stored,
+                _ => return Err(DatabaseError::InvalidToken),
+            };
+
+            let new = StoredToken {
+                user_id: old.user_id.clone(),
+                scope: old.scope,
+                created_at: now,
+                last_used_at: None,
+                expires_at: Some(expires_at.timestamp()),
+            };
+
+            let token: String = OsRng
+                .sample_iter(&Alphanumeric)
+                .take(SqliteDatabase::TOKEN_LENGTH)
+                .collect();
+
+            state.tokens.remove(old_token.as_str());
+            state.tokens.insert(token.clone(), new);
+
+            Ok(Token(token))
+        })
+    }
+
+    fn get_last_transactions_named(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<NamedTransaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            Ok(state
+                .live()
+                .rev()
+                .take(limit as usize)
+                .map(|stored| state.named(&stored.transaction))
+                .collect())
+        })
+    }
+
+    #[cfg(feature = "serde")]
+    fn export_json(
+        &self,
+        include_tokens: bool,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        self.run(move |state| {
+            let mut backup = Backup {
+                users: state
+                    .users
+                    .iter()
+                    .map(|(user_id, user)| BackupUser {
+                        user_id: user_id.clone(),
+                        display_name: user.display_name.clone(),
+                        created_at: user.created_at,
+                        display_name_overridden: user.display_name_overridden,
+                        active: user.active,
+                        version: user.version,
+                    })
+                    .collect(),
+                identities: state
+                    .identities
+                    .iter()
+                    .map(|((provider, provider_id), user_id)| BackupIdentity {
+                        provider: provider.clone(),
+                        provider_id: provider_id.clone(),
+                        user_id: user_id.clone(),
+                    })
+                    .collect(),
+                transactions: state
+                    .transactions
+                    .iter()
+                    .map(|stored| BackupTransaction {
+                        id: stored.id(),
+                        shafter: stored.transaction.shafter.clone(),
+                        shaftee: stored.transaction.shaftee.clone(),
+                        amount: stored.transaction.amount,
+                        time_sec: stored.transaction.datetime.timestamp(),
+                        reason: stored.transaction.reason.clone(),
+                        idempotency_key: stored.transaction.idempotency_key.clone(),
+                        category: stored.transaction.category.clone(),
+                        deleted_at: stored.deleted_at,
+                        reversed_transaction_id: stored.reversed_transaction_id,
+                    })
+                    .collect(),
+                tokens: Vec::new(),
+                teams: state
+                    .user_teams
+                    .iter()
+                    .map(|(user_id, team)| BackupTeam {
+                        user_id: user_id.clone(),
+                        team: team.clone(),
+                    })
+                    .collect(),
+                attachments: state
+                    .attachments
+                    .iter()
+                    .map(|attachment| BackupAttachment {
+                        id: attachment.id,
+                        transaction_id: attachment.transaction_id,
+                        url: attachment.url.clone(),
+                        uploaded_at: attachment.uploaded_at.timestamp(),
+                    })
+                    .collect(),
+            };
+
+            if include_tokens {
+                backup.tokens = state
+                    .tokens
+                    .iter()
+                    .map(|(token, stored)| BackupToken {
+                        user_id: stored.user_id.clone(),
+                        token: token.clone(),
+                        scope: stored.scope,
+                        created_at: Some(stored.created_at),
+                        last_used_at: stored.last_used_at,
+                        expires_at: stored.expires_at,
+                    })
+                    .collect();
+            }
+
+            backup_to_json(&backup)
+        })
+    }
+
+    #[cfg(feature = "serde")]
+    fn import_json(&self, data: &str) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let backup = match backup_from_json(data) {
+            Ok(backup) => backup,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            if !state.users.is_empty()
+                || !state.identities.is_empty()
+                || !state.transactions.is_empty()
+                || !state.tokens.is_empty()
+                || !state.user_teams.is_empty()
+                || !state.attachments.is_empty()
+            {
+                return Err(DatabaseError::InvalidInput {
+                    message: "can only import a backup into an empty database".to_string(),
+                });
+            }
+
+            state.users = backup
+                .users
+                .into_iter()
+                .map(|user| {
+                    let stored = StoredUser {
+                        display_name: user.display_name,
+                        created_at: user.created_at,
+                        display_name_overridden: user.display_name_overridden,
+                        active: user.active,
+                        version: user.version,
+                    };
+                    (user.user_id, stored)
+                })
+                .collect();
+            state.identities = backup
+                .identities
+                .into_iter()
+                .map(|identity| ((identity.provider, identity.provider_id), identity.user_id))
+                .collect();
+
+            let mut transactions: Vec<_> = backup
+                .transactions
+                .into_iter()
+                .map(|row| StoredTransaction {
+                    transaction: Transaction {
+                        id: Some(row.id),
+                        shafter: row.shafter,
+                        shaftee: row.shaftee,
+                        amount: row.amount,
+                        datetime: chrono::Utc.timestamp(row.time_sec, 0),
+                        reason: row.reason,
+                        idempotency_key: row.idempotency_key,
+                        category: row.category,
+                    },
+                    deleted_at: row.deleted_at,
+                    reversed_transaction_id: row.reversed_transaction_id,
+                })
+                .collect();
+            transactions.sort_by_key(StoredTransaction::id);
+            state.transactions = transactions;
+
+            state.tokens = backup
+                .tokens
+                .into_iter()
+                .map(|token| {
+                    let stored = StoredToken {
+                        user_id: token.user_id,
+                        scope: token.scope,
+                        created_at: token.created_at.unwrap_or(0),
+                        last_used_at: token.last_used_at,
+                        expires_at: token.expires_at,
+                    };
+                    (token.token, stored)
+                })
+                .collect();
+            state.user_teams = backup
+                .teams
+                .into_iter()
+                .map(|team| (team.user_id, team.team))
+                .collect();
+
+            let mut attachments: Vec<_> = backup
+                .attachments
+                .into_iter()
+                .map(|attachment| Attachment {
+                    id: attachment.id,
+                    transaction_id: attachment.transaction_id,
+                    url: attachment.url,
+                    uploaded_at: chrono::Utc.timestamp(attachment.uploaded_at, 0),
+                })
+                .collect();
+            attachments.sort_by_key(|attachment| attachment.id);
+            state.attachments = attachments;
+
+            Ok(())
+        })
+    }
+
+    fn get_currency(&self) -> LocalBoxFuture<'static, Result<Currency, DatabaseError>> {
+        self.run(|state| Ok(state.currency.clone()))
+    }
+
+    fn set_currency(
+        &self,
+        currency: Currency,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        if let Err(err) = validate_currency(&currency) {
+            return futures::future::err(err).boxed();
+        }
+
+        self.run(move |state| {
+            state.currency = currency;
+            Ok(())
+        })
+    }
+
+    fn set_display_name_checked(
+        &self,
+        user_id: UserId,
+        display_name: String,
+        expected_version: i32,
+    ) -> LocalBoxFuture<'static, Result<i32, DatabaseError>> {
+        let require_unique_display_name = self.require_unique_display_name;
+
+        self.run(move |state| {
+            let display_name = display_name.trim().to_string();
+            if display_name.is_empty() {
+                return Err(DatabaseError::InvalidInput {
+                    message: "display name must not be empty".to_string(),
+                });
+            }
+
+            if require_unique_display_name {
+                state.check_display_name_unique(user_id.as_str(), &display_name)?;
+            }
+
+            match state.users.get_mut(user_id.as_str()) {
+                Some(user) if user.version == expected_version => {
+                    user.display_name = display_name;
+                    user.display_name_overridden = true;
+                    user.version += 1;
+                    Ok(user.version)
+                }
+                Some(_) => Err(DatabaseError::VersionConflict {
+                    user_id: user_id.0,
+                    expected_version,
+                }),
+                None => Err(DatabaseError::UnknownUser { user_id: user_id.0 }),
+            }
+        })
+    }
+
+    fn get_transactions_for_users(
+        &self,
+        user_ids: Vec<UserId>,
+        before: Option<i64>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            let user_ids: HashSet<String> = user_ids.into_iter().map(|user_id| user_id.0).collect();
+
+            let items = state
+                .live()
+                .rev()
+                .filter(|stored| before.is_none_or(|before| stored.id() < before))
+                .filter(|stored| {
+                    user_ids.contains(&stored.transaction.shafter)
+                        || user_ids.contains(&stored.transaction.shaftee)
+                })
+                .take(limit as usize)
+                .map(|stored| stored.transaction.clone())
+                .collect();
+
+            Ok(Page::from_keyset(items, limit, None))
+        })
+    }
+
+    fn get_largest_transactions(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<NamedTransaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        self.run(move |state| {
+            let mut transactions: Vec<_> = state.counted(true).collect();
+            transactions.sort_by(|a, b| b.amount.cmp(&a.amount).then(b.id.cmp(&a.id)));
+
+            Ok(transactions
+                .into_iter()
+                .take(limit as usize)
+                .map(|transaction| state.named(transaction))
+                .collect())
+        })
+    }
+
+    fn get_user_ids(&self) -> LocalBoxFuture<'static, Result<Vec<UserId>, DatabaseError>> {
+        self.run(|state| {
+            let mut user_ids: Vec<UserId> = state.users.keys().cloned().map(UserId).collect();
+            user_ids.sort();
+            Ok(user_ids)
+        })
+    }
+
+    fn verify_balances(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Vec<BalanceMismatch>, DatabaseError>> {
+        self.run(|state| {
+            let balances = state.balances();
+            let mut user_ids: Vec<&String> = state.users.keys().collect();
+            user_ids.sort();
+
+            let mut mismatches = Vec::new();
+            for user_id in user_ids {
+                let balance_a = state.balance_of(&UserId(user_id.clone()), None)?;
+                let balance_b = balances.get(user_id).copied().unwrap_or(0);
+                if balance_a != balance_b {
+                    mismatches.push((user_id.clone(), balance_a, balance_b));
+                }
+            }
+            Ok(mismatches)
+        })
+    }
+}
+
+/// Whether the transaction is in the given direction for the user.
+fn in_direction(transaction: &Transaction, user_id: &str, direction: TransactionDirection) -> bool {
+    match direction {
+        TransactionDirection::Sent => transaction.shafter == user_id,
+        TransactionDirection::Received => transaction.shaftee == user_id,
+        TransactionDirection::Both => {
+            transaction.shafter == user_id || transaction.shaftee == user_id
+        }
+    }
+}
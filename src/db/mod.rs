@@ -1,101 +1,1468 @@
 //! Handles talking to local data store.
 
 use chrono;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use csv;
 use futures::future::LocalBoxFuture;
+use futures::stream::LocalBoxStream;
+use futures_cpupool::CpuPool;
 
 use linear_map::LinearMap;
 use r2d2;
 use rusqlite;
-use serde;
-use serde::Serialize;
-use snafu::{Backtrace, Snafu};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use snafu::{Backtrace, ResultExt, Snafu};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
+#[cfg(feature = "serde")]
+use std::collections::HashSet;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "test-util")]
+mod memory;
 // mod postgres;
 mod sqlite;
 
+#[cfg(feature = "test-util")]
+pub use self::memory::InMemoryDatabase;
 // pub use self::postgres::PostgresDatabase;
 pub use self::sqlite::SqliteDatabase;
 
+/// Defines a newtype wrapper around `String`, so that different kinds of
+/// identifier can't be mixed up.
+macro_rules! string_newtype {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[cfg_attr(feature = "serde", derive(Serialize))]
+        #[cfg_attr(feature = "serde", serde(transparent))]
+        pub struct $name(pub String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(s: String) -> $name {
+                $name(s)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(s: &str) -> $name {
+                $name(s.to_string())
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> String {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+string_newtype!(
+    /// A local user ID.
+    UserId
+);
+
+string_newtype!(
+    /// A Shaft access token.
+    Token
+);
+
+string_newtype!(
+    /// A user's Github login ID.
+    GithubId
+);
+
 /// A single transaction between two users.
-#[derive(Clone, Debug, Serialize)]
+///
+/// With the `serde` feature this can be (de)serialized, with `datetime` as
+/// an RFC 3339 string.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Transaction {
+    /// The transaction's ID, or `None` if it hasn't been committed yet.
+    pub id: Option<i64>,
     /// The user who is creating the transaction.
     pub shafter: String,
     /// The other party in the transaction.
     pub shaftee: String,
-    /// The amount of money in pence. Positive means shafter is owed the amount,
-    /// negative means shafter owes the amount.
+    /// The amount of money in pence that the shafter is owed. New shafts must
+    /// have a positive amount (c.f. [Database::shaft_user]).
     pub amount: i64,
     /// Time transaction happened.
-    #[serde(serialize_with = "serialize_time")]
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serialize_rfc3339",
+            deserialize_with = "deserialize_rfc3339"
+        )
+    )]
     pub datetime: chrono::DateTime<chrono::Utc>,
-    /// A human readable description of the transaction.
-    pub reason: String,
+    /// An optional human readable description of the transaction.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reason: Option<String>,
+    /// An optional client chosen key identifying this shaft. Submitting a
+    /// shaft with the same key as an existing one is a no-op, so that e.g.
+    /// double clicks don't shaft twice.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub idempotency_key: Option<String>,
+    /// An optional tag for reporting, e.g. "food" or "drinks". Must not be
+    /// empty if given.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub category: Option<String>,
+}
+
+/// A transaction along with the current state of both parties.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TransactionDetail {
+    /// The transaction itself.
+    pub transaction: Transaction,
+    /// The shafter's display name.
+    pub shafter_display_name: String,
+    /// The shaftee's display name.
+    pub shaftee_display_name: String,
+    /// The shafter's current balance.
+    pub shafter_balance: i64,
+    /// The shaftee's current balance.
+    pub shaftee_balance: i64,
+}
+
+/// A transaction along with the display names of both parties. See
+/// [Database::get_last_transactions_named].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct NamedTransaction {
+    /// The transaction itself.
+    pub transaction: Transaction,
+    /// The shafter's display name, or their ID if they no longer exist.
+    pub shafter_display_name: String,
+    /// The shaftee's display name, or their ID if they no longer exist.
+    pub shaftee_display_name: String,
+}
+
+/// A link to a file, e.g. a receipt, attached to a transaction.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Attachment {
+    pub id: i64,
+    pub transaction_id: i64,
+    pub url: String,
+    /// When the attachment was added.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_time"))]
+    pub uploaded_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A full copy of a database's data, as produced by
+/// [Database::export_json] and restored by [Database::import_json]. Rows
+/// are stored much as they are in the SQLite schema.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Backup {
+    users: Vec<BackupUser>,
+    identities: Vec<BackupIdentity>,
+    transactions: Vec<BackupTransaction>,
+    /// Empty if tokens were excluded from the export.
+    #[serde(default)]
+    tokens: Vec<BackupToken>,
+    teams: Vec<BackupTeam>,
+    attachments: Vec<BackupAttachment>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupUser {
+    user_id: String,
+    display_name: String,
+    created_at: Option<i64>,
+    display_name_overridden: bool,
+    active: bool,
+    #[serde(default)]
+    version: i32,
 }
 
-/// A user and their balance
-#[derive(Debug, Clone, Serialize)]
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupIdentity {
+    provider: String,
+    provider_id: String,
+    user_id: String,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupTransaction {
+    id: i64,
+    shafter: String,
+    shaftee: String,
+    amount: i64,
+    time_sec: i64,
+    reason: Option<String>,
+    idempotency_key: Option<String>,
+    category: Option<String>,
+    deleted_at: Option<i64>,
+    reversed_transaction_id: Option<i64>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupToken {
+    user_id: String,
+    /// The token as stored, i.e. hashed for [SqliteDatabase].
+    token: String,
+    scope: TokenScope,
+    created_at: Option<i64>,
+    last_used_at: Option<i64>,
+    expires_at: Option<i64>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupTeam {
+    user_id: String,
+    team: String,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupAttachment {
+    id: i64,
+    transaction_id: i64,
+    url: String,
+    uploaded_at: i64,
+}
+
+/// How much a user shafted and was shafted over some period. See
+/// [Database::get_summary].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct UserSummary {
+    pub user_id: String,
+    pub display_name: String,
+    /// The total the user shafted others for.
+    pub total_shafted: i64,
+    /// The total others shafted the user for.
+    pub total_received: i64,
+    /// `total_shafted - total_received`, i.e. the change in their balance.
+    pub net: i64,
+}
+
+/// The users with the highest and lowest balances, in that order. See
+/// [Database::get_balance_extremes].
+pub type BalanceExtremes = (Option<User>, Option<User>);
+
+/// Two users who have shafted each other, as `(a, b, a_to_b, b_to_a)`. See
+/// [Database::get_nettable_pairs].
+pub type NettablePair = (String, String, i64, i64);
+
+/// A user whose balance depends on how it's summed, as
+/// `(user_id, balance_a, balance_b)`. See [Database::verify_balances].
+pub type BalanceMismatch = (String, i64, i64);
+
+/// A user and their balance.
+///
+/// Fields are private so that more can be added without breaking callers:
+/// build one with `User::from(user_id)` and the `with_*`
+/// methods, and read it with the accessors. With the `serde` feature this
+/// can be (de)serialized.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct User {
     /// Their internal shaft user ID
-    pub user_id: String,
+    user_id: String,
     /// Their display name
-    pub display_name: String,
+    display_name: String,
     /// Their current balance
-    pub balance: i64,
+    balance: i64,
+    /// When they first logged in.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serialize_time",
+            deserialize_with = "deserialize_time"
+        )
+    )]
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Bumped whenever their display name changes, see
+    /// [Database::set_display_name_checked].
+    #[cfg_attr(feature = "serde", serde(default))]
+    version: i32,
+}
+
+impl User {
+    /// Set their display name.
+    pub fn with_display_name(mut self, display_name: impl Into<String>) -> User {
+        self.display_name = display_name.into();
+        self
+    }
+
+    /// Set their balance.
+    pub fn with_balance(mut self, balance: i64) -> User {
+        self.balance = balance;
+        self
+    }
+
+    /// Set when they first logged in.
+    pub fn with_created_at(mut self, created_at: chrono::DateTime<chrono::Utc>) -> User {
+        self.created_at = created_at;
+        self
+    }
+
+    /// Set the version of their details.
+    pub fn with_version(mut self, version: i32) -> User {
+        self.version = version;
+        self
+    }
+
+    /// Their internal shaft user ID
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Their display name
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    /// Their current balance
+    pub fn balance(&self) -> i64 {
+        self.balance
+    }
+
+    /// When they first logged in, or the unix epoch if we don't know.
+    pub fn created_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.created_at
+    }
+
+    /// The version of their details, to pass to
+    /// [Database::set_display_name_checked].
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+}
+
+impl From<String> for User {
+    /// A user with the given ID, which is also used as their display name,
+    /// a zero balance, an unknown creation time and version 0.
+    fn from(user_id: String) -> User {
+        User {
+            display_name: user_id.clone(),
+            user_id,
+            balance: 0,
+            created_at: chrono::Utc.timestamp(0, 0),
+            version: 0,
+        }
+    }
+}
+
+/// Details of one of a user's access tokens, for showing them their
+/// sessions. Deliberately doesn't include the token itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TokenInfo {
+    /// When the token was created.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_time"))]
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// When the token was last used, to within a minute (c.f.
+    /// [Database::touch_token]), or `None` if it never has been.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_optional_time"))]
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// How to render amounts for humans, e.g. as pounds or as beers. Amounts are
+/// always stored as integers of the smallest unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Currency {
+    /// The symbol to prefix amounts with, e.g. "£".
+    pub symbol: String,
+    /// The number of decimal places amounts are stored in, e.g. 2 for pence.
+    pub decimal_places: u32,
+}
+
+impl Default for Currency {
+    fn default() -> Currency {
+        Currency {
+            symbol: "£".to_string(),
+            decimal_places: 2,
+        }
+    }
+}
+
+impl Currency {
+    /// The most decimal places a currency may have, as an `i64` has 18
+    /// significant digits.
+    pub const MAX_DECIMAL_PLACES: u32 = 18;
+
+    /// Render an amount, e.g. `-450` as `-£4.50`.
+    pub fn format_amount(&self, amount: i64) -> String {
+        self.display(amount).to_string()
+    }
+
+    /// Get a [Display](fmt::Display) implementation for an amount.
+    pub fn display(&self, amount: i64) -> AmountDisplay<'_> {
+        AmountDisplay {
+            currency: self,
+            amount,
+        }
+    }
+}
+
+/// Renders an amount in a [Currency].
+pub struct AmountDisplay<'a> {
+    currency: &'a Currency,
+    amount: i64,
+}
+
+impl fmt::Display for AmountDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.amount < 0 { "-" } else { "" };
+        let abs = self.amount.unsigned_abs();
+
+        if self.currency.decimal_places == 0 {
+            return write!(f, "{}{}{}", sign, self.currency.symbol, abs);
+        }
+
+        let scale = 10u64.pow(self.currency.decimal_places);
+        write!(
+            f,
+            "{}{}{}.{:0width$}",
+            sign,
+            self.currency.symbol,
+            abs / scale,
+            abs % scale,
+            width = self.currency.decimal_places as usize
+        )
+    }
+}
+
+/// Statistics about a database connection pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PoolStats {
+    /// The number of open connections.
+    pub connections: u32,
+    /// The number of open connections not currently in use.
+    pub idle_connections: u32,
+    /// The number of times we've timed out waiting for a connection because
+    /// all connections were in use, as opposed to being unable to connect.
+    pub timeout_count: u64,
+}
+
+/// A callback run with each newly committed shaft, c.f.
+/// [SqliteDatabase::with_shaft_listener].
+pub type ShaftListener = Arc<dyn Fn(&Transaction) + Send + Sync>;
+
+/// A hook told about each database operation, c.f.
+/// [SqliteDatabase::with_metrics].
+pub trait DatabaseMetrics: Send + Sync {
+    /// Called once an operation has finished, with the name of the
+    /// [Database] method, how long it took and whether it succeeded.
+    fn on_query(&self, method: &'static str, duration: Duration, result: Result<(), ()>);
+}
+
+/// Headline numbers about the whole system, e.g. for the landing page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SystemStats {
+    /// The number of users.
+    pub user_count: i64,
+    /// The number of live transactions, i.e. excluding deleted ones.
+    pub transaction_count: i64,
+    /// The total amount owed, c.f. [Database::get_total_outstanding].
+    pub total_outstanding: i64,
+}
+
+/// A user's debts split by direction, c.f. [Database::get_personal_ledger].
+/// Each list is ordered by the size of the balance, largest first.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PersonalLedger {
+    /// The users who owe the user money, with the (positive) net balance.
+    pub owed_to_me: Vec<(User, i64)>,
+    /// The users the user owes money to, with the (negative) net balance.
+    pub i_owe: Vec<(User, i64)>,
+}
+
+/// The balances a shaft would leave its parties with, c.f.
+/// [Database::validate_shaft].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ShaftPreview {
+    /// The shafter's balance once the shaft is committed.
+    pub shafter_balance_after: i64,
+    /// The shaftee's balance once the shaft is committed.
+    pub shaftee_balance_after: i64,
+}
+
+/// Configuration for a database's connection and thread pools. The defaults
+/// match those of r2d2 and one thread per CPU, with up to two retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// The maximum number of connections the pool will open.
+    pub max_pool_size: u32,
+    /// The number of idle connections the pool tries to keep open. `None`
+    /// means the same as `max_pool_size`.
+    pub min_idle: Option<u32>,
+    /// How long to wait for a connection before giving up.
+    pub connection_timeout: Duration,
+    /// Whether to check that a connection still works each time it's taken
+    /// from the pool, replacing it if not, so that a connection that's gone
+    /// bad while idle doesn't fail the next query. This costs a trivial
+    /// query on every checkout, which for SQLite is a few microseconds.
+    pub test_on_check_out: bool,
+    /// The number of threads used to run database operations. `None` means
+    /// one per CPU.
+    pub cpu_pool_threads: Option<usize>,
+    /// How many times to retry an operation that fails with a transient
+    /// error (c.f. [DatabaseError::is_transient]).
+    pub max_retries: u32,
+    /// The largest amount a single shaft may be for, to catch typos. `None`
+    /// means no limit.
+    pub max_amount: Option<i64>,
+    /// The longest reason a shaft may have, in characters, ignoring
+    /// trailing whitespace.
+    pub max_reason_length: usize,
+    /// The most rows a query may be asked for. Larger limits are clamped
+    /// to this.
+    pub max_limit: u32,
+    /// Whether display names must be unique, ignoring ASCII case. When set,
+    /// adding or renaming a user to a name another user already has fails
+    /// with [DatabaseError::Conflict].
+    pub require_unique_display_name: bool,
+    /// How often each user may shaft, c.f. [Database::shaft_user]. `None`
+    /// means no limit.
+    pub shaft_rate_limit: Option<ShaftRateLimit>,
+    /// The most a user may owe, as a positive amount: a shaft that would take
+    /// the shaftee's balance below minus this fails with
+    /// [DatabaseError::DebtLimitExceeded]. `None` means no limit.
+    pub max_debt: Option<i64>,
+}
+
+/// A limit on how many shafts a user may make in a trailing window of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShaftRateLimit {
+    /// The most shafts a user may make within `window`.
+    pub max_shafts_per_window: u32,
+    /// The length of the trailing window.
+    pub window: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_pool_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(30),
+            test_on_check_out: true,
+            cpu_pool_threads: None,
+            max_retries: 2,
+            max_amount: None,
+            max_reason_length: DEFAULT_MAX_REASON_LENGTH,
+            max_limit: DEFAULT_MAX_LIMIT,
+            require_unique_display_name: false,
+            shaft_rate_limit: None,
+            max_debt: None,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Build a pool with the given connection manager.
+    fn build_pool<M: r2d2::ManageConnection>(
+        &self,
+        manager: M,
+    ) -> Result<r2d2::Pool<M>, DatabaseError> {
+        r2d2::Pool::builder()
+            .max_size(self.max_pool_size)
+            .min_idle(self.min_idle)
+            .connection_timeout(self.connection_timeout)
+            .test_on_check_out(self.test_on_check_out)
+            .build(manager)
+            .context(ConnectionPoolError)
+    }
+
+    /// Build the thread pool used to run database operations.
+    fn build_cpu_pool(&self) -> CpuPool {
+        match self.cpu_pool_threads {
+            Some(threads) => CpuPool::new(threads),
+            None => CpuPool::new_num_cpus(),
+        }
+    }
+}
+
+/// What an access token is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum TokenScope {
+    /// May only read data, e.g. for integrations.
+    Read,
+    /// May do everything, e.g. for UI sessions.
+    Write,
+}
+
+impl TokenScope {
+    /// The name of the scope as stored in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+        }
+    }
+}
+
+/// The order to sort results in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    /// The SQL keyword for the order.
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// How to sort a list of users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserSort {
+    /// Lowest balance first.
+    BalanceAsc,
+    /// Highest balance first.
+    BalanceDesc,
+    /// By display name, ignoring case.
+    NameAsc,
+    /// By display name in reverse, ignoring case.
+    NameDesc,
+}
+
+/// Which of a user's transactions to get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionDirection {
+    /// Transactions where the user is the shafter.
+    Sent,
+    /// Transactions where the user is the shaftee.
+    Received,
+    /// All transactions involving the user.
+    Both,
+}
+
+/// A page of results from a paginated query.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Page<T> {
+    /// The items in this page.
+    pub items: Vec<T>,
+    /// The cursor to pass in to get the next page, or `None` if this is the
+    /// last page.
+    pub next_cursor: Option<i64>,
+    /// The total number of items across all pages, if requested.
+    pub total: Option<i64>,
+}
+
+impl Page<Transaction> {
+    /// Make a page from the result of a keyset query for at most `limit`
+    /// transactions, newest first. If the page is full then there may be
+    /// more, so the next page starts before its last transaction.
+    fn from_keyset(items: Vec<Transaction>, limit: i64, total: Option<i64>) -> Page<Transaction> {
+        let next_cursor = if items.len() as i64 == limit {
+            items.last().and_then(|last| last.id)
+        } else {
+            None
+        };
+
+        Page {
+            items,
+            next_cursor,
+            total,
+        }
+    }
 }
 
 /// A generic datastore for the app
+///
+/// Limits are given as `u32`s, but are always bound to queries as `i64`s,
+/// which is what `LIMIT` expects. They must be positive, or the query fails
+/// with [DatabaseError::InvalidInput], and are clamped to
+/// [PoolConfig::max_limit].
 pub trait Database: Send + Sync {
-    /// Get local user ID by their Github login ID
+    /// Get local user ID by their Github login ID. The same as
+    /// [Database::get_user_by_identity] with [GITHUB_PROVIDER].
     fn get_user_by_github_id(
         &self,
-        github_user_id: String,
-    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>>;
+        github_user_id: GithubId,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>>;
 
     /// Add a new user from github
+    ///
+    /// If the user already exists then this updates their display name
+    /// (unless they've overridden it) rather than erroring.
     fn add_user_by_github_id(
         &self,
-        github_user_id: String,
+        github_user_id: GithubId,
         display_name: String,
-    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
+    ) -> LocalBoxFuture<'static, Result<UserId, DatabaseError>>;
 
-    /// Create a new Shaft access token
+    /// Create a new Shaft access token with [TokenScope::Write] scope.
     fn create_token_for_user(
         &self,
-        user_id: String,
-    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>>;
+
+    /// Create a new Shaft access token with the given scope.
+    fn create_token_for_user_with_scope(
+        &self,
+        user_id: UserId,
+        scope: TokenScope,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>>;
 
     /// Delete a Shaft access token.
-    fn delete_token(&self, token: String) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+    fn delete_token(&self, token: Token) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
 
-    /// Get a user by Shaft access token.
+    /// Get a user, and the token's scope, by Shaft access token. Returns
+    /// `None` if the token has expired.
     fn get_user_from_token(
         &self,
-        token: String,
-    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>>;
+        token: Token,
+    ) -> LocalBoxFuture<'static, Result<Option<(User, TokenScope)>, DatabaseError>>;
 
-    /// Get a user's balance in pence
+    /// Get a user's balance in pence. Users with no transactions have a
+    /// balance of 0, while unknown users are a [DatabaseError::UnknownUser]
+    /// error.
     fn get_balance_for_user(
         &self,
-        user: String,
+        user: UserId,
     ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
 
-    /// Get a map of all users from local user ID to [User] object
+    /// Get a map of all users from local user ID to [User] object, ordered
+    /// by ascending balance, then by display name ignoring case, then by
+    /// user ID. Deactivated users (c.f. [Database::set_user_active]) are only
+    /// included if `include_inactive` is set.
     fn get_all_users(
         &self,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>>;
+
+    /// Like [Database::get_all_users], but with the given balance ordering.
+    fn get_all_users_ordered(
+        &self,
+        order: SortOrder,
+        include_inactive: bool,
     ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>>;
 
+    /// Get all users, sorted as given. Ties in balance are broken by display
+    /// name, ignoring case, and any remaining ties by user ID, so the order
+    /// is stable. Deactivated users are only included if `include_inactive`
+    /// is set.
+    fn get_all_users_sorted(
+        &self,
+        sort: UserSort,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>>;
+
     /// Commit a new Shaft [Transaction]
+    ///
+    /// The amount must be positive, the shafter and shaftee must be
+    /// different, and the transaction's time must not be too far in the
+    /// future, nor implausibly far in the past (c.f. [validate_shaft]). If
+    /// [PoolConfig::shaft_rate_limit] is set and the shafter has already
+    /// made that many shafts in the window, it fails with
+    /// [DatabaseError::RateLimited]. A shaft that would overflow either
+    /// party's balance fails with [DatabaseError::BalanceOverflow].
+    ///
+    /// If the returned future is dropped before the shaft commits, e.g.
+    /// because the request was cancelled, the shaft is rolled back.
     fn shaft_user(
         &self,
         transaction: Transaction,
     ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
 
+    /// Commit a [Transaction] without validating its time, for admins entering
+    /// historical data.
+    fn record_historical_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
     /// Get a list of the most recent Shaft transactions
     fn get_last_transactions(
         &self,
         limit: u32,
     ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Get a page of the transactions involving a user, in the given
+    /// direction, most recent first. `before` is as in
+    /// [get_transaction_feed](Database::get_transaction_feed), but the total
+    /// isn't counted.
+    fn get_transactions_for_user(
+        &self,
+        user_id: UserId,
+        direction: TransactionDirection,
+        before: Option<i64>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>>;
+
+    /// Like [Database::get_last_transactions], but skipping the `offset` most
+    /// recent transactions.
+    fn get_transactions_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Get a page of the transaction feed, most recent first.
+    ///
+    /// `before` is the `next_cursor` of the previous page, or `None` to start
+    /// from the most recent transaction. The total number of transactions is
+    /// only counted if `with_total` is set.
+    fn get_transaction_feed(
+        &self,
+        before: Option<i64>,
+        limit: u32,
+        with_total: bool,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>>;
+
+    /// Get a map from local user ID to the number of times they've been
+    /// shafted, i.e. the number of transactions where they were the shaftee.
+    /// Ordered by most shafted first.
+    ///
+    /// If `exclude_reversed` is set then reversed transactions and their
+    /// reversals aren't counted.
+    fn get_shaft_counts(
+        &self,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>>;
+
+    /// Purge all personal data for a user, e.g. to honor a deletion request.
+    ///
+    /// This atomically:
+    ///  - removes all of the user's access tokens,
+    ///  - removes the link to their Github account,
+    ///  - replaces their user ID with a random opaque one everywhere it
+    ///    appears, including their team membership (since the user ID is
+    ///    their Github login), and
+    ///  - sets their display name to "Deleted user".
+    ///
+    /// Transactions involving the user are retained, so that their
+    /// counterparties' balances are unchanged, but the reasons and
+    /// attachments of those transactions are removed. The amounts and times
+    /// are kept.
+    fn purge_user_data(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the total amount of money ever shafted, i.e. the sum of the
+    /// amounts of all transactions.
+    ///
+    /// If `exclude_reversed` is set then reversed transactions and their
+    /// reversals aren't counted.
+    fn get_total_shafted(
+        &self,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get a user's position on the leaderboard, where rank 1 has the lowest
+    /// balance. Users with equal balances share a rank. Returns `None` if the
+    /// user doesn't exist.
+    fn get_user_rank(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<i64>, DatabaseError>>;
+
+    /// Get the balances of the given users in one go. Unknown users are
+    /// omitted from the result, while known users with no transactions have a
+    /// balance of 0.
+    fn get_balances_for_users(
+        &self,
+        user_ids: Vec<UserId>,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>>;
+
+    /// Get all users who haven't been part of a transaction since the cutoff,
+    /// including users with no transactions who registered before the cutoff.
+    /// Users who registered before registration times were recorded count as
+    /// having registered at the start of time.
+    fn get_inactive_users_since(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>>;
+
+    /// Get all users who haven't been part of a transaction since `since`,
+    /// e.g. to nudge dormant members, in ID order. Unlike
+    /// [get_inactive_users_since](Database::get_inactive_users_since), users
+    /// with no transactions at all are always included, however recently
+    /// they registered.
+    fn get_inactive_users(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>>;
+
+    /// Update a user's display name to match their Github profile, unless
+    /// they've manually overridden it.
+    fn sync_display_name_from_github(
+        &self,
+        github_user_id: GithubId,
+        new_display_name: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get a transaction along with both parties' display names and current
+    /// balances, or `None` if there's no transaction with that ID.
+    fn get_transaction_detail(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<TransactionDetail>, DatabaseError>>;
+
+    /// Mark a transaction as deleted. The row is kept so that it can be
+    /// restored, but it no longer counts towards balances or the feed.
+    fn soft_delete_transaction(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Undo a previous [Database::soft_delete_transaction].
+    fn restore_transaction(&self, id: i64) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get every user's balance as of the end of the given month in the
+    /// given timezone, i.e. only counting transactions before local midnight
+    /// at the start of the next month.
+    fn get_month_end_balances(
+        &self,
+        year: i32,
+        month: u32,
+        tz: Tz,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>>;
+
+    /// Record that the token has just been used. This is kept separate from
+    /// [Database::get_user_from_token] so that the auth read path doesn't do
+    /// writes; it's a no-op if the token was already touched in the last
+    /// minute.
+    fn touch_token(&self, token: Token) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the oldest transaction, or `None` if there aren't any yet.
+    fn get_first_transaction(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>>;
+
+    /// Attach a URL, e.g. of a receipt, to a transaction. Returns the new
+    /// attachment's ID.
+    ///
+    /// A transaction can have at most [MAX_ATTACHMENTS_PER_TRANSACTION]
+    /// attachments.
+    fn add_attachment(
+        &self,
+        transaction_id: i64,
+        url: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get a transaction's attachments, oldest first.
+    fn get_attachments(
+        &self,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Attachment>, DatabaseError>>;
+
+    /// Get a map from local user ID to the number of transactions they've
+    /// been part of, as either shafter or shaftee. Ordered by most active
+    /// first, and limited to `limit` users.
+    ///
+    /// If `exclude_reversed` is set then reversed transactions and their
+    /// reversals aren't counted.
+    fn get_most_active_users(
+        &self,
+        limit: u32,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>>;
+
+    /// Get a user by their display name, ignoring case. Display names aren't
+    /// unique, so if several users share the name then the one with the
+    /// lowest user ID is returned.
+    fn get_user_by_display_name(
+        &self,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>>;
+
+    /// Get a map from team to the sum of its members' balances, ordered by
+    /// ascending balance. Users not in a team are omitted.
+    fn get_team_balances(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>>;
+
+    /// Commit several [Transaction]s atomically: either all of them are
+    /// committed or, if any is invalid, none are.
+    fn shaft_users(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Commit several [Transaction]s independently, returning the result of
+    /// each in the same order as the input. Unlike [Database::shaft_users],
+    /// invalid transactions don't stop the rest from being committed.
+    fn try_shaft_users(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> LocalBoxFuture<'static, Result<Vec<Result<(), DatabaseError>>, DatabaseError>>;
+
+    /// Get pairs of users who have shafted each other in both directions, so
+    /// could net out their debts. Returns `(a, b, a_to_b, b_to_a)` tuples,
+    /// where `a_to_b` is the total that `a` has shafted `b` and vice versa,
+    /// with each pair appearing once (`a` being the lower user ID).
+    fn get_nettable_pairs(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Vec<NettablePair>, DatabaseError>>;
+
+    /// Get the users with the highest balance (i.e. the biggest creditor) and
+    /// the lowest balance (i.e. the biggest debtor), in that order. Both are
+    /// `None` if there are no users.
+    fn get_balance_extremes(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<BalanceExtremes, DatabaseError>>;
+
+    /// Delete a transaction, e.g. one entered by mistake.
+    ///
+    /// This is a soft delete (c.f. [Database::soft_delete_transaction]), so
+    /// that history is kept and the deletion can be undone with
+    /// [Database::restore_transaction].
+    fn delete_transaction(&self, id: i64) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Change a user's display name. Leading and trailing whitespace is
+    /// trimmed, and the name must not be empty.
+    ///
+    /// The name is then marked as overridden, so it's no longer synced from
+    /// Github (c.f. [Database::sync_display_name_from_github]).
+    fn set_display_name(
+        &self,
+        user_id: UserId,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Delete all tokens that have expired, returning how many were deleted.
+    fn purge_expired_tokens(&self) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Delete all of a user's access tokens, logging them out everywhere.
+    /// Returns the number of tokens revoked.
+    fn delete_all_tokens_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Get the `limit` users with the lowest balances if `ascending` is set,
+    /// i.e. the biggest debtors, or otherwise the highest balances.
+    /// Deactivated users are only included if `include_inactive` is set.
+    fn get_leaderboard(
+        &self,
+        limit: u32,
+        ascending: bool,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>>;
+
+    /// Get the net balance between two users, i.e. the total `user_a` has
+    /// shafted `user_b` minus the total `user_b` has shafted `user_a`. A
+    /// positive result means `user_b` owes `user_a`. Errors with
+    /// [DatabaseError::UnknownUser] if either user doesn't exist.
+    fn get_balance_between(
+        &self,
+        user_a: UserId,
+        user_b: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get a user by their user ID, or `None` if there's no such user.
+    fn get_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>>;
+
+    /// Search for users whose display name contains `query`, ignoring case
+    /// and accents (so "jose" finds "José"), ordered by display name. At
+    /// most `limit` users are returned, capped at [MAX_SEARCH_RESULTS]. `%`
+    /// and `_` in the query match literally.
+    fn search_users(
+        &self,
+        query: String,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>>;
+
+    /// Export all transactions as CSV, ordered by ID, with a header row of
+    /// `id,shafter,shaftee,amount,datetime,reason`. Times are in RFC 3339.
+    fn export_transactions_csv(&self) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
+
+    /// Count the transactions where `shafter` shafted `shaftee`. Unknown
+    /// users simply have no transactions.
+    fn count_transactions_between(
+        &self,
+        shafter: UserId,
+        shaftee: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get the most recent `limit` transactions that happened between `from`
+    /// and `to` inclusive, most recent first. Errors with
+    /// [DatabaseError::InvalidInput] if `from` is after `to`.
+    fn get_transactions_in_range(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Bring the database schema up to date, creating it if necessary. Safe
+    /// to call on every start up, as already applied migrations are skipped.
+    fn migrate(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Summarise each user's transactions that happened between `from` and
+    /// `to` inclusive, ordered by user ID. Users with no transactions in the
+    /// range are omitted. Errors with [DatabaseError::InvalidInput] if `from`
+    /// is after `to`.
+    fn get_summary(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<UserSummary>, DatabaseError>>;
+
+    /// Get local user ID by their ID with an identity provider, e.g. their
+    /// login ID for [GITHUB_PROVIDER].
+    fn get_user_by_identity(
+        &self,
+        provider: String,
+        provider_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>>;
+
+    /// Link an identity with a provider to an existing user, so that they
+    /// can log in with it. Linking an identity to the user it's already
+    /// linked to is a no-op, while linking it to a different user is a
+    /// [DatabaseError::Conflict].
+    fn link_identity(
+        &self,
+        user_id: UserId,
+        provider: String,
+        provider_id: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Merge the user `from_user_id` into `into_user_id`, e.g. if someone
+    /// accidentally created two accounts. All of `from_user_id`'s
+    /// transactions, tokens and identities are moved over, so their balances
+    /// add up, and then `from_user_id` is deleted. Transactions between the
+    /// two users would end up as the user shafting themselves, so they're
+    /// soft deleted as with
+    /// [soft_delete_transaction](Database::soft_delete_transaction) first.
+    /// They don't affect the merged balance either way. Errors with
+    /// [DatabaseError::UnknownUser] if either user doesn't exist, or
+    /// [DatabaseError::InvalidInput] if they're the same user.
+    fn merge_users(
+        &self,
+        from_user_id: UserId,
+        into_user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Stream all live transactions in ID order. Unlike the other queries
+    /// the results aren't all held in memory at once, which makes this
+    /// suitable for e.g. exports. The stream ends after the first error.
+    fn stream_all_transactions(
+        &self,
+    ) -> LocalBoxStream<'static, Result<Transaction, DatabaseError>>;
+
+    /// Get the most recent transaction where `shafter` shafted `shaftee`, if
+    /// any.
+    fn get_latest_transaction_between(
+        &self,
+        shafter: UserId,
+        shaftee: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>>;
+
+    /// Like [`get_last_transactions`](Database::get_last_transactions), but
+    /// if `min_amount` is given only transactions of at least that amount
+    /// are returned.
+    fn get_last_transactions_filtered(
+        &self,
+        limit: u32,
+        min_amount: Option<i64>,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Record a settlement between two users, i.e. a single transaction that
+    /// brings their [balance](Database::get_balance_between) to zero. Returns
+    /// the balance that was settled, with the same sign convention as
+    /// [Database::get_balance_between], or 0 if there was nothing to settle
+    /// (in which case nothing is recorded).
+    ///
+    /// The settlement isn't subject to
+    /// [PoolConfig::max_amount](crate::db::PoolConfig::max_amount), since it
+    /// only cancels out existing shafts.
+    fn settle_between(
+        &self,
+        user_a: UserId,
+        user_b: UserId,
+        reason: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get the total amount currently owed across all users, i.e. the sum of
+    /// all positive balances (which equals minus the sum of all negative
+    /// ones). Returns 0 if nobody owes anything.
+    fn get_total_outstanding(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get details of the user's unexpired access tokens, oldest first.
+    fn list_tokens_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Vec<TokenInfo>, DatabaseError>>;
+
+    /// Shaft several users at once with the same reason and time, e.g. to
+    /// split the cost of a group meal. Like [Database::shaft_users], either
+    /// every shaft is committed or, if any shaftee is unknown or any amount
+    /// invalid, none are.
+    fn shaft_many(
+        &self,
+        shafter: UserId,
+        shaftees: Vec<(UserId, i64)>,
+        reason: String,
+        datetime: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get counts of users and transactions along with the total amount
+    /// owed, without loading every user.
+    fn get_stats(&self) -> LocalBoxFuture<'static, Result<SystemStats, DatabaseError>>;
+
+    /// Undo a transaction by recording a new one for the same amount with
+    /// the shafter and shaftee swapped, linked back to the original so the
+    /// audit trail is kept. Returns the new transaction's ID.
+    ///
+    /// Errors with [DatabaseError::UnknownTransaction] if there's no such
+    /// live transaction, or [DatabaseError::AlreadyReversed] if it has
+    /// already been reversed.
+    fn reverse_transaction(
+        &self,
+        id: i64,
+        reason: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Deactivate a user who has left, or reactivate them. Deactivated users
+    /// are hidden from [Database::get_all_users] and the leaderboard by
+    /// default, but their transactions still count towards everyone's
+    /// balances.
+    fn set_user_active(
+        &self,
+        user_id: UserId,
+        active: bool,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get a user's balance as it was at the given time, i.e. counting only
+    /// transactions up to and including then. Unknown users are a
+    /// [DatabaseError::UnknownUser] error.
+    fn get_balance_at(
+        &self,
+        user: UserId,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get the last `limit` transactions filed under `category`, most recent
+    /// first.
+    fn get_transactions_by_category(
+        &self,
+        category: String,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Get the total amount shafted per category, largest first.
+    /// Uncategorized transactions are totalled under the empty string.
+    /// Reversed transactions and their reversals are left out.
+    fn get_category_totals(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>>;
+
+    /// Bulk load historical transactions, e.g. when migrating from another
+    /// system, atomically. Returns the number inserted, which excludes any
+    /// skipped as duplicate idempotency keys.
+    ///
+    /// If `validate` is false the data is trusted, so none of the checks
+    /// [shaft_user](Database::shaft_user) does are made, including that the
+    /// users exist. The shaft listener is never called.
+    fn import_transactions(
+        &self,
+        transactions: Vec<Transaction>,
+        validate: bool,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Get everyone the user has transacted with, along with the net balance
+    /// between them as returned by
+    /// [get_balance_between](Database::get_balance_between) with the user
+    /// first, i.e. positive if the counterparty owes the user. Ordered by
+    /// the size of the balance, largest first. Unknown users are a
+    /// [DatabaseError::UnknownUser] error.
+    fn get_counterparties(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Vec<(User, i64)>, DatabaseError>>;
+
+    /// Run all the checks [shaft_user](Database::shaft_user) would on the
+    /// transaction, erroring exactly as it would, but without committing it.
+    /// Returns the balances its parties would be left with. A resubmission
+    /// of an existing idempotency key leaves them unchanged.
+    fn validate_shaft(
+        &self,
+        transaction: &Transaction,
+    ) -> LocalBoxFuture<'static, Result<ShaftPreview, DatabaseError>>;
+
+    /// Get a transaction by its ID, or `None` if there's no live transaction
+    /// with that ID.
+    fn get_transaction(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>>;
+
+    /// Get the user's counterparties (c.f. [Database::get_counterparties])
+    /// split into those who owe them and those they owe, leaving out anyone
+    /// they're square with. Unknown users are a [DatabaseError::UnknownUser]
+    /// error.
+    fn get_personal_ledger(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<PersonalLedger, DatabaseError>>;
+
+    /// Get the ID of the user a Shaft access token belongs to, or `None` if
+    /// it's unknown or has expired. Unlike
+    /// [get_user_from_token](Database::get_user_from_token) this doesn't load
+    /// the user, so is cheap enough to check every request with.
+    fn resolve_token(
+        &self,
+        token: Token,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>>;
+
+    /// Get the total the user shafted on each UTC day of the trailing `days`
+    /// days, including today, in date order. Days without any shafts are
+    /// left out.
+    fn get_daily_activity(
+        &self,
+        user_id: UserId,
+        days: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<(chrono::NaiveDate, i64)>, DatabaseError>>;
+
+    /// Count the user's live transactions in the given direction, i.e. all
+    /// those [get_transactions_for_user](Database::get_transactions_for_user)
+    /// could return. Unknown users have none.
+    fn count_transactions_for_user(
+        &self,
+        user_id: UserId,
+        direction: TransactionDirection,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Replace a Shaft access token with a new one for the same user and
+    /// scope, with a fresh expiry, atomically. Errors with
+    /// [DatabaseError::InvalidToken] if the old token is unknown or has
+    /// expired, in which case the user needs to log in again.
+    fn rotate_token(
+        &self,
+        old_token: Token,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>>;
+
+    /// Like [`get_last_transactions`](Database::get_last_transactions), but
+    /// with the display names of the users involved. If a user no longer
+    /// exists their ID is used as their name.
+    fn get_last_transactions_named(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<NamedTransaction>, DatabaseError>>;
+
+    /// Export all users, identities, transactions (including deleted ones),
+    /// teams and attachments, and the access tokens if `include_tokens` is
+    /// set, as a single JSON object for backup. Tokens are exported as
+    /// stored, i.e. hashed, but still grant access to whoever restores them.
+    #[cfg(feature = "serde")]
+    fn export_json(
+        &self,
+        include_tokens: bool,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
+
+    /// Restore a backup made by [export_json](Database::export_json) into
+    /// an empty database, in a single SQL transaction. Fails with
+    /// [DatabaseError::InvalidInput], restoring nothing, if the backup can't
+    /// be parsed, refers to users or transactions it doesn't contain, or if
+    /// the database already has any users, identities, transactions, tokens,
+    /// teams or attachments.
+    #[cfg(feature = "serde")]
+    fn import_json(&self, data: &str) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the install's currency, which clients should use to render
+    /// amounts. Defaults to pounds and pence.
+    fn get_currency(&self) -> LocalBoxFuture<'static, Result<Currency, DatabaseError>>;
+
+    /// Set the install's currency, for admins. This only changes how
+    /// amounts are rendered, not the stored amounts. Fails with
+    /// [DatabaseError::InvalidInput] if it has more than
+    /// [Currency::MAX_DECIMAL_PLACES] decimal places.
+    fn set_currency(
+        &self,
+        currency: Currency,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Like [set_display_name](Database::set_display_name), but only if the
+    /// user's [version](User::version) is still `expected_version`, i.e.
+    /// nobody else has changed their name since it was read. Fails with
+    /// [DatabaseError::VersionConflict] if it's changed. Returns the new
+    /// version.
+    fn set_display_name_checked(
+        &self,
+        user_id: UserId,
+        display_name: String,
+        expected_version: i32,
+    ) -> LocalBoxFuture<'static, Result<i32, DatabaseError>>;
+
+    /// Get a page of the transactions involving any of the given users, e.g.
+    /// for a team's feed, newest first. `before` is as in
+    /// [get_transaction_feed](Database::get_transaction_feed), but the total
+    /// isn't counted.
+    fn get_transactions_for_users(
+        &self,
+        user_ids: Vec<UserId>,
+        before: Option<i64>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>>;
+
+    /// Get the largest individual transactions ever, with the display names
+    /// of the users involved, largest (then newest) first. Reversed
+    /// transactions and their reversals are left out, so a typo that was
+    /// reversed doesn't top the list.
+    fn get_largest_transactions(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<NamedTransaction>, DatabaseError>>;
+
+    /// Get the IDs of all users, including inactive ones, in ID order. This
+    /// is much cheaper than [get_all_users](Database::get_all_users) as it
+    /// doesn't work out balances.
+    fn get_user_ids(&self) -> LocalBoxFuture<'static, Result<Vec<UserId>, DatabaseError>>;
+
+    /// Work out every user's balance both from per-user sums (as in
+    /// [get_balance_for_user](Database::get_balance_for_user)) and from the
+    /// grouped sums (as in [get_all_users](Database::get_all_users)),
+    /// returning a [BalanceMismatch] for each user where the
+    /// two disagree, in ID order. This should always be empty; it's a
+    /// diagnostic, so ignores any balance cache or read replica.
+    fn verify_balances(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Vec<BalanceMismatch>, DatabaseError>>;
 }
 
 /// Error using database.
@@ -108,29 +1475,715 @@ pub enum DatabaseError {
         backtrace: Backtrace,
     },
 
+    /// Timed out waiting for a connection because every connection in the
+    /// pool was in use. The timeout is
+    /// [PoolConfig::connection_timeout](crate::db::PoolConfig::connection_timeout).
+    #[snafu(display("Timed out after {:?} waiting for a DB connection", timeout))]
+    PoolTimeout {
+        timeout: Duration,
+        backtrace: Backtrace,
+    },
+
+    /// The caller dropped the operation's future before it committed, so it
+    /// was rolled back.
+    #[snafu(display("Operation cancelled before committing"))]
+    Cancelled,
+
     /// SQLite error.
-    #[snafu(display("Sqlite error: {}", source))]
+    #[snafu(display("Sqlite error during {}: {}", operation, source))]
     SqliteError {
+        /// The query that failed, e.g. `shaft_user.begin`.
+        operation: &'static str,
         source: rusqlite::Error,
         backtrace: Backtrace,
     },
 
     /// Postgres error.
-    #[snafu(display("Postgres error: {}", source))]
+    #[snafu(display("Postgres error during {}: {}", operation, source))]
     PostgresError {
+        /// The query that failed, e.g. `shaft_user.begin`.
+        operation: &'static str,
         source: ::postgres::Error,
         backtrace: Backtrace,
     },
 
+    /// Error writing JSON.
+    #[cfg(feature = "serde")]
+    #[snafu(display("JSON error: {}", source))]
+    JsonError {
+        source: serde_json::Error,
+        backtrace: Backtrace,
+    },
+
+    /// Error writing CSV.
+    #[snafu(display("CSV error: {}", source))]
+    CsvError {
+        source: csv::Error,
+        backtrace: Backtrace,
+    },
+
+    /// A database operation panicked.
+    #[snafu(display("Database worker panicked: {}", message))]
+    WorkerPanic { message: String },
+
     /// One of the users is unknown.
     #[snafu(display("Unknown user: {}", user_id))]
     UnknownUser { user_id: String },
+
+    /// The user's details changed since they were read, c.f.
+    /// [Database::set_display_name_checked].
+    #[snafu(display(
+        "User {} was changed by someone else since version {}",
+        user_id,
+        expected_version
+    ))]
+    VersionConflict {
+        user_id: String,
+        expected_version: i32,
+    },
+
+    /// The token is unknown or has expired, so the user must log in again.
+    #[snafu(display("Invalid or expired token"))]
+    InvalidToken,
+
+    /// There's no (live) transaction with the given ID.
+    #[snafu(display("Unknown transaction: {}", id))]
+    UnknownTransaction { id: i64 },
+
+    /// The shafter has hit [PoolConfig::shaft_rate_limit].
+    #[snafu(display("Too many shafts, retry after {} seconds", retry_after_secs))]
+    RateLimited { retry_after_secs: i64 },
+
+    /// The transaction already has a live reversal.
+    #[snafu(display("Transaction {} has already been reversed", id))]
+    AlreadyReversed { id: i64 },
+
+    /// The given year and month don't form a valid date.
+    #[snafu(display("Invalid month: {}-{}", year, month))]
+    InvalidMonth { year: i32, month: u32 },
+
+    /// A value given by the user was invalid.
+    #[snafu(display("Invalid input: {}", message))]
+    InvalidInput { message: String },
+
+    /// Attachment URLs must not be empty.
+    #[snafu(display("Attachment URL is empty"))]
+    EmptyAttachmentUrl,
+
+    /// The transaction already has the maximum number of attachments.
+    #[snafu(display("Transaction {} already has {} attachments", transaction_id, max))]
+    TooManyAttachments { transaction_id: i64, max: usize },
+
+    /// A write would have violated a uniqueness constraint, e.g. because the
+    /// row already exists.
+    #[snafu(display("Conflict on unique constraint: {}", constraint))]
+    Conflict { constraint: String },
+
+    /// Users can't shaft themselves.
+    #[snafu(display("User tried to shaft themselves: {}", user_id))]
+    SelfShaft { user_id: String },
+
+    /// The shaft's reason is over the configured length, in characters.
+    #[snafu(display("Reason of length {} is longer than the maximum of {}", len, max))]
+    ReasonTooLong { len: usize, max: usize },
+
+    /// Shaft amounts must be positive.
+    #[snafu(display("Invalid amount: {}", amount))]
+    InvalidAmount { amount: i64 },
+
+    /// The shaft's amount is over the configured limit.
+    #[snafu(display("Amount {} is larger than the maximum of {}", amount, max))]
+    AmountTooLarge { amount: i64, max: i64 },
+
+    /// The shaft would take the shaftee further into debt than
+    /// [PoolConfig::max_debt] allows.
+    #[snafu(display(
+        "Shaft would leave {} with a balance of {}, below the limit of -{}",
+        user_id,
+        balance_would_be,
+        limit
+    ))]
+    DebtLimitExceeded {
+        user_id: String,
+        balance_would_be: i64,
+        limit: i64,
+    },
+
+    /// The user's balance doesn't fit in an `i64`, which should only happen
+    /// with data imported without validation.
+    #[snafu(display("Balance of user {} overflowed", user_id))]
+    BalanceOverflow { user_id: String },
+
+    /// The transaction's time is too far in the future.
+    #[snafu(display("Transaction time is in the future: {}", datetime))]
+    TimestampInFuture {
+        datetime: chrono::DateTime<chrono::Utc>,
+    },
+
+    /// The transaction's time is implausibly far in the past.
+    #[snafu(display("Transaction time is too old: {}", datetime))]
+    TimestampTooOld {
+        datetime: chrono::DateTime<chrono::Utc>,
+    },
+}
+
+impl DatabaseError {
+    /// Turn a unique constraint violation from the underlying database into
+    /// a [DatabaseError::Conflict], so it can be told apart from other
+    /// errors. Other errors are returned unchanged.
+    fn map_unique_violation(self) -> DatabaseError {
+        // The extended result codes aren't in the generated bindings.
+        const SQLITE_CONSTRAINT_PRIMARYKEY: i32 = rusqlite::ffi::SQLITE_CONSTRAINT | (6 << 8);
+        const SQLITE_CONSTRAINT_UNIQUE: i32 = rusqlite::ffi::SQLITE_CONSTRAINT | (8 << 8);
+
+        match &self {
+            DatabaseError::SqliteError {
+                source: rusqlite::Error::SqliteFailure(error, message),
+                ..
+            } if error.extended_code == SQLITE_CONSTRAINT_UNIQUE
+                || error.extended_code == SQLITE_CONSTRAINT_PRIMARYKEY =>
+            {
+                // SQLite only names the constrained columns, in a message like
+                // "UNIQUE constraint failed: users.user_id".
+                let constraint = message
+                    .as_ref()
+                    .map(|message| {
+                        message
+                            .trim_start_matches("UNIQUE constraint failed: ")
+                            .to_string()
+                    })
+                    .unwrap_or_default();
+                DatabaseError::Conflict { constraint }
+            }
+            DatabaseError::PostgresError { source, .. }
+                if source.code() == Some(&::postgres::error::SqlState::UNIQUE_VIOLATION) =>
+            {
+                let constraint = std::error::Error::source(source)
+                    .and_then(|err| err.downcast_ref::<::postgres::error::DbError>())
+                    .and_then(|err| err.constraint())
+                    .unwrap_or_default()
+                    .to_string();
+                DatabaseError::Conflict { constraint }
+            }
+            _ => self,
+        }
+    }
+
+    /// Whether the error is likely to go away if the operation is retried,
+    /// e.g. the database being locked or a connection failing, as opposed to
+    /// a problem with the query itself such as a constraint violation.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DatabaseError::ConnectionPoolError { .. } => true,
+            // The pool is overloaded, so retrying would just make it worse.
+            DatabaseError::PoolTimeout { .. } => false,
+            DatabaseError::SqliteError {
+                source: rusqlite::Error::SqliteFailure(error, _),
+                ..
+            } => matches!(
+                error.code,
+                rusqlite::ErrorCode::DatabaseBusy
+                    | rusqlite::ErrorCode::DatabaseLocked
+                    | rusqlite::ErrorCode::SystemIOFailure
+                    | rusqlite::ErrorCode::CannotOpen
+            ),
+            // Errors without a SQLSTATE didn't come from the server, so are
+            // connection failures. Class 08 are connection exceptions.
+            DatabaseError::PostgresError { source, .. } => match source.code() {
+                None => true,
+                Some(code) => code.code().starts_with("08"),
+            },
+            _ => false,
+        }
+    }
+}
+
+/// The default for how far in the future a transaction time may be, to allow
+/// for clock skew between client and server.
+pub const DEFAULT_MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+/// The default for how long a new access token is valid for.
+pub const DEFAULT_TOKEN_LIFETIME_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// The default for the longest reason a shaft may have, in characters.
+pub const DEFAULT_MAX_REASON_LENGTH: usize = 500;
+
+/// The default for the most rows a query may be asked for.
+pub const DEFAULT_MAX_LIMIT: u32 = 1000;
+
+/// The minimum time between updates of a token's `last_used_at`.
+const TOKEN_TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// The identity provider name used for Github logins, c.f.
+/// [Database::get_user_by_identity].
+pub const GITHUB_PROVIDER: &str = "github";
+
+/// The maximum number of results [Database::search_users] returns, whatever
+/// limit is asked for.
+pub const MAX_SEARCH_RESULTS: u32 = 50;
+
+/// The hard limit on a single shaft's amount, in pence, whatever
+/// [PoolConfig::max_amount] says. This leaves room for millions of maximal
+/// shafts before a balance could overflow.
+pub const MAX_AMOUNT: i64 = 1_000_000_000_000;
+
+/// The maximum number of attachments a single transaction may have.
+pub const MAX_ATTACHMENTS_PER_TRANSACTION: usize = 5;
+
+/// Transactions before this time (2000-01-01T00:00:00Z) are rejected as
+/// implausible.
+const MIN_TRANSACTION_TIMESTAMP: i64 = 946_684_800;
+
+/// Check that a query limit is positive, clamping it to `max_limit` and
+/// converting it to the `i64` that `LIMIT` is bound as.
+fn validate_limit(limit: u32, max_limit: u32) -> Result<i64, DatabaseError> {
+    if limit == 0 {
+        return Err(DatabaseError::InvalidInput {
+            message: "limit must be positive".to_string(),
+        });
+    }
+
+    Ok(i64::from(limit.min(max_limit)))
+}
+
+/// Check that a shaft's amount is positive, so that shafting can't be used
+/// to silently reverse a debt, and no more than `max_amount` if set or
+/// [MAX_AMOUNT] otherwise.
+fn validate_amount(amount: i64, max_amount: Option<i64>) -> Result<(), DatabaseError> {
+    if amount <= 0 {
+        return Err(DatabaseError::InvalidAmount { amount });
+    }
+
+    let max = max_amount.map_or(MAX_AMOUNT, |max| max.min(MAX_AMOUNT));
+    if amount > max {
+        return Err(DatabaseError::AmountTooLarge { amount, max });
+    }
+
+    Ok(())
+}
+
+/// Run all the checks for a new shaft that don't need the database: that the
+/// amount is positive and within the limit, that the reason isn't too long,
+/// that the shafter isn't shafting themselves, and that the time is
+/// plausible.
+fn validate_shaft(
+    transaction: &Transaction,
+    max_skew: chrono::Duration,
+    max_amount: Option<i64>,
+    max_reason_length: usize,
+) -> Result<(), DatabaseError> {
+    validate_amount(transaction.amount, max_amount)?;
+
+    // Count characters rather than bytes, so that e.g. emoji aren't
+    // penalised.
+    let len = transaction
+        .reason
+        .as_deref()
+        .map_or(0, |reason| reason.trim_end().chars().count());
+    if len > max_reason_length {
+        return Err(DatabaseError::ReasonTooLong {
+            len,
+            max: max_reason_length,
+        });
+    }
+
+    if transaction
+        .category
+        .as_deref()
+        .is_some_and(|c| c.trim().is_empty())
+    {
+        return Err(DatabaseError::InvalidInput {
+            message: "category must not be empty".to_string(),
+        });
+    }
+
+    if transaction.shafter == transaction.shaftee {
+        return Err(DatabaseError::SelfShaft {
+            user_id: transaction.shafter.clone(),
+        });
+    }
+
+    validate_transaction_time(transaction.datetime, max_skew)
+}
+
+/// Split a user's counterparties, as returned by
+/// [Database::get_counterparties], into a [PersonalLedger].
+fn personal_ledger(counterparties: Vec<(User, i64)>) -> PersonalLedger {
+    let mut ledger = PersonalLedger {
+        owed_to_me: Vec::new(),
+        i_owe: Vec::new(),
+    };
+
+    for (user, net) in counterparties {
+        if net > 0 {
+            ledger.owed_to_me.push((user, net));
+        } else if net < 0 {
+            ledger.i_owe.push((user, net));
+        }
+    }
+
+    ledger
+}
+
+/// Work out the balances that committing `transaction` for `amount` would
+/// leave its parties with, given their current ones.
+fn project_shaft(
+    transaction: &Transaction,
+    shafter_balance: i64,
+    shaftee_balance: i64,
+    amount: i64,
+) -> Result<ShaftPreview, DatabaseError> {
+    let shafter_balance_after =
+        shafter_balance
+            .checked_add(amount)
+            .ok_or_else(|| DatabaseError::BalanceOverflow {
+                user_id: transaction.shafter.clone(),
+            })?;
+    let shaftee_balance_after =
+        shaftee_balance
+            .checked_sub(amount)
+            .ok_or_else(|| DatabaseError::BalanceOverflow {
+                user_id: transaction.shaftee.clone(),
+            })?;
+
+    Ok(ShaftPreview {
+        shafter_balance_after,
+        shaftee_balance_after,
+    })
+}
+
+/// Check that a shaft leaving its shaftee with `balance_after` doesn't take
+/// them past `max_debt`, if set.
+fn check_debt_limit(
+    transaction: &Transaction,
+    balance_after: i64,
+    max_debt: Option<i64>,
+) -> Result<(), DatabaseError> {
+    match max_debt {
+        Some(limit) if balance_after < -limit => Err(DatabaseError::DebtLimitExceeded {
+            user_id: transaction.shaftee.clone(),
+            balance_would_be: balance_after,
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Run the shaft listener, if any, with a newly committed transaction. A
+/// panic in the listener is swallowed, as the shaft has already happened.
+fn notify_shaft_listener(listener: Option<&ShaftListener>, transaction: &Transaction) {
+    if let Some(listener) = listener {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| listener(transaction)));
+    }
+}
+
+/// Build the transactions for [Database::shaft_many], one per shaftee.
+fn split_shaft(
+    shafter: UserId,
+    shaftees: Vec<(UserId, i64)>,
+    reason: String,
+    datetime: chrono::DateTime<chrono::Utc>,
+) -> Vec<Transaction> {
+    shaftees
+        .into_iter()
+        .map(|(shaftee, amount)| Transaction {
+            id: None,
+            shafter: shafter.to_string(),
+            shaftee: shaftee.0,
+            amount,
+            datetime,
+            reason: Some(reason.clone()),
+            idempotency_key: None,
+            category: None,
+        })
+        .collect()
+}
+
+/// Build the transaction that cancels out a non-zero `balance` between two
+/// users, as returned by [Database::get_balance_between].
+fn settlement(user_a: &str, user_b: &str, balance: i64, reason: String) -> Transaction {
+    let (shafter, shaftee) = if balance > 0 {
+        (user_b, user_a)
+    } else {
+        (user_a, user_b)
+    };
+
+    Transaction {
+        id: None,
+        shafter: shafter.to_string(),
+        shaftee: shaftee.to_string(),
+        amount: balance.abs(),
+        datetime: chrono::Utc::now(),
+        reason: Some(reason),
+        idempotency_key: None,
+        category: None,
+    }
+}
+
+/// Check that a transaction time is no more than `max_skew` in the future and
+/// not before [MIN_TRANSACTION_TIMESTAMP].
+fn validate_transaction_time(
+    datetime: chrono::DateTime<chrono::Utc>,
+    max_skew: chrono::Duration,
+) -> Result<(), DatabaseError> {
+    if datetime > chrono::Utc::now() + max_skew {
+        return Err(DatabaseError::TimestampInFuture { datetime });
+    }
+
+    if datetime.timestamp() < MIN_TRANSACTION_TIMESTAMP {
+        return Err(DatabaseError::TimestampTooOld { datetime });
+    }
+
+    Ok(())
+}
+
+/// Fold a display name or search query so that comparing the results
+/// ignores case and accents, by decomposing characters and dropping the
+/// combining marks.
+fn fold_for_search(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Sort users as [Database::get_all_users_sorted] does, breaking ties by
+/// display name (ignoring ASCII case, like SQLite's `NOCASE`) then user ID.
+fn sort_users(users: &mut [User], sort: UserSort) {
+    let name = |user: &User| user.display_name.to_ascii_lowercase();
+    users.sort_by(|a, b| {
+        let by_name = name(a).cmp(&name(b));
+        let order = match sort {
+            UserSort::BalanceAsc => a.balance.cmp(&b.balance).then(by_name),
+            UserSort::BalanceDesc => b.balance.cmp(&a.balance).then(by_name),
+            UserSort::NameAsc => by_name,
+            UserSort::NameDesc => by_name.reverse(),
+        };
+        order.then_with(|| a.user_id.cmp(&b.user_id))
+    });
+}
+
+/// Get the unix timestamp of the start of the window for
+/// [Database::get_daily_activity], i.e. UTC midnight `days - 1` days ago. If
+/// `days` is 0 the window starts tomorrow, so is empty.
+fn activity_window_start(days: u32) -> i64 {
+    let today = chrono::Utc::now().timestamp().div_euclid(86400);
+    (today - i64::from(days) + 1) * 86400
+}
+
+/// Convert a number of days since the unix epoch to a date.
+fn day_to_date(day: i64) -> chrono::NaiveDate {
+    chrono::NaiveDateTime::from_timestamp(day * 86400, 0).date()
+}
+
+/// Get the unix timestamp of local midnight at the start of the month after
+/// the given one, i.e. the end of the given month. If a DST change skips local
+/// midnight then the day starts an hour later.
+fn month_end_timestamp(year: i32, month: u32, tz: Tz) -> Result<i64, DatabaseError> {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+
+    let next_month_start = match (
+        chrono::NaiveDate::from_ymd_opt(year, month, 1),
+        chrono::NaiveDate::from_ymd_opt(next_year, next_month, 1),
+    ) {
+        (Some(_), Some(next_month_start)) => next_month_start.and_hms(0, 0, 0),
+        _ => return Err(DatabaseError::InvalidMonth { year, month }),
+    };
+
+    tz.from_local_datetime(&next_month_start)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(next_month_start + chrono::Duration::hours(1)))
+                .earliest()
+        })
+        .map(|month_end| month_end.timestamp())
+        .ok_or(DatabaseError::InvalidMonth { year, month })
+}
+
+/// Render transactions as CSV, with a header row and times in RFC 3339.
+fn transactions_to_csv(
+    transactions: impl IntoIterator<Item = Transaction>,
+) -> Result<String, DatabaseError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+
+    writer
+        .write_record(["id", "shafter", "shaftee", "amount", "datetime", "reason"])
+        .context(CsvError)?;
+
+    for transaction in transactions {
+        writer
+            .write_record(&[
+                transaction.id.map(|id| id.to_string()).unwrap_or_default(),
+                transaction.shafter,
+                transaction.shaftee,
+                transaction.amount.to_string(),
+                transaction.datetime.to_rfc3339(),
+                transaction.reason.unwrap_or_default(),
+            ])
+            .context(CsvError)?;
+    }
+
+    let bytes = writer
+        .into_inner()
+        .map_err(|err| csv::Error::from(err.into_error()))
+        .context(CsvError)?;
+
+    // We only ever write strings, so the output is valid UTF-8.
+    Ok(String::from_utf8(bytes).expect("CSV output is valid UTF-8"))
+}
+
+/// Check a currency passed to [Database::set_currency].
+fn validate_currency(currency: &Currency) -> Result<(), DatabaseError> {
+    if currency.decimal_places > Currency::MAX_DECIMAL_PLACES {
+        return Err(DatabaseError::InvalidInput {
+            message: format!(
+                "currency may have at most {} decimal places",
+                Currency::MAX_DECIMAL_PLACES
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+/// Serialize a backup for [Database::export_json].
+#[cfg(feature = "serde")]
+fn backup_to_json(backup: &Backup) -> Result<String, DatabaseError> {
+    serde_json::to_string(backup).context(JsonError)
+}
+
+/// Parse a backup for [Database::import_json], checking that IDs are unique
+/// and every reference to a user or transaction is to one in the backup.
+#[cfg(feature = "serde")]
+fn backup_from_json(data: &str) -> Result<Backup, DatabaseError> {
+    let invalid = |message: String| DatabaseError::InvalidInput {
+        message: format!("invalid backup: {}", message),
+    };
+
+    let backup: Backup = serde_json::from_str(data).map_err(|err| invalid(err.to_string()))?;
+
+    let mut user_ids = HashSet::new();
+    for user in &backup.users {
+        if !user_ids.insert(user.user_id.as_str()) {
+            return Err(invalid(format!("duplicate user {}", user.user_id)));
+        }
+    }
+
+    let mut transaction_ids = HashSet::new();
+    for transaction in &backup.transactions {
+        if !transaction_ids.insert(transaction.id) {
+            return Err(invalid(format!("duplicate transaction {}", transaction.id)));
+        }
+    }
+
+    let check_user = |user_id: &str, what: &str| {
+        if user_ids.contains(user_id) {
+            Ok(())
+        } else {
+            Err(invalid(format!(
+                "{} refers to unknown user {}",
+                what, user_id
+            )))
+        }
+    };
+    let check_transaction = |id: i64, what: &str| {
+        if transaction_ids.contains(&id) {
+            Ok(())
+        } else {
+            Err(invalid(format!(
+                "{} refers to unknown transaction {}",
+                what, id
+            )))
+        }
+    };
+
+    for identity in &backup.identities {
+        check_user(&identity.user_id, "identity")?;
+    }
+    for transaction in &backup.transactions {
+        check_user(&transaction.shafter, "transaction")?;
+        check_user(&transaction.shaftee, "transaction")?;
+        if let Some(id) = transaction.reversed_transaction_id {
+            check_transaction(id, "reversal")?;
+        }
+    }
+    for token in &backup.tokens {
+        check_user(&token.user_id, "token")?;
+    }
+    for team in &backup.teams {
+        check_user(&team.user_id, "team")?;
+    }
+    for attachment in &backup.attachments {
+        check_transaction(attachment.transaction_id, "attachment")?;
+    }
+
+    Ok(backup)
+}
+
+/// Serialize time as an RFC 3339 string, e.g. `2020-01-31T18:00:00+00:00`.
+#[cfg(feature = "serde")]
+fn serialize_rfc3339<S>(
+    date: &chrono::DateTime<chrono::Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&date.to_rfc3339())
+}
+
+/// Deserialize time from an RFC 3339 string, c.f. [serialize_rfc3339].
+#[cfg(feature = "serde")]
+fn deserialize_rfc3339<'de, D>(deserializer: D) -> Result<chrono::DateTime<chrono::Utc>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let date = String::deserialize(deserializer)?;
+
+    chrono::DateTime::parse_from_rfc3339(&date)
+        .map(|date| date.with_timezone(&chrono::Utc))
+        .map_err(|err| serde::de::Error::custom(format!("invalid time {:?}: {}", date, err)))
 }
 
 /// Serialize time into timestamp.
+#[cfg(feature = "serde")]
 fn serialize_time<S>(date: &chrono::DateTime<chrono::Utc>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
     serializer.serialize_i64(date.timestamp())
 }
+
+/// Like [serialize_time], but for optional times which are serialized as
+/// null if missing.
+#[cfg(feature = "serde")]
+fn serialize_optional_time<S>(
+    date: &Option<chrono::DateTime<chrono::Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match date {
+        Some(date) => serializer.serialize_some(&date.timestamp()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Deserialize time from a timestamp, c.f. [serialize_time].
+#[cfg(feature = "serde")]
+fn deserialize_time<'de, D>(deserializer: D) -> Result<chrono::DateTime<chrono::Utc>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let timestamp = i64::deserialize(deserializer)?;
+
+    chrono::Utc
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp: {}", timestamp)))
+}
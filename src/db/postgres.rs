@@ -14,7 +14,9 @@ use snafu::ResultExt;
 use std::pin::Pin;
 use std::sync::Arc;
 
-use crate::db::{ConnectionPoolError, Database, DatabaseError, PostgresError, Transaction, User};
+use crate::db::{
+    ConnectionPoolError, Database, DatabaseError, PoolConfig, PostgresError, Transaction, User,
+};
 
 /// An implementation of [Database] using posgtres
 ///
@@ -23,20 +25,30 @@ use crate::db::{ConnectionPoolError, Database, DatabaseError, PostgresError, Tra
 pub struct PostgresDatabase {
     /// Thread pool used to do database operations.
     cpu_pool: CpuPool,
-    /// SQLite connection pool.
+    /// Postgres connection pool.
     db_pool: Arc<r2d2::Pool<PostgresConnectionManager>>,
 }
 
 impl PostgresDatabase {
-    /// Create new instance with given path. If file does not exist a new
-    /// database is created.
-    pub fn with_manager(manager: PostgresConnectionManager) -> PostgresDatabase {
-        let pool = r2d2::Pool::new(manager).unwrap();
+    /// Create new instance with the given connection manager.
+    pub fn with_manager(
+        manager: PostgresConnectionManager,
+    ) -> Result<PostgresDatabase, DatabaseError> {
+        PostgresDatabase::with_config(manager, PoolConfig::default())
+    }
+
+    /// Like [PostgresDatabase::with_manager], but with the given pool
+    /// configuration.
+    pub fn with_config(
+        manager: PostgresConnectionManager,
+        config: PoolConfig,
+    ) -> Result<PostgresDatabase, DatabaseError> {
+        let pool = config.build_pool(manager)?;
 
-        PostgresDatabase {
-            cpu_pool: CpuPool::new_num_cpus(),
+        Ok(PostgresDatabase {
+            cpu_pool: config.build_cpu_pool(),
             db_pool: Arc::new(pool),
-        }
+        })
     }
 }
 
@@ -1,22 +1,49 @@
 use chrono;
 use chrono::TimeZone;
+use chrono_tz::Tz;
 use futures::future::LocalBoxFuture;
-use futures::{compat::Future01CompatExt, FutureExt};
+use futures::stream::{self, LocalBoxStream};
+use futures::{compat::Future01CompatExt, FutureExt, StreamExt, TryFutureExt};
 use futures_cpupool::CpuPool;
+use itertools::Itertools;
 use linear_map::LinearMap;
+use openssl::sha::sha256;
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
 use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
 use rand::{thread_rng, Rng};
 use rusqlite;
-use rusqlite::params;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, OpenFlags};
 use snafu::ResultExt;
 
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 
-use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::db::{ConnectionPoolError, Database, DatabaseError, SqliteError, Transaction, User};
+use crate::db::{
+    activity_window_start, check_debt_limit, day_to_date, fold_for_search, month_end_timestamp,
+    notify_shaft_listener, personal_ledger, project_shaft, settlement, sort_users, split_shaft,
+    transactions_to_csv, validate_currency, validate_limit, validate_shaft, Attachment,
+    BalanceExtremes, BalanceMismatch, ConnectionPoolError, Currency, Database, DatabaseError,
+    DatabaseMetrics, GithubId, NamedTransaction, NettablePair, Page, PersonalLedger, PoolConfig,
+    PoolStats, PoolTimeout, ShaftListener, ShaftPreview, ShaftRateLimit, SortOrder, SqliteError,
+    SystemStats, Token, TokenInfo, TokenScope, Transaction, TransactionDetail,
+    TransactionDirection, User, UserId, UserSort, UserSummary, DEFAULT_MAX_CLOCK_SKEW_SECS,
+    DEFAULT_TOKEN_LIFETIME_SECS, GITHUB_PROVIDER, MAX_ATTACHMENTS_PER_TRANSACTION,
+    MAX_SEARCH_RESULTS, TOKEN_TOUCH_INTERVAL_SECS,
+};
+#[cfg(feature = "serde")]
+use crate::db::{
+    backup_from_json, backup_to_json, Backup, BackupAttachment, BackupIdentity, BackupTeam,
+    BackupToken, BackupTransaction, BackupUser,
+};
 
 /// An implementation of [Database] using sqlite.Database
 ///
@@ -26,354 +53,5424 @@ pub struct SqliteDatabase {
     /// Thread pool used to do database operations.
     cpu_pool: CpuPool,
     /// SQLite connection pool.
-    db_pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
+    db_pool: Arc<ConnectionPool>,
+    /// Connection pool for a read replica, if any, see
+    /// [with_read_replica](SqliteDatabase::with_read_replica).
+    read_pool: Option<Arc<ConnectionPool>>,
+    /// How far in the future a new transaction's time may be.
+    max_clock_skew: chrono::Duration,
+    /// How long new access tokens are valid for.
+    token_lifetime: chrono::Duration,
+    /// How to render amounts.
+    currency: Currency,
+    /// How many times to retry operations that fail with transient errors.
+    max_retries: u32,
+    /// The most rows a query may be asked to return.
+    max_limit: u32,
+    /// The largest amount a single shaft may be for, if any.
+    max_amount: Option<i64>,
+    /// The longest reason a shaft may have, in characters.
+    max_reason_length: usize,
+    /// Whether display names must be unique.
+    require_unique_display_name: bool,
+    /// How often each user may shaft, if limited.
+    shaft_rate_limit: Option<ShaftRateLimit>,
+    /// The most a user may owe, if limited.
+    max_debt: Option<i64>,
+    /// Called with each newly committed shaft.
+    shaft_listener: Option<ShaftListener>,
+    /// Told how long each operation took, if set.
+    metrics: Option<Arc<dyn DatabaseMetrics>>,
+    /// Cached user balances, if enabled.
+    balance_cache: Option<Arc<BalanceCache>>,
+    /// Shared by all clones of this database, so that
+    /// [shutdown](SqliteDatabase::shutdown) can tell whether it's the last.
+    handles: Arc<()>,
 }
 
 impl SqliteDatabase {
+    /// The number of characters in an access token. Tokens are alphanumeric,
+    /// and so URL safe, and generated with the OS's secure RNG.
+    pub const TOKEN_LENGTH: usize = 32;
+
     /// Create new instance with given path. If file does not exist a new
     /// database is created.
-    pub fn with_path<P: AsRef<Path>>(path: P) -> SqliteDatabase {
-        let manager = SqliteConnectionManager::file(path);
-        let pool = r2d2::Pool::new(manager).unwrap();
-
-        SqliteDatabase {
-            cpu_pool: CpuPool::new_num_cpus(),
-            db_pool: Arc::new(pool),
-        }
+    ///
+    /// Fails if the connection pool can't be built, e.g. if the database
+    /// can't be opened.
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Result<SqliteDatabase, DatabaseError> {
+        SqliteDatabase::with_config(path, PoolConfig::default())
     }
 
-    /// Runs the given statements synchronously
-    pub fn run_statements(&self, stmts: &str) -> Result<(), DatabaseError> {
-        let conn = self.db_pool.get().context(ConnectionPoolError)?;
+    /// Like [SqliteDatabase::with_path], but with the given pool
+    /// configuration.
+    pub fn with_config<P: AsRef<Path>>(
+        path: P,
+        config: PoolConfig,
+    ) -> Result<SqliteDatabase, DatabaseError> {
+        SqliteDatabase::with_config_and_cpu_pool(path, config, config.build_cpu_pool())
+    }
 
-        conn.execute_batch(stmts).context(SqliteError)?;
+    /// Like [SqliteDatabase::with_config], but running database operations
+    /// on the given thread pool, e.g. one shared with other databases,
+    /// rather than creating one. [PoolConfig::cpu_pool_threads] is ignored.
+    ///
+    /// Each operation holds a thread for as long as it holds a connection,
+    /// so a pool with fewer threads than [PoolConfig::max_pool_size] leaves
+    /// connections unused, while one with many more just has threads waiting
+    /// for connections (and possibly failing with
+    /// [DatabaseError::PoolTimeout]). When sharing a thread pool, size it for
+    /// the sum of the databases' pool sizes.
+    pub fn with_config_and_cpu_pool<P: AsRef<Path>>(
+        path: P,
+        config: PoolConfig,
+        cpu_pool: CpuPool,
+    ) -> Result<SqliteDatabase, DatabaseError> {
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+            Ok(())
+        });
+        let pool = config.build_pool(manager)?;
 
-        Ok(())
+        Ok(SqliteDatabase {
+            cpu_pool,
+            db_pool: Arc::new(ConnectionPool {
+                pool,
+                timeout_count: AtomicU64::new(0),
+            }),
+            read_pool: None,
+            max_clock_skew: chrono::Duration::seconds(DEFAULT_MAX_CLOCK_SKEW_SECS),
+            token_lifetime: chrono::Duration::seconds(DEFAULT_TOKEN_LIFETIME_SECS),
+            currency: Currency::default(),
+            max_retries: config.max_retries,
+            max_amount: config.max_amount,
+            max_reason_length: config.max_reason_length,
+            max_limit: config.max_limit,
+            require_unique_display_name: config.require_unique_display_name,
+            shaft_rate_limit: config.shaft_rate_limit,
+            max_debt: config.max_debt,
+            shaft_listener: None,
+            metrics: None,
+            balance_cache: None,
+            handles: Arc::new(()),
+        })
     }
-}
 
-impl Database for SqliteDatabase {
-    fn get_user_by_github_id(
-        &self,
-        github_user_id: String,
-    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
-        let db_pool = self.db_pool.clone();
+    /// Set how far in the future a new transaction's time may be. Defaults to
+    /// five minutes.
+    pub fn with_max_clock_skew(mut self, max_clock_skew: chrono::Duration) -> SqliteDatabase {
+        self.max_clock_skew = max_clock_skew;
+        self
+    }
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+    /// Set how long new access tokens are valid for. Defaults to 30 days.
+    pub fn with_token_lifetime(mut self, token_lifetime: chrono::Duration) -> SqliteDatabase {
+        self.token_lifetime = token_lifetime;
+        self
+    }
 
-                let row = conn
-                    .query_row(
-                        "SELECT user_id FROM github_users WHERE github_id = $1",
-                        &[&github_user_id],
-                        |row| row.get(0),
-                    )
-                    .map(Some)
-                    .or_else(|err| {
-                        if let rusqlite::Error::QueryReturnedNoRows = err {
-                            Ok(None)
-                        } else {
-                            Err(err)
-                        }
-                    })
-                    .context(SqliteError)?;
+    /// Set how amounts are rendered by [format_amount](Self::format_amount).
+    /// Defaults to pounds and pence. This is separate from the stored
+    /// currency clients use, c.f. [Database::get_currency].
+    pub fn with_currency(mut self, currency: Currency) -> SqliteDatabase {
+        self.currency = currency;
+        self
+    }
 
-                Ok(row)
-            })
-            .compat()
-            .boxed()
+    /// Set a callback to run with each transaction committed by
+    /// [Database::shaft_user], e.g. to announce it in chat. Resubmissions
+    /// with an existing idempotency key don't trigger it.
+    ///
+    /// The callback runs on the database thread pool, only once the SQL
+    /// transaction has committed, so it should be quick. If it panics the
+    /// panic is caught and the shaft still succeeds.
+    pub fn with_shaft_listener(mut self, listener: ShaftListener) -> SqliteDatabase {
+        self.shaft_listener = Some(listener);
+        self
     }
 
-    fn add_user_by_github_id(
-        &self,
-        github_user_id: String,
-        display_name: String,
-    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
-        let db_pool = self.db_pool.clone();
+    /// Send the main read-only queries, i.e. [Database::get_all_users] (and
+    /// the other `get_all_users_*` methods), [Database::get_balance_for_user],
+    /// [Database::get_leaderboard] and [Database::search_users], to a read
+    /// replica of the database at `path`, e.g. one kept up to date by
+    /// streaming replication, which is opened read only. Everything else,
+    /// including all writes and token lookups, still uses the primary.
+    ///
+    /// The replica may lag behind the primary, so these reads may not yet
+    /// see a write that's just been made, e.g. the list of balances shown
+    /// straight after a shaft may not include it, and a user who's just
+    /// logged in for the first time may be missing from it. Logging in
+    /// itself isn't affected. If the [balance
+    /// cache](SqliteDatabase::with_balance_cache) is enabled then a lagging
+    /// read may be cached for up to its TTL.
+    ///
+    /// Fails if the replica's connection pool can't be built.
+    pub fn with_read_replica<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        config: PoolConfig,
+    ) -> Result<SqliteDatabase, DatabaseError> {
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI,
+            )
+            .with_init(|conn| {
+                conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+                Ok(())
+            });
+        let pool = config.build_pool(manager)?;
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.read_pool = Some(Arc::new(ConnectionPool {
+            pool,
+            timeout_count: AtomicU64::new(0),
+        }));
+        Ok(self)
+    }
 
-                conn.execute(
-                    "INSERT INTO github_users (user_id, github_id)
-                VALUES ($1, $1)",
-                    &[&github_user_id],
-                )
-                .context(SqliteError)?;
+    /// Cache user balances in process for up to `ttl`, so that
+    /// [Database::get_balance_for_user] and [Database::get_all_users] (and
+    /// the other `get_all_users_*` methods) needn't sum the whole
+    /// transactions table each time. Off by default.
+    ///
+    /// Whenever this database, or a clone of it, commits a write that changes
+    /// balances, e.g. a shaft, deletion, settlement, merge or import, the
+    /// affected cached balances are dropped, so the next read sees it. Only
+    /// writes from other processes go unnoticed until the cached balances
+    /// expire, so reads may be up to `ttl` out of date with those.
+    pub fn with_balance_cache(mut self, ttl: chrono::Duration) -> SqliteDatabase {
+        self.balance_cache = Some(Arc::new(BalanceCache::new(ttl)));
+        self
+    }
 
-                conn.execute(
-                    "INSERT INTO users (user_id, display_name)
-                VALUES ($1, $2)",
-                    &[&github_user_id, &display_name],
-                )
-                .context(SqliteError)?;
+    /// Report the latency and outcome of each database operation to
+    /// `metrics`, e.g. to export them to a monitoring system. Unset by
+    /// default, in which case operations aren't timed at all.
+    ///
+    /// Only operations that reach the thread pool are reported, so e.g. a
+    /// call rejected for an invalid limit isn't. The time includes any
+    /// retries and waiting for a connection, but not waiting for a thread.
+    /// The hook runs on the database thread pool, so it should be quick. If
+    /// it panics the panic is caught and the operation's result is
+    /// unaffected.
+    pub fn with_metrics(mut self, metrics: Arc<dyn DatabaseMetrics>) -> SqliteDatabase {
+        self.metrics = Some(metrics);
+        self
+    }
 
-                Ok(github_user_id)
-            })
-            .compat()
-            .boxed()
+    /// Render an amount in the configured currency, e.g. `-450` as `-£4.50`.
+    pub fn format_amount(&self, amount: i64) -> String {
+        self.currency.format_amount(amount)
     }
 
-    fn create_token_for_user(
-        &self,
-        user_id: String,
-    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+    /// Eagerly open the pool's minimum number of idle connections, checking
+    /// each one works, so that early requests don't pay the cost of
+    /// connecting.
+    pub fn warm_pool(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
-
-                let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+        self.spawn("warm_pool", move || -> Result<_, DatabaseError> {
+            let num_connections = db_pool
+                .pool
+                .min_idle()
+                .unwrap_or_else(|| db_pool.pool.max_size());
 
-                conn.execute(
-                    "INSERT INTO tokens (user_id, token) VALUES ($1, $2)",
-                    &[&user_id, &token],
-                )
-                .context(SqliteError)?;
+            // We hold on to all the connections until we're done, so that
+            // we don't just get the same one back each time.
+            let mut conns = Vec::with_capacity(num_connections as usize);
+            for _ in 0..num_connections {
+                let conn = db_pool.get()?;
+                conn.execute_batch("SELECT 1").context(SqliteError {
+                    operation: "warm_pool",
+                })?;
+                conns.push(conn);
+            }
 
-                Ok(token)
-            })
-            .compat()
-            .boxed()
+            Ok(())
+        })
     }
 
-    fn delete_token(&self, token: String) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
-        let db_pool = self.db_pool.clone();
+    /// Run a blocking database operation on the thread pool.
+    ///
+    /// If the operation fails with a transient error (c.f.
+    /// [DatabaseError::is_transient]) then it's retried, up to the configured
+    /// number of times, after a short backoff. Operations must therefore be
+    /// safe to run again, e.g. by doing multiple writes in a SQL transaction.
+    ///
+    /// Panics are caught and returned as [DatabaseError::WorkerPanic], so a
+    /// bad query fails rather than taking down the caller.
+    ///
+    /// `method` names the [Database] method for the metrics hook, if any.
+    fn spawn<F, T>(
+        &self,
+        method: &'static str,
+        f: F,
+    ) -> LocalBoxFuture<'static, Result<T, DatabaseError>>
+    where
+        F: Fn() -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let max_retries = self.max_retries;
+        let metrics = self.metrics.clone();
 
         self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+            .spawn_fn(move || {
+                let start = metrics.as_ref().map(|_| Instant::now());
 
-                conn.execute("DELETE FROM tokens WHERE token = $1", &[&token])
-                    .context(SqliteError)?;
+                let mut attempt = 0;
+                let result = loop {
+                    match catch_panic(&f).map_err(DatabaseError::map_unique_violation) {
+                        Err(ref err) if err.is_transient() && attempt < max_retries => {
+                            attempt += 1;
+                            thread::sleep(RETRY_BACKOFF * attempt);
+                        }
+                        result => break result,
+                    }
+                };
 
-                Ok(())
+                if let (Some(metrics), Some(start)) = (&metrics, start) {
+                    let outcome = result.as_ref().map(|_| ()).map_err(|_| ());
+                    report_query(metrics, method, start.elapsed(), outcome);
+                }
+
+                result
             })
             .compat()
             .boxed()
     }
 
-    fn get_user_from_token(
+    /// Like [spawn](Self::spawn), but `f` is given a flag that's set once
+    /// the returned future is dropped, e.g. because the request was
+    /// cancelled. Dropping the future doesn't stop an operation that's
+    /// already running, so writes should check the flag just before
+    /// committing and roll back with [DatabaseError::Cancelled] if it's set.
+    ///
+    /// The future can still be dropped after the check, in which case the
+    /// write commits anyway.
+    fn spawn_cancellable<F, T>(
         &self,
-        token: String,
-    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>> {
-        let db_pool = self.db_pool.clone();
-
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        method: &'static str,
+        f: F,
+    ) -> LocalBoxFuture<'static, Result<T, DatabaseError>>
+    where
+        F: Fn(&AtomicBool) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let guard = CancelOnDrop(cancelled.clone());
+        let future = self.spawn(method, move || f(&cancelled));
 
-                let row = conn
-                    .query_row(
-                        r#"
-                SELECT user_id, display_name, COALESCE(balance, 0)
-                FROM tokens
-                INNER JOIN users USING (user_id)
-                LEFT JOIN (
-                    SELECT user_id, SUM(amount) as balance
-                    FROM (
-                        SELECT shafter AS user_id, SUM(amount) AS amount
-                        FROM transactions GROUP BY shafter
-                        UNION ALL
-                        SELECT shaftee AS user_id, -SUM(amount) AS amount
-                        FROM transactions GROUP BY shaftee
-                    ) t GROUP BY user_id
-                )
-                USING (user_id)
-                WHERE token = $1
-                "#,
-                        &[&token],
-                        |row| {
-                            Ok(User {
-                                user_id: row.get(0)?,
-                                display_name: row.get(1)?,
-                                balance: row.get(2)?,
-                            })
-                        },
-                    )
-                    .map(Some)
-                    .or_else(|err| {
-                        if let rusqlite::Error::QueryReturnedNoRows = err {
-                            Ok(None)
-                        } else {
-                            Err(err)
-                        }
-                    })
-                    .context(SqliteError)?;
+        async move {
+            // Setting the flag once the operation has finished is harmless.
+            let _guard = guard;
+            future.await
+        }
+        .boxed_local()
+    }
 
-                Ok(row)
+    /// Wait for all outstanding database operations to finish, then close
+    /// the pool's connections, e.g. before exiting during a redeploy.
+    ///
+    /// Every other clone of the database must have been dropped first, so
+    /// that no new operations can start; otherwise this fails with
+    /// [DatabaseError::InvalidInput]. Operations already started, even if
+    /// their futures have been dropped, still run to completion.
+    pub fn shutdown(self) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let clones = Arc::strong_count(&self.handles) - 1;
+        if clones > 0 {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: format!("can't shut down with {} other clones alive", clones),
             })
-            .compat()
-            .boxed()
-    }
+            .boxed();
+        }
 
-    fn get_balance_for_user(
-        &self,
-        user: String,
-    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let cpu_pool = self.cpu_pool.clone();
         let db_pool = self.db_pool.clone();
+        let read_pool = self.read_pool.clone();
+        drop(self);
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
-
-                let row = conn
-                    .query_row(
-                        r#"SELECT (
-                    SELECT COALESCE(SUM(amount), 0)
-                    FROM transactions
-                    WHERE shafter = $1
-                ) - (
-                    SELECT COALESCE(SUM(amount), 0)
-                    FROM transactions
-                    WHERE shaftee = $1
-                )"#,
-                        &[&user],
-                        |row| row.get(0),
-                    )
-                    .context(SqliteError)?;
+        cpu_pool
+            .spawn_fn(move || {
+                // Each outstanding operation holds a reference to the pool
+                // it's using until it's finished.
+                while Arc::strong_count(&db_pool) > 1
+                    || read_pool
+                        .as_ref()
+                        .is_some_and(|pool| Arc::strong_count(pool) > 1)
+                {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
 
-                Ok(row)
+                // Dropping the last references closes the connections.
+                drop(db_pool);
+                drop(read_pool);
+                Ok(())
             })
             .compat()
             .boxed()
     }
 
-    fn get_all_users(
-        &self,
-    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+    /// The pool to use for queries that may go to the read replica.
+    fn read_pool(&self) -> Arc<ConnectionPool> {
+        self.read_pool.as_ref().unwrap_or(&self.db_pool).clone()
+    }
+
+    /// Get the current state of the connection pool.
+    pub fn pool_state(&self) -> PoolStats {
+        let state = self.db_pool.pool.state();
+
+        PoolStats {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            timeout_count: self.db_pool.timeout_count.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Run `f` inside a single SQL transaction on the thread pool, committing
+    /// if it succeeds and rolling back otherwise. This lets several
+    /// operations, e.g. [shaft_user_in](Self::shaft_user_in), be composed
+    /// atomically. `f` may be run again if the transaction fails with a
+    /// transient error.
+    pub fn in_transaction<F, T>(&self, f: F) -> LocalBoxFuture<'static, Result<T, DatabaseError>>
+    where
+        F: Fn(&rusqlite::Connection) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn("in_transaction", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn.transaction().context(SqliteError {
+                operation: "in_transaction.begin",
+            })?;
 
-                let mut stmt = conn
-                    .prepare(
-                        r#"
-                SELECT user_id, display_name, COALESCE(balance, 0) AS balance
-                FROM users
-                LEFT JOIN (
-                    SELECT user_id, SUM(amount) as balance
-                    FROM (
-                        SELECT shafter AS user_id, SUM(amount) AS amount
-                        FROM transactions GROUP BY shafter
-                        UNION ALL
-                        SELECT shaftee AS user_id, -SUM(amount) AS amount
-                        FROM transactions GROUP BY shaftee
-                    ) t GROUP BY user_id
-                )
-                USING (user_id)
-                ORDER BY balance ASC
-                "#,
-                    )
-                    .context(SqliteError)?;
+            let result = f(&txn)?;
 
-                let rows: Result<LinearMap<String, User>, _> = stmt
-                    .query_map(params![], |row| {
-                        Ok((
-                            row.get(0)?,
-                            User {
-                                user_id: row.get(0)?,
-                                display_name: row.get(1)?,
-                                balance: row.get(2)?,
-                            },
-                        ))
-                    })
-                    .context(SqliteError)?
-                    .collect();
+            txn.commit().context(SqliteError {
+                operation: "in_transaction.commit",
+            })?;
 
-                Ok(rows.context(SqliteError)?)
-            })
-            .compat()
-            .boxed()
+            Ok(result)
+        })
     }
 
-    fn shaft_user(
+    /// Like [Database::shaft_user], but using the given connection, e.g. an
+    /// open transaction from [in_transaction](Self::in_transaction).
+    pub fn shaft_user_in(
         &self,
+        conn: &rusqlite::Connection,
         transaction: Transaction,
-    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
-        let db_pool = self.db_pool.clone();
+    ) -> Result<(), DatabaseError> {
+        validate_shaft(
+            &transaction,
+            self.max_clock_skew,
+            self.max_amount,
+            self.max_reason_length,
+        )?;
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        check_shaft_limits(conn, &transaction, self.shaft_rate_limit, self.max_debt)?;
 
-                match conn.query_row(
-                    "SELECT user_id FROM users WHERE user_id = $1",
-                    &[&transaction.shaftee],
-                    |_row| Ok(()),
-                ) {
-                    Ok(_) => (),
-                    Err(rusqlite::Error::QueryReturnedNoRows) => {
-                        return Err(DatabaseError::UnknownUser {
-                            user_id: transaction.shaftee,
-                        })
-                    }
-                    Err(err) => Err(err).context(SqliteError)?,
-                }
+        insert_transaction(conn, transaction).map(|_| ())
+    }
+
+    /// Runs the given statements synchronously
+    pub fn run_statements(&self, stmts: &str) -> Result<(), DatabaseError> {
+        let conn = self.db_pool.get()?;
+
+        conn.execute_batch(stmts).context(SqliteError {
+            operation: "run_statements",
+        })?;
 
+        Ok(())
+    }
+
+    /// Get SQLite's query plan for each of the queries run on every request,
+    /// keyed by the method that runs it, e.g. to check that they use the
+    /// expected indexes.
+    pub fn query_plans(&self) -> Result<Vec<(&'static str, Vec<String>)>, DatabaseError> {
+        let conn = self.db_pool.get()?;
+
+        let queries = [
+            ("get_balance_for_user", USER_BALANCE_SQL.to_string(), 1),
+            ("get_user_from_token", user_from_token_sql(), 2),
+        ];
+
+        queries
+            .iter()
+            .map(|(name, sql, parameter_count)| {
                 let mut stmt = conn
-                    .prepare(
-                        "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason)\
-                     VALUES ($1, $2, $3, $4, $5)",
-                    )
-                    .context(SqliteError)?;
+                    .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+                    .context(SqliteError {
+                        operation: "query_plans.prepare",
+                    })?;
 
-                stmt.execute(params![
-                    &transaction.shafter,
-                    &transaction.shaftee,
-                    &transaction.amount,
-                    &transaction.datetime.timestamp(),
-                    &transaction.reason,
-                ])
-                .context(SqliteError)?;
+                // The plan doesn't depend on the parameters' values.
+                let nulls = vec![rusqlite::types::Null; *parameter_count];
+                let plan = stmt
+                    .query_map(&nulls, |row| row.get(3))
+                    .and_then(|rows| rows.collect::<Result<Vec<String>, _>>())
+                    .context(SqliteError {
+                        operation: "query_plans.explain",
+                    })?;
 
-                Ok(())
+                Ok((*name, plan))
             })
-            .compat()
-            .boxed()
+            .collect()
     }
 
-    fn get_last_transactions(
+    /// Get up to `limit` live transactions with IDs greater than `after_id`,
+    /// in ID order.
+    fn get_transactions_after(
         &self,
+        after_id: i64,
         limit: u32,
     ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(
+            "get_transactions_after",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
 
                 let mut stmt = conn
-                    .prepare(
-                        r#"SELECT shafter, shaftee, amount, time_sec, reason
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
                 FROM transactions
-                ORDER BY id DESC
-                LIMIT $1
+                WHERE id > $1 AND deleted_at IS NULL
+                ORDER BY id
+                LIMIT $2
                 "#,
                     )
-                    .context(SqliteError)?;
+                    .context(SqliteError {
+                        operation: "get_transactions_after",
+                    })?;
 
                 let rows: Result<Vec<_>, _> = stmt
-                    .query_map(&[&limit], |row| {
+                    .query_map(params![after_id, i64::from(limit)], |row| {
                         Ok(Transaction {
-                            shafter: row.get(0)?,
-                            shaftee: row.get(1)?,
-                            amount: row.get(2)?,
-                            datetime: chrono::Utc.timestamp(row.get(3)?, 0),
-                            reason: row.get(4)?,
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
                         })
                     })
-                    .context(SqliteError)?
+                    .context(SqliteError {
+                        operation: "get_transactions_after",
+                    })?
                     .collect();
 
-                Ok(rows.context(SqliteError)?)
-            })
-            .compat()
-            .boxed()
+                rows.context(SqliteError {
+                    operation: "get_transactions_after",
+                })
+            },
+        )
+    }
+}
+
+/// An in-process cache of user balances, see
+/// [SqliteDatabase::with_balance_cache].
+///
+/// Writes invalidate the balances they affect rather than updating them, so
+/// that concurrent writes can't leave a wrong balance behind.
+struct BalanceCache {
+    /// How long a balance may be served from the cache.
+    ttl: Duration,
+    state: Mutex<BalanceCacheState>,
+}
+
+#[derive(Default)]
+struct BalanceCacheState {
+    /// Each user's balance, and when it was read from the database.
+    balances: HashMap<String, (i64, Instant)>,
+    /// Bumped by every invalidation. Reads note it before querying and only
+    /// cache their results if it hasn't changed, so a read that raced with
+    /// a write can't cache the balance from before the write.
+    generation: u64,
+}
+
+impl BalanceCache {
+    fn new(ttl: chrono::Duration) -> BalanceCache {
+        BalanceCache {
+            // A negative TTL is as good as zero, i.e. nothing is cached.
+            ttl: ttl.to_std().unwrap_or_default(),
+            state: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BalanceCacheState> {
+        self.state.lock().expect("balance cache lock poisoned")
+    }
+
+    /// The generation to pass to [insert](Self::insert) after reading
+    /// balances from the database.
+    fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Get the user's balance, if it's cached and hasn't expired.
+    fn get(&self, user_id: &str) -> Option<i64> {
+        match self.lock().balances.get(user_id) {
+            Some(&(balance, read_at)) if read_at.elapsed() < self.ttl => Some(balance),
+            _ => None,
+        }
+    }
+
+    /// Cache balances read from the database, unless there's been an
+    /// invalidation since `generation` was fetched.
+    fn insert(&self, generation: u64, balances: impl IntoIterator<Item = (String, i64)>) {
+        let mut state = self.lock();
+        if state.generation != generation {
+            return;
+        }
+
+        let read_at = Instant::now();
+        state.balances.extend(
+            balances
+                .into_iter()
+                .map(|(user_id, balance)| (user_id, (balance, read_at))),
+        );
+    }
+
+    /// Drop the given users' balances. Must be called after the write that
+    /// changed them has committed.
+    fn invalidate(&self, user_ids: &[&str]) {
+        let mut state = self.lock();
+        state.generation += 1;
+        for user_id in user_ids {
+            state.balances.remove(*user_id);
+        }
+    }
+
+    /// Drop the balances of both parties to each of the given transactions.
+    /// Must be called after the write that added them has committed.
+    fn invalidate_parties(&self, transactions: &[Transaction]) {
+        let mut state = self.lock();
+        state.generation += 1;
+        for transaction in transactions {
+            state.balances.remove(&transaction.shafter);
+            state.balances.remove(&transaction.shaftee);
+        }
+    }
+
+    /// Drop all balances, for writes where we don't know who's affected.
+    fn clear(&self) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.balances.clear();
+    }
+}
+
+/// A connection pool that keeps track of how often it was exhausted.
+struct ConnectionPool {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+    /// The number of times we timed out waiting for a connection while all
+    /// connections were in use.
+    timeout_count: AtomicU64,
+}
+
+impl ConnectionPool {
+    /// Get a connection from the pool, waiting for one to become available if
+    /// necessary.
+    fn get(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>, DatabaseError> {
+        let result = self.pool.get();
+
+        if result.is_err() {
+            // If every connection is checked out then we timed out due to
+            // the pool being exhausted, rather than failing to connect.
+            let state = self.pool.state();
+            if state.idle_connections == 0 && state.connections == self.pool.max_size() {
+                self.timeout_count.fetch_add(1, Ordering::Relaxed);
+
+                return PoolTimeout {
+                    timeout: self.pool.connection_timeout(),
+                }
+                .fail();
+            }
+        }
+
+        result.context(ConnectionPoolError)
+    }
+}
+
+/// How many prepared statements each connection caches. Most queries are
+/// prepared with `prepare_cached`, so this should comfortably exceed the
+/// number of distinct hot queries.
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// How many transactions [Database::stream_all_transactions] fetches at a
+/// time.
+const STREAM_CHUNK_SIZE: u32 = 1000;
+
+/// How long to wait before retrying an operation that failed with a transient
+/// error, multiplied by the number of the retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How often [SqliteDatabase::shutdown] checks whether outstanding
+/// operations have finished.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The schema migrations, in order. Each is applied at most once by
+/// [Database::migrate], which records the number applied in
+/// `schema_version`. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // The original schema.
+    r#"
+    CREATE TABLE IF NOT EXISTS tokens ( user_id TEXT NOT NULL, token TEXT NOT NULL );
+    CREATE TABLE IF NOT EXISTS github_users (user_id text primary key not null, github_id text not null);
+    CREATE TABLE IF NOT EXISTS users ( user_id TEXT NOT NULL UNIQUE, display_name TEXT );
+    CREATE TABLE IF NOT EXISTS "transactions" (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT NOT NULL);
+    "#,
+    // Token scopes, usage and expiry. Tokens are now stored hashed, so any
+    // existing plaintext ones can never match and are dropped.
+    r#"
+    DELETE FROM tokens;
+    ALTER TABLE tokens ADD COLUMN scope TEXT NOT NULL DEFAULT 'write';
+    ALTER TABLE tokens ADD COLUMN last_used_at BIGINT;
+    ALTER TABLE tokens ADD COLUMN created_at BIGINT;
+    ALTER TABLE tokens ADD COLUMN expires_at BIGINT;
+    "#,
+    // User creation times and overridden display names. We don't know when
+    // existing users were created, so use now.
+    r#"
+    ALTER TABLE users ADD COLUMN created_at BIGINT;
+    ALTER TABLE users ADD COLUMN display_name_overridden BOOLEAN NOT NULL DEFAULT 0;
+    UPDATE users SET created_at = CAST(strftime('%s', 'now') AS INTEGER);
+    "#,
+    // Soft deletion and reversal of transactions.
+    r#"
+    ALTER TABLE transactions ADD COLUMN deleted_at BIGINT;
+    ALTER TABLE transactions ADD COLUMN reversed_transaction_id BIGINT;
+    "#,
+    // Teams and attachments.
+    r#"
+    CREATE TABLE user_teams ( user_id TEXT NOT NULL UNIQUE, team TEXT NOT NULL );
+    CREATE TABLE attachments ( id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, transaction_id BIGINT NOT NULL, url TEXT NOT NULL, uploaded_at BIGINT NOT NULL );
+    "#,
+    // Idempotency keys, to detect resubmitted shafts.
+    r#"
+    ALTER TABLE transactions ADD COLUMN idempotency_key TEXT;
+    CREATE UNIQUE INDEX transactions_idempotency_key ON transactions (idempotency_key);
+    "#,
+    // Identities from any provider, replacing github_users.
+    r#"
+    CREATE TABLE identities ( provider TEXT NOT NULL, provider_id TEXT NOT NULL, user_id TEXT NOT NULL, PRIMARY KEY (provider, provider_id) );
+    INSERT INTO identities (provider, provider_id, user_id)
+        SELECT 'github', github_id, user_id FROM github_users;
+    DROP TABLE github_users;
+    "#,
+    // Looking up users by display name, e.g. to check it's unique.
+    r#"
+    CREATE INDEX users_display_name ON users (display_name COLLATE NOCASE);
+    "#,
+    // Deactivating users who've left, rather than deleting them.
+    r#"
+    ALTER TABLE users ADD COLUMN active BOOLEAN NOT NULL DEFAULT 1;
+    "#,
+    // Categories for reporting.
+    r#"
+    ALTER TABLE transactions ADD COLUMN category TEXT;
+    CREATE INDEX transactions_category ON transactions (category);
+    "#,
+    // Indexes for computing balances and looking up tokens. Identities are
+    // already indexed by their primary key.
+    r#"
+    CREATE INDEX IF NOT EXISTS transactions_shafter ON transactions (shafter);
+    CREATE INDEX IF NOT EXISTS transactions_shaftee ON transactions (shaftee);
+    CREATE INDEX IF NOT EXISTS tokens_token ON tokens (token);
+    "#,
+    // Optional reasons. SQLite can't drop the NOT NULL constraint, so the
+    // table is rebuilt, keeping its AUTOINCREMENT sequence so IDs aren't
+    // reused. Existing empty reasons become NULL.
+    r#"
+    CREATE TABLE transactions_new (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT, deleted_at BIGINT, reversed_transaction_id BIGINT, idempotency_key TEXT, category TEXT);
+    INSERT INTO transactions_new (id, shafter, shaftee, amount, time_sec, reason, deleted_at, reversed_transaction_id, idempotency_key, category)
+        SELECT id, shafter, shaftee, amount, time_sec, NULLIF(reason, ''), deleted_at, reversed_transaction_id, idempotency_key, category FROM transactions;
+    DELETE FROM sqlite_sequence WHERE name = 'transactions_new';
+    UPDATE sqlite_sequence SET name = 'transactions_new' WHERE name = 'transactions';
+    DROP TABLE transactions;
+    ALTER TABLE transactions_new RENAME TO transactions;
+    CREATE UNIQUE INDEX transactions_idempotency_key ON transactions (idempotency_key);
+    CREATE INDEX transactions_category ON transactions (category);
+    CREATE INDEX transactions_shafter ON transactions (shafter);
+    CREATE INDEX transactions_shaftee ON transactions (shaftee);
+    "#,
+    // The install's currency settings.
+    r#"
+    CREATE TABLE settings ( id INTEGER PRIMARY KEY CHECK (id = 1), currency_symbol TEXT NOT NULL, decimal_places INTEGER NOT NULL );
+    INSERT INTO settings (id, currency_symbol, decimal_places) VALUES (1, '£', 2);
+    "#,
+    // Versions for optimistic locking of display name changes.
+    r#"
+    ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+    "#,
+];
+
+/// Computes the balance of each user with transactions, as rows of
+/// `(user_id, balance)`.
+const BALANCES_SQL: &str = r#"
+    SELECT user_id, SUM(amount) as balance
+    FROM (
+        SELECT shafter AS user_id, SUM(amount) AS amount
+        FROM transactions WHERE deleted_at IS NULL GROUP BY shafter
+        UNION ALL
+        SELECT shaftee AS user_id, -SUM(amount) AS amount
+        FROM transactions WHERE deleted_at IS NULL GROUP BY shaftee
+    ) t GROUP BY user_id
+"#;
+
+/// Gets the balance of the user `$1`, with no rows if they don't exist.
+const USER_BALANCE_SQL: &str = r#"
+    SELECT (
+        SELECT COALESCE(SUM(amount), 0)
+        FROM transactions
+        WHERE shafter = user_id AND deleted_at IS NULL
+    ) - (
+        SELECT COALESCE(SUM(amount), 0)
+        FROM transactions
+        WHERE shaftee = user_id AND deleted_at IS NULL
+    )
+    FROM users
+    WHERE user_id = $1
+"#;
+
+/// Gets the user, balance and scope of the (hashed) token `$1`, if it
+/// hasn't expired by `$2`.
+fn user_from_token_sql() -> String {
+    format!(
+        r#"
+    SELECT user_id, display_name, COALESCE(balance, 0), scope,
+        COALESCE(users.created_at, 0), users.version
+    FROM tokens
+    INNER JOIN users USING (user_id)
+    LEFT JOIN ({}) USING (user_id)
+    WHERE token = $1 AND (expires_at IS NULL OR expires_at > $2)
+    "#,
+        BALANCES_SQL
+    )
+}
+
+impl ToSql for TokenScope {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for TokenScope {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "read" => Ok(TokenScope::Read),
+            "write" => Ok(TokenScope::Write),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for UserId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for UserId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        String::column_result(value).map(UserId)
+    }
+}
+
+impl ToSql for Token {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl ToSql for GithubId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl Database for SqliteDatabase {
+    fn get_user_by_github_id(
+        &self,
+        github_user_id: GithubId,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>> {
+        self.get_user_by_identity(GITHUB_PROVIDER.to_string(), github_user_id.0)
+    }
+
+    fn add_user_by_github_id(
+        &self,
+        github_user_id: GithubId,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<UserId, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let require_unique_display_name = self.require_unique_display_name;
+
+        self.spawn(
+            "add_user_by_github_id",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+
+                // Take the write lock up front, so that concurrent first logins
+                // for the same account queue up and the later ones find the
+                // identity the first inserted, rather than racing to insert it.
+                let txn = conn
+                    .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                    .context(SqliteError {
+                        operation: "add_user_by_github_id.begin",
+                    })?;
+
+                // The Github account may already be linked to a user, otherwise
+                // new users' IDs are their Github logins.
+                let user_id: String = match txn.query_row(
+                    "SELECT user_id FROM identities WHERE provider = $1 AND provider_id = $2",
+                    params![GITHUB_PROVIDER, &github_user_id],
+                    |row| row.get(0),
+                ) {
+                    Ok(user_id) => user_id,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => {
+                        txn.execute(
+                            "INSERT INTO identities (provider, provider_id, user_id)
+                        VALUES ($1, $2, $2)",
+                            params![GITHUB_PROVIDER, &github_user_id],
+                        )
+                        .context(SqliteError {
+                            operation: "add_user_by_github_id.insert_identity",
+                        })?;
+
+                        github_user_id.to_string()
+                    }
+                    Err(err) => Err(err).context(SqliteError {
+                        operation: "add_user_by_github_id.select_identity",
+                    })?,
+                };
+
+                if require_unique_display_name {
+                    // Existing users who've chosen their own name keep it, so
+                    // there's nothing to check.
+                    let overridden: Option<bool> = txn
+                        .query_row(
+                            "SELECT display_name_overridden FROM users WHERE user_id = $1",
+                            params![&user_id],
+                            |row| row.get(0),
+                        )
+                        .map(Some)
+                        .or_else(|err| {
+                            if let rusqlite::Error::QueryReturnedNoRows = err {
+                                Ok(None)
+                            } else {
+                                Err(err)
+                            }
+                        })
+                        .context(SqliteError {
+                            operation: "add_user_by_github_id.select_user",
+                        })?;
+
+                    if overridden != Some(true) {
+                        check_display_name_unique(&txn, &user_id, &display_name)?;
+                    }
+                }
+
+                txn.execute(
+                    "INSERT INTO users (user_id, display_name, created_at)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (user_id) DO UPDATE
+                SET display_name = excluded.display_name, version = version + 1
+                WHERE NOT display_name_overridden AND display_name IS NOT excluded.display_name",
+                    params![&user_id, &display_name, chrono::Utc::now().timestamp()],
+                )
+                .context(SqliteError {
+                    operation: "add_user_by_github_id.insert_user",
+                })?;
+
+                txn.commit().context(SqliteError {
+                    operation: "add_user_by_github_id.commit",
+                })?;
+
+                Ok(UserId(user_id))
+            },
+        )
+    }
+
+    fn create_token_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>> {
+        self.create_token_for_user_with_scope(user_id, TokenScope::Write)
+    }
+
+    fn create_token_for_user_with_scope(
+        &self,
+        user_id: UserId,
+        scope: TokenScope,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let token_lifetime = self.token_lifetime;
+
+        self.spawn(
+            "create_token_for_user_with_scope",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let created_at = chrono::Utc::now();
+                let expires_at = created_at + token_lifetime;
+
+                let token: String = OsRng
+                    .sample_iter(&Alphanumeric)
+                    .take(SqliteDatabase::TOKEN_LENGTH)
+                    .collect();
+
+                // Only the hash is stored, the plaintext token is handed back
+                // to the caller exactly once.
+                conn.execute(
+                    "INSERT INTO tokens (user_id, token, scope, created_at, expires_at)
+                VALUES ($1, $2, $3, $4, $5)",
+                    params![
+                        &user_id,
+                        hash_token(&token),
+                        scope,
+                        created_at.timestamp(),
+                        expires_at.timestamp(),
+                    ],
+                )
+                .context(SqliteError {
+                    operation: "create_token_for_user",
+                })?;
+
+                Ok(Token(token))
+            },
+        )
+    }
+
+    fn delete_token(&self, token: Token) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("delete_token", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            conn.execute(
+                "DELETE FROM tokens WHERE token = $1",
+                &[hash_token(token.as_str())],
+            )
+            .context(SqliteError {
+                operation: "delete_token",
+            })?;
+
+            Ok(())
+        })
+    }
+
+    fn get_user_from_token(
+        &self,
+        token: Token,
+    ) -> LocalBoxFuture<'static, Result<Option<(User, TokenScope)>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_user_from_token",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                // This runs on every authenticated request, so is worth
+                // caching.
+                let mut stmt =
+                    conn.prepare_cached(&user_from_token_sql())
+                        .context(SqliteError {
+                            operation: "get_user_from_token",
+                        })?;
+
+                let row = stmt
+                    .query_row(
+                        params![hash_token(token.as_str()), chrono::Utc::now().timestamp()],
+                        |row| {
+                            let user = User::from(row.get::<_, String>(0)?)
+                                .with_display_name(row.get::<_, String>(1)?)
+                                .with_balance(row.get(2)?)
+                                .with_created_at(chrono::Utc.timestamp(row.get(4)?, 0))
+                                .with_version(row.get(5)?);
+                            Ok((user, row.get(3)?))
+                        },
+                    )
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "get_user_from_token",
+                    })?;
+
+                Ok(row)
+            },
+        )
+    }
+
+    fn get_balance_for_user(
+        &self,
+        user: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let balance_cache = self.balance_cache.clone();
+        if let Some(balance) = balance_cache
+            .as_ref()
+            .and_then(|cache| cache.get(user.as_str()))
+        {
+            return futures::future::ok(balance).boxed();
+        }
+
+        let db_pool = self.read_pool();
+
+        self.spawn(
+            "get_balance_for_user",
+            move || -> Result<_, DatabaseError> {
+                let generation = balance_cache.as_ref().map(|cache| cache.generation());
+                let conn = db_pool.get()?;
+
+                let row = conn
+                    .query_row(USER_BALANCE_SQL, &[&user], |row| row.get(0))
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    });
+
+                let row = match row {
+                    Err(ref err) if is_integer_overflow(err) => {
+                        return Err(DatabaseError::BalanceOverflow {
+                            user_id: user.to_string(),
+                        })
+                    }
+                    row => row.context(SqliteError {
+                        operation: "get_balance_for_user",
+                    })?,
+                };
+
+                let balance = row.ok_or(DatabaseError::UnknownUser {
+                    user_id: user.to_string(),
+                })?;
+
+                if let (Some(cache), Some(generation)) = (&balance_cache, generation) {
+                    cache.insert(generation, Some((user.to_string(), balance)));
+                }
+
+                Ok(balance)
+            },
+        )
+    }
+
+    fn get_all_users(
+        &self,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        self.get_all_users_ordered(SortOrder::Asc, include_inactive)
+    }
+
+    fn get_all_users_ordered(
+        &self,
+        order: SortOrder,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        let sort = match order {
+            SortOrder::Asc => UserSort::BalanceAsc,
+            SortOrder::Desc => UserSort::BalanceDesc,
+        };
+
+        self.get_all_users_sorted(sort, include_inactive)
+            .map_ok(|users| {
+                users
+                    .into_iter()
+                    .map(|user| (user.user_id.clone(), user))
+                    .collect()
+            })
+            .boxed_local()
+    }
+
+    fn get_all_users_sorted(
+        &self,
+        sort: UserSort,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let db_pool = self.read_pool();
+        let balance_cache = self.balance_cache.clone();
+
+        let order_by = match sort {
+            UserSort::BalanceAsc => "balance ASC, display_name COLLATE NOCASE ASC, user_id ASC",
+            UserSort::BalanceDesc => "balance DESC, display_name COLLATE NOCASE ASC, user_id ASC",
+            UserSort::NameAsc => "display_name COLLATE NOCASE ASC, user_id ASC",
+            UserSort::NameDesc => "display_name COLLATE NOCASE DESC, user_id ASC",
+        };
+        let filter = active_filter(include_inactive);
+
+        self.spawn(
+            "get_all_users_sorted",
+            move || -> Result<_, DatabaseError> {
+                let generation = balance_cache.as_ref().map(|cache| cache.generation());
+                let conn = db_pool.get()?;
+
+                if let Some(cache) = &balance_cache {
+                    if let Some(mut users) = cached_users(&conn, cache, filter)? {
+                        sort_users(&mut users, sort);
+                        return Ok(users);
+                    }
+                }
+
+                let mut stmt = conn
+                    .prepare_cached(&format!(
+                        r#"
+                SELECT user_id, display_name, COALESCE(balance, 0) AS balance,
+                    COALESCE(created_at, 0), version
+                FROM users
+                LEFT JOIN ({}) USING (user_id)
+                WHERE {}
+                ORDER BY {}
+                "#,
+                        BALANCES_SQL, filter, order_by,
+                    ))
+                    .context(SqliteError {
+                        operation: "get_all_users_sorted",
+                    })?;
+
+                let rows: Result<Vec<User>, _> = stmt
+                    .query_map(params![], |row| user_from_row(row, 0))
+                    .context(SqliteError {
+                        operation: "get_all_users_sorted",
+                    })?
+                    .collect();
+
+                let users = rows.context(SqliteError {
+                    operation: "get_all_users_sorted",
+                })?;
+
+                if let (Some(cache), Some(generation)) = (&balance_cache, generation) {
+                    cache.insert(
+                        generation,
+                        users
+                            .iter()
+                            .map(|user| (user.user_id.clone(), user.balance)),
+                    );
+                }
+
+                Ok(users)
+            },
+        )
+    }
+
+    fn shaft_user(
+        &self,
+        transaction: Transaction,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+        let shaft_listener = self.shaft_listener.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn_cancellable("shaft_user", move |cancelled| -> Result<_, DatabaseError> {
+            // Validate before touching the database.
+            validate_shaft(&transaction, max_clock_skew, max_amount, max_reason_length)?;
+
+            let mut conn = db_pool.get()?;
+
+            // Take the write lock up front, so that no other shaft can change
+            // the balances we check between reading and inserting.
+            let txn = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .context(SqliteError {
+                    operation: "shaft_user.begin",
+                })?;
+
+            check_shaft_limits(&txn, &transaction, shaft_rate_limit, max_debt)?;
+
+            let id = insert_transaction(&txn, transaction.clone())?;
+
+            // Don't commit a shaft the caller has given up on; dropping the
+            // SQL transaction rolls it back.
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(DatabaseError::Cancelled);
+            }
+
+            txn.commit().context(SqliteError {
+                operation: "shaft_user.commit",
+            })?;
+
+            if let Some(cache) = &balance_cache {
+                cache.invalidate(&[&transaction.shafter, &transaction.shaftee]);
+            }
+
+            if let Some(id) = id {
+                let transaction = Transaction {
+                    id: Some(id),
+                    ..transaction.clone()
+                };
+                notify_shaft_listener(shaft_listener.as_ref(), &transaction);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn record_historical_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn(
+            "record_historical_transaction",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+                let txn = conn.transaction().context(SqliteError {
+                    operation: "record_historical_transaction.begin",
+                })?;
+
+                insert_transaction(&txn, transaction.clone())?;
+
+                txn.commit().context(SqliteError {
+                    operation: "record_historical_transaction.commit",
+                })?;
+
+                if let Some(cache) = &balance_cache {
+                    cache.invalidate(&[&transaction.shafter, &transaction.shaftee]);
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    fn get_last_transactions(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        self.get_transactions_page(limit, 0)
+    }
+
+    fn get_transactions_for_user(
+        &self,
+        user_id: UserId,
+        direction: TransactionDirection,
+        before: Option<i64>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        let condition = direction_filter(direction);
+
+        self.spawn(
+            "get_transactions_for_user",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(&format!(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE {} AND deleted_at IS NULL AND ($2 IS NULL OR id < $2)
+                ORDER BY id DESC
+                LIMIT $3
+                "#,
+                        condition
+                    ))
+                    .context(SqliteError {
+                        operation: "get_transactions_for_user",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![user_id, before, limit], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_transactions_for_user",
+                    })?
+                    .collect();
+
+                let items = rows.context(SqliteError {
+                    operation: "get_transactions_for_user",
+                })?;
+
+                Ok(Page::from_keyset(items, limit, None))
+            },
+        )
+    }
+
+    fn get_transactions_page(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_transactions_page",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE deleted_at IS NULL
+                ORDER BY id DESC
+                LIMIT $1
+                OFFSET $2
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_transactions_page",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![limit, i64::from(offset)], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_transactions_page",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_transactions_page",
+                })
+            },
+        )
+    }
+
+    fn get_shaft_counts(
+        &self,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_shaft_counts", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(&format!(
+                    r#"SELECT shaftee, COUNT(*) AS count
+                FROM transactions
+                WHERE deleted_at IS NULL AND {}
+                GROUP BY shaftee
+                ORDER BY count DESC
+                "#,
+                    reversed_filter(exclude_reversed)
+                ))
+                .context(SqliteError {
+                    operation: "get_shaft_counts",
+                })?;
+
+            let rows: Result<LinearMap<String, i64>, _> = stmt
+                .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context(SqliteError {
+                    operation: "get_shaft_counts",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_shaft_counts",
+            })
+        })
+    }
+
+    fn purge_user_data(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn("purge_user_data", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn.transaction().context(SqliteError {
+                operation: "purge_user_data.begin",
+            })?;
+
+            let anon_id: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+            let anon_id = format!("deleted-{}", anon_id);
+
+            let updated = txn
+                .execute(
+                    "UPDATE users SET user_id = $1, display_name = 'Deleted user',
+                    version = version + 1
+                WHERE user_id = $2",
+                    params![anon_id, user_id],
+                )
+                .context(SqliteError {
+                    operation: "purge_user_data.update_user",
+                })?;
+
+            if updated == 0 {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: user_id.to_string(),
+                });
+            }
+
+            txn.execute("DELETE FROM tokens WHERE user_id = $1", &[&user_id])
+                .context(SqliteError {
+                    operation: "purge_user_data.delete_tokens",
+                })?;
+
+            txn.execute("DELETE FROM identities WHERE user_id = $1", &[&user_id])
+                .context(SqliteError {
+                    operation: "purge_user_data.delete_identities",
+                })?;
+
+            // Attachments are URLs the user or their counterparty chose, so
+            // may well identify them.
+            txn.execute(
+                "DELETE FROM attachments WHERE transaction_id IN (
+                    SELECT id FROM transactions WHERE shafter = $1 OR shaftee = $1
+                )",
+                &[&user_id],
+            )
+            .context(SqliteError {
+                operation: "purge_user_data.delete_attachments",
+            })?;
+
+            txn.execute(
+                "UPDATE transactions SET shafter = $1, reason = NULL WHERE shafter = $2",
+                params![anon_id, user_id],
+            )
+            .context(SqliteError {
+                operation: "purge_user_data.update_shafter",
+            })?;
+
+            txn.execute(
+                "UPDATE transactions SET shaftee = $1, reason = NULL WHERE shaftee = $2",
+                params![anon_id, user_id],
+            )
+            .context(SqliteError {
+                operation: "purge_user_data.update_shaftee",
+            })?;
+
+            txn.execute(
+                "UPDATE user_teams SET user_id = $1 WHERE user_id = $2",
+                params![anon_id, user_id],
+            )
+            .context(SqliteError {
+                operation: "purge_user_data.update_team",
+            })?;
+
+            txn.commit().context(SqliteError {
+                operation: "purge_user_data.commit",
+            })?;
+
+            // The user's cached balance is under their old ID.
+            if let Some(cache) = &balance_cache {
+                cache.clear();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_transaction_feed(
+        &self,
+        before: Option<i64>,
+        limit: u32,
+        with_total: bool,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_transaction_feed",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+
+                // Use a transaction so that the total is consistent with the
+                // page.
+                let txn = conn.transaction().context(SqliteError {
+                    operation: "get_transaction_feed.begin",
+                })?;
+
+                let mut stmt = txn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE deleted_at IS NULL AND ($1 IS NULL OR id < $1)
+                ORDER BY id DESC
+                LIMIT $2
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_transaction_feed.select",
+                    })?;
+
+                let items: Vec<Transaction> = stmt
+                    .query_map(params![before, limit], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_transaction_feed.select",
+                    })?
+                    .collect::<Result<_, _>>()
+                    .context(SqliteError {
+                        operation: "get_transaction_feed.select",
+                    })?;
+                drop(stmt);
+
+                let total = if with_total {
+                    let count = txn
+                        .query_row(
+                            "SELECT COUNT(*) FROM transactions WHERE deleted_at IS NULL",
+                            params![],
+                            |row| row.get(0),
+                        )
+                        .context(SqliteError {
+                            operation: "get_transaction_feed.count",
+                        })?;
+                    Some(count)
+                } else {
+                    None
+                };
+
+                txn.commit().context(SqliteError {
+                    operation: "get_transaction_feed.commit",
+                })?;
+
+                Ok(Page::from_keyset(items, limit, total))
+            },
+        )
+    }
+
+    fn get_total_shafted(
+        &self,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_total_shafted", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let total = conn
+                .query_row(
+                    &format!(
+                        "SELECT COALESCE(SUM(amount), 0) FROM transactions
+                WHERE deleted_at IS NULL AND {}",
+                        reversed_filter(exclude_reversed)
+                    ),
+                    params![],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "get_total_shafted",
+                })?;
+
+            Ok(total)
+        })
+    }
+
+    fn get_user_rank(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_user_rank", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let rank = conn
+                .query_row(
+                    &format!(
+                        r#"
+                SELECT rank FROM (
+                    SELECT user_id, RANK() OVER (ORDER BY COALESCE(balance, 0) ASC) AS rank
+                    FROM users
+                    LEFT JOIN ({}) USING (user_id)
+                )
+                WHERE user_id = $1
+                "#,
+                        BALANCES_SQL
+                    ),
+                    &[&user_id],
+                    |row| row.get(0),
+                )
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError {
+                    operation: "get_user_rank",
+                })?;
+
+            Ok(rank)
+        })
+    }
+
+    fn get_balances_for_users(
+        &self,
+        user_ids: Vec<UserId>,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        if user_ids.is_empty() {
+            return futures::future::ok(LinearMap::new()).boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_balances_for_users",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(&format!(
+                        r#"
+                SELECT user_id, COALESCE(balance, 0)
+                FROM users
+                LEFT JOIN ({}) USING (user_id)
+                WHERE user_id IN ({})
+                "#,
+                        BALANCES_SQL,
+                        placeholders(1, user_ids.len()),
+                    ))
+                    .context(SqliteError {
+                        operation: "get_balances_for_users",
+                    })?;
+
+                let rows: Result<LinearMap<String, i64>, _> = stmt
+                    .query_map(&user_ids, |row| Ok((row.get(0)?, row.get(1)?)))
+                    .context(SqliteError {
+                        operation: "get_balances_for_users",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_balances_for_users",
+                })
+            },
+        )
+    }
+
+    fn get_inactive_users_since(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_inactive_users_since",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                inactive_users(&conn, cutoff, true)
+            },
+        )
+    }
+
+    fn get_inactive_users(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_inactive_users", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            inactive_users(&conn, since, false)
+        })
+    }
+
+    fn sync_display_name_from_github(
+        &self,
+        github_user_id: GithubId,
+        new_display_name: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "sync_display_name_from_github",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+                let txn = conn.transaction().context(SqliteError {
+                    operation: "sync_display_name_from_github.begin",
+                })?;
+
+                let user_id: String = match txn.query_row(
+                    "SELECT user_id FROM identities WHERE provider = $1 AND provider_id = $2",
+                    params![GITHUB_PROVIDER, &github_user_id],
+                    |row| row.get(0),
+                ) {
+                    Ok(user_id) => user_id,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => {
+                        return Err(DatabaseError::UnknownUser {
+                            user_id: github_user_id.to_string(),
+                        })
+                    }
+                    Err(err) => Err(err).context(SqliteError {
+                        operation: "sync_display_name_from_github.select_user",
+                    })?,
+                };
+
+                txn.execute(
+                    "UPDATE users SET display_name = $1, version = version + 1
+                WHERE user_id = $2 AND NOT display_name_overridden AND display_name IS NOT $1",
+                    &[&new_display_name, &user_id],
+                )
+                .context(SqliteError {
+                    operation: "sync_display_name_from_github.update_user",
+                })?;
+
+                txn.commit().context(SqliteError {
+                    operation: "sync_display_name_from_github.commit",
+                })
+            },
+        )
+    }
+
+    fn get_transaction_detail(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<TransactionDetail>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_transaction_detail",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let row = conn
+                    .query_row(
+                        &format!(
+                            r#"
+                WITH balances AS ({})
+                SELECT t.id, t.shafter, t.shaftee, t.amount, t.time_sec, t.reason,
+                    COALESCE(shafter_user.display_name, t.shafter),
+                    COALESCE(shaftee_user.display_name, t.shaftee),
+                    COALESCE(shafter_balance.balance, 0),
+                    COALESCE(shaftee_balance.balance, 0),
+                    t.idempotency_key, t.category
+                FROM transactions AS t
+                LEFT JOIN users AS shafter_user ON shafter_user.user_id = t.shafter
+                LEFT JOIN users AS shaftee_user ON shaftee_user.user_id = t.shaftee
+                LEFT JOIN balances AS shafter_balance ON shafter_balance.user_id = t.shafter
+                LEFT JOIN balances AS shaftee_balance ON shaftee_balance.user_id = t.shaftee
+                WHERE t.id = $1 AND t.deleted_at IS NULL
+                "#,
+                            BALANCES_SQL
+                        ),
+                        &[&id],
+                        |row| {
+                            Ok(TransactionDetail {
+                                transaction: Transaction {
+                                    id: row.get(0)?,
+                                    shafter: row.get(1)?,
+                                    shaftee: row.get(2)?,
+                                    amount: row.get(3)?,
+                                    datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                                    reason: row.get(5)?,
+                                    idempotency_key: row.get(10)?,
+                                    category: row.get(11)?,
+                                },
+                                shafter_display_name: row.get(6)?,
+                                shaftee_display_name: row.get(7)?,
+                                shafter_balance: row.get(8)?,
+                                shaftee_balance: row.get(9)?,
+                            })
+                        },
+                    )
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "get_transaction_detail",
+                    })?;
+
+                Ok(row)
+            },
+        )
+    }
+
+    fn soft_delete_transaction(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn(
+            "soft_delete_transaction",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let updated = conn
+                    .execute(
+                        "UPDATE transactions SET deleted_at = $1
+                WHERE id = $2 AND deleted_at IS NULL",
+                        &[&chrono::Utc::now().timestamp(), &id],
+                    )
+                    .context(SqliteError {
+                        operation: "soft_delete_transaction",
+                    })?;
+
+                if updated == 0 {
+                    return Err(DatabaseError::UnknownTransaction { id });
+                }
+
+                // Deleting is rare, so rather than look up who was involved we
+                // just start afresh.
+                if let Some(cache) = &balance_cache {
+                    cache.clear();
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    fn restore_transaction(&self, id: i64) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn(
+            "restore_transaction",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let updated = conn
+                    .execute(
+                        "UPDATE transactions SET deleted_at = NULL
+                WHERE id = $1 AND deleted_at IS NOT NULL",
+                        &[&id],
+                    )
+                    .context(SqliteError {
+                        operation: "restore_transaction",
+                    })?;
+
+                if updated == 0 {
+                    return Err(DatabaseError::UnknownTransaction { id });
+                }
+
+                // Like deleting, restoring is rare so we just start afresh.
+                if let Some(cache) = &balance_cache {
+                    cache.clear();
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    fn get_month_end_balances(
+        &self,
+        year: i32,
+        month: u32,
+        tz: Tz,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        // SQLite doesn't know about timezones, so we work out when the month
+        // ends here.
+        let month_end = match month_end_timestamp(year, month, tz) {
+            Ok(month_end) => month_end,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_month_end_balances",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"
+                SELECT user_id, COALESCE(balance, 0)
+                FROM users
+                LEFT JOIN (
+                    SELECT user_id, SUM(amount) as balance
+                    FROM (
+                        SELECT shafter AS user_id, amount
+                        FROM transactions WHERE deleted_at IS NULL AND time_sec < $1
+                        UNION ALL
+                        SELECT shaftee AS user_id, -amount AS amount
+                        FROM transactions WHERE deleted_at IS NULL AND time_sec < $1
+                    ) t GROUP BY user_id
+                ) USING (user_id)
+                ORDER BY user_id
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_month_end_balances",
+                    })?;
+
+                let rows: Result<LinearMap<String, i64>, _> = stmt
+                    .query_map(&[&month_end], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .context(SqliteError {
+                        operation: "get_month_end_balances",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_month_end_balances",
+                })
+            },
+        )
+    }
+
+    fn touch_token(&self, token: Token) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("touch_token", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let now = chrono::Utc::now().timestamp();
+
+            conn.prepare_cached(
+                "UPDATE tokens SET last_used_at = $1
+                WHERE token = $2 AND (last_used_at IS NULL OR last_used_at <= $3)",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    now,
+                    hash_token(token.as_str()),
+                    now - TOKEN_TOUCH_INTERVAL_SECS
+                ])
+            })
+            .context(SqliteError {
+                operation: "touch_token",
+            })?;
+
+            Ok(())
+        })
+    }
+
+    fn get_first_transaction(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_first_transaction",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let row = conn
+                    .query_row(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE deleted_at IS NULL
+                ORDER BY id ASC
+                LIMIT 1
+                "#,
+                        params![],
+                        |row| {
+                            Ok(Transaction {
+                                id: row.get(0)?,
+                                shafter: row.get(1)?,
+                                shaftee: row.get(2)?,
+                                amount: row.get(3)?,
+                                datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                                reason: row.get(5)?,
+                                idempotency_key: row.get(6)?,
+                                category: row.get(7)?,
+                            })
+                        },
+                    )
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "get_first_transaction",
+                    })?;
+
+                Ok(row)
+            },
+        )
+    }
+
+    fn add_attachment(
+        &self,
+        transaction_id: i64,
+        url: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        if url.trim().is_empty() {
+            return futures::future::err(DatabaseError::EmptyAttachmentUrl).boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("add_attachment", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn.transaction().context(SqliteError {
+                operation: "add_attachment.begin",
+            })?;
+
+            let exists: bool = txn
+                .query_row(
+                    "SELECT EXISTS(
+                    SELECT 1 FROM transactions WHERE id = $1 AND deleted_at IS NULL
+                )",
+                    &[&transaction_id],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "add_attachment.check_transaction",
+                })?;
+
+            if !exists {
+                return Err(DatabaseError::UnknownTransaction { id: transaction_id });
+            }
+
+            let count: i64 = txn
+                .query_row(
+                    "SELECT COUNT(*) FROM attachments WHERE transaction_id = $1",
+                    &[&transaction_id],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "add_attachment.count",
+                })?;
+
+            if count as usize >= MAX_ATTACHMENTS_PER_TRANSACTION {
+                return Err(DatabaseError::TooManyAttachments {
+                    transaction_id,
+                    max: MAX_ATTACHMENTS_PER_TRANSACTION,
+                });
+            }
+
+            txn.execute(
+                "INSERT INTO attachments (transaction_id, url, uploaded_at)
+                VALUES ($1, $2, $3)",
+                params![transaction_id, url, chrono::Utc::now().timestamp()],
+            )
+            .context(SqliteError {
+                operation: "add_attachment.insert",
+            })?;
+
+            let id = txn.last_insert_rowid();
+
+            txn.commit().context(SqliteError {
+                operation: "add_attachment.commit",
+            })?;
+
+            Ok(id)
+        })
+    }
+
+    fn get_attachments(
+        &self,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Attachment>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_attachments", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, transaction_id, url, uploaded_at
+                FROM attachments
+                WHERE transaction_id = $1
+                ORDER BY id ASC
+                "#,
+                )
+                .context(SqliteError {
+                    operation: "get_attachments",
+                })?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&transaction_id], |row| {
+                    Ok(Attachment {
+                        id: row.get(0)?,
+                        transaction_id: row.get(1)?,
+                        url: row.get(2)?,
+                        uploaded_at: chrono::Utc.timestamp(row.get(3)?, 0),
+                    })
+                })
+                .context(SqliteError {
+                    operation: "get_attachments",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_attachments",
+            })
+        })
+    }
+
+    fn get_most_active_users(
+        &self,
+        limit: u32,
+        exclude_reversed: bool,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_most_active_users",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(&format!(
+                        r#"
+                WITH counted AS (
+                    SELECT shafter, shaftee FROM transactions
+                    WHERE deleted_at IS NULL AND {}
+                )
+                SELECT user_id, COUNT(*) AS count
+                FROM (
+                    SELECT shafter AS user_id FROM counted
+                    UNION ALL
+                    SELECT shaftee AS user_id FROM counted
+                ) t
+                GROUP BY user_id
+                ORDER BY count DESC, user_id ASC
+                LIMIT $1
+                "#,
+                        reversed_filter(exclude_reversed)
+                    ))
+                    .context(SqliteError {
+                        operation: "get_most_active_users",
+                    })?;
+
+                let rows: Result<LinearMap<String, i64>, _> = stmt
+                    .query_map(&[&limit], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .context(SqliteError {
+                        operation: "get_most_active_users",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_most_active_users",
+                })
+            },
+        )
+    }
+
+    fn get_user_by_display_name(
+        &self,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_user_by_display_name",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let user = conn
+                    .query_row(
+                        &format!(
+                            r#"
+                SELECT user_id, display_name, COALESCE(balance, 0),
+                    COALESCE(created_at, 0), version
+                FROM users
+                LEFT JOIN ({}) USING (user_id)
+                WHERE display_name = $1 COLLATE NOCASE
+                ORDER BY user_id
+                LIMIT 1
+                "#,
+                            BALANCES_SQL
+                        ),
+                        &[&display_name],
+                        |row| user_from_row(row, 0),
+                    )
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "get_user_by_display_name",
+                    })?;
+
+                Ok(user)
+            },
+        )
+    }
+
+    fn get_team_balances(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_team_balances", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(&format!(
+                    r#"
+                SELECT team, SUM(COALESCE(balance, 0)) AS team_balance
+                FROM user_teams
+                LEFT JOIN ({}) USING (user_id)
+                GROUP BY team
+                ORDER BY team_balance ASC, team ASC
+                "#,
+                    BALANCES_SQL
+                ))
+                .context(SqliteError {
+                    operation: "get_team_balances",
+                })?;
+
+            let rows: Result<LinearMap<String, i64>, _> = stmt
+                .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context(SqliteError {
+                    operation: "get_team_balances",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_team_balances",
+            })
+        })
+    }
+
+    fn shaft_users(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+
+        self.spawn("shaft_users", move || -> Result<_, DatabaseError> {
+            for transaction in &transactions {
+                validate_shaft(transaction, max_clock_skew, max_amount, max_reason_length)?;
+            }
+
+            let mut conn = db_pool.get()?;
+
+            // Take the write lock up front, like shaft_user, so that nothing
+            // else can change the balances we check.
+            let txn = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .context(SqliteError {
+                    operation: "shaft_users.begin",
+                })?;
+
+            // Each shaft is checked after the ones before it are inserted, so
+            // that they count towards the limits.
+            for transaction in &transactions {
+                check_shaft_limits(&txn, transaction, shaft_rate_limit, max_debt)?;
+                insert_transaction(&txn, transaction.clone())?;
+            }
+
+            txn.commit().context(SqliteError {
+                operation: "shaft_users.commit",
+            })?;
+
+            if let Some(cache) = &balance_cache {
+                cache.invalidate_parties(&transactions);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn try_shaft_users(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> LocalBoxFuture<'static, Result<Vec<Result<(), DatabaseError>>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+
+        self.spawn("try_shaft_users", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+
+            // Each shaft gets its own SQL transaction, so that it's checked
+            // and inserted atomically but doesn't depend on the others.
+            let mut try_shaft = |transaction: &Transaction| -> Result<(), DatabaseError> {
+                validate_shaft(transaction, max_clock_skew, max_amount, max_reason_length)?;
+
+                let txn = conn
+                    .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                    .context(SqliteError {
+                        operation: "try_shaft_users.begin",
+                    })?;
+
+                check_shaft_limits(&txn, transaction, shaft_rate_limit, max_debt)?;
+                insert_transaction(&txn, transaction.clone())?;
+
+                txn.commit().context(SqliteError {
+                    operation: "try_shaft_users.commit",
+                })
+            };
+
+            let results = transactions.iter().map(&mut try_shaft).collect();
+
+            // Each shaft commits on its own, so some may have gone through
+            // even if others failed.
+            if let Some(cache) = &balance_cache {
+                cache.invalidate_parties(&transactions);
+            }
+
+            Ok(results)
+        })
+    }
+
+    fn get_nettable_pairs(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Vec<NettablePair>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_nettable_pairs", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                WITH directed AS (
+                    SELECT shafter, shaftee, SUM(amount) AS total
+                    FROM transactions
+                    WHERE deleted_at IS NULL
+                    GROUP BY shafter, shaftee
+                )
+                SELECT forward.shafter, forward.shaftee, forward.total, backward.total
+                FROM directed AS forward
+                INNER JOIN directed AS backward
+                    ON backward.shafter = forward.shaftee AND backward.shaftee = forward.shafter
+                WHERE forward.shafter < forward.shaftee
+                    AND forward.total != 0 AND backward.total != 0
+                ORDER BY forward.shafter, forward.shaftee
+                "#,
+                )
+                .context(SqliteError {
+                    operation: "get_nettable_pairs",
+                })?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })
+                .context(SqliteError {
+                    operation: "get_nettable_pairs",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_nettable_pairs",
+            })
+        })
+    }
+
+    fn get_balance_extremes(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<BalanceExtremes, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_balance_extremes",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(&format!(
+                        r#"
+                WITH user_balances AS (
+                    SELECT user_id, display_name, COALESCE(balance, 0) AS balance,
+                        COALESCE(created_at, 0) AS created_at, version
+                    FROM users
+                    LEFT JOIN ({}) USING (user_id)
+                )
+                SELECT * FROM (
+                    SELECT 'max', user_id, display_name, balance, created_at, version
+                    FROM user_balances
+                    ORDER BY balance DESC, user_id ASC LIMIT 1
+                )
+                UNION ALL
+                SELECT * FROM (
+                    SELECT 'min', user_id, display_name, balance, created_at, version
+                    FROM user_balances
+                    ORDER BY balance ASC, user_id ASC LIMIT 1
+                )
+                "#,
+                        BALANCES_SQL
+                    ))
+                    .context(SqliteError {
+                        operation: "get_balance_extremes",
+                    })?;
+
+                let mut max = None;
+                let mut min = None;
+
+                let rows = stmt
+                    .query_map(params![], |row| {
+                        let which: String = row.get(0)?;
+                        let user = user_from_row(row, 1)?;
+                        Ok((which, user))
+                    })
+                    .context(SqliteError {
+                        operation: "get_balance_extremes",
+                    })?;
+
+                for row in rows {
+                    let (which, user) = row.context(SqliteError {
+                        operation: "get_balance_extremes",
+                    })?;
+
+                    if which == "max" {
+                        max = Some(user);
+                    } else {
+                        min = Some(user);
+                    }
+                }
+
+                Ok((max, min))
+            },
+        )
+    }
+
+    fn delete_transaction(&self, id: i64) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.soft_delete_transaction(id)
+    }
+
+    fn set_display_name(
+        &self,
+        user_id: UserId,
+        display_name: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let display_name = display_name.trim().to_string();
+        if display_name.is_empty() {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: "display name must not be empty".to_string(),
+            })
+            .boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+        let require_unique_display_name = self.require_unique_display_name;
+
+        self.spawn("set_display_name", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            if require_unique_display_name {
+                check_display_name_unique(&conn, user_id.as_str(), &display_name)?;
+            }
+
+            let updated = conn
+                .execute(
+                    "UPDATE users
+                SET display_name = $1, display_name_overridden = 1, version = version + 1
+                WHERE user_id = $2",
+                    params![display_name, user_id],
+                )
+                .context(SqliteError {
+                    operation: "set_display_name",
+                })?;
+
+            if updated == 0 {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: user_id.to_string(),
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    fn purge_expired_tokens(&self) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "purge_expired_tokens",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let now = chrono::Utc::now().timestamp();
+
+                let deleted = conn
+                    .execute("DELETE FROM tokens WHERE expires_at <= $1", params![now])
+                    .context(SqliteError {
+                        operation: "purge_expired_tokens",
+                    })?;
+
+                Ok(deleted as u64)
+            },
+        )
+    }
+
+    fn delete_all_tokens_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "delete_all_tokens_for_user",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let deleted = conn
+                    .execute("DELETE FROM tokens WHERE user_id = $1", &[&user_id])
+                    .context(SqliteError {
+                        operation: "delete_all_tokens_for_user",
+                    })?;
+
+                Ok(deleted as u64)
+            },
+        )
+    }
+
+    fn get_leaderboard(
+        &self,
+        limit: u32,
+        ascending: bool,
+        include_inactive: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.read_pool();
+
+        let order = if ascending {
+            SortOrder::Asc
+        } else {
+            SortOrder::Desc
+        };
+        let filter = active_filter(include_inactive);
+
+        self.spawn("get_leaderboard", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(&format!(
+                    r#"
+                SELECT user_id, display_name, COALESCE(balance, 0) AS balance,
+                    COALESCE(created_at, 0), version
+                FROM users
+                LEFT JOIN ({}) USING (user_id)
+                WHERE {}
+                ORDER BY balance {}, user_id ASC
+                LIMIT $1
+                "#,
+                    BALANCES_SQL,
+                    filter,
+                    order.as_sql(),
+                ))
+                .context(SqliteError {
+                    operation: "get_leaderboard",
+                })?;
+
+            let rows: Result<Vec<User>, _> = stmt
+                .query_map(&[&limit], |row| user_from_row(row, 0))
+                .context(SqliteError {
+                    operation: "get_leaderboard",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_leaderboard",
+            })
+        })
+    }
+
+    fn get_balance_between(
+        &self,
+        user_a: UserId,
+        user_b: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_balance_between",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                balance_between(&conn, user_a.as_str(), user_b.as_str())
+            },
+        )
+    }
+
+    fn get_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_user", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let user = conn
+                .query_row(
+                    &format!(
+                        r#"
+                SELECT user_id, display_name, COALESCE(balance, 0),
+                    COALESCE(created_at, 0), version
+                FROM users
+                LEFT JOIN ({}) USING (user_id)
+                WHERE user_id = $1
+                "#,
+                        BALANCES_SQL
+                    ),
+                    &[&user_id],
+                    |row| user_from_row(row, 0),
+                )
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError {
+                    operation: "get_user",
+                })?;
+
+            Ok(user)
+        })
+    }
+
+    fn search_users(
+        &self,
+        query: String,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+        let limit = limit.min(i64::from(MAX_SEARCH_RESULTS));
+
+        let db_pool = self.read_pool();
+        let query = fold_for_search(&query);
+
+        self.spawn("search_users", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            // SQLite can't ignore accents, so we match in Rust. There are
+            // few enough users that scanning them all is fine.
+            let mut stmt = conn
+                .prepare_cached(&format!(
+                    r#"
+                SELECT user_id, display_name, COALESCE(balance, 0),
+                    COALESCE(created_at, 0), version
+                FROM users
+                LEFT JOIN ({}) USING (user_id)
+                ORDER BY display_name COLLATE NOCASE, user_id
+                "#,
+                    BALANCES_SQL
+                ))
+                .context(SqliteError {
+                    operation: "search_users",
+                })?;
+
+            let rows = stmt
+                .query_map(params![], |row| user_from_row(row, 0))
+                .context(SqliteError {
+                    operation: "search_users",
+                })?;
+
+            let mut users = Vec::new();
+            for user in rows {
+                let user = user.context(SqliteError {
+                    operation: "search_users",
+                })?;
+                if fold_for_search(&user.display_name).contains(&query) {
+                    users.push(user);
+                    if users.len() as i64 == limit {
+                        break;
+                    }
+                }
+            }
+
+            Ok(users)
+        })
+    }
+
+    fn export_transactions_csv(&self) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "export_transactions_csv",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE deleted_at IS NULL
+                ORDER BY id
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "export_transactions_csv",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "export_transactions_csv",
+                    })?
+                    .collect();
+
+                let transactions = rows.context(SqliteError {
+                    operation: "export_transactions_csv",
+                })?;
+
+                transactions_to_csv(transactions)
+            },
+        )
+    }
+
+    fn count_transactions_between(
+        &self,
+        shafter: UserId,
+        shaftee: UserId,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "count_transactions_between",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                conn.query_row(
+                    "SELECT COUNT(*) FROM transactions
+                WHERE shafter = $1 AND shaftee = $2 AND deleted_at IS NULL",
+                    params![shafter, shaftee],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "count_transactions_between",
+                })
+            },
+        )
+    }
+
+    fn get_transactions_in_range(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        if from > to {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: "range start must not be after its end".to_string(),
+            })
+            .boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_transactions_in_range",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE time_sec BETWEEN $1 AND $2 AND deleted_at IS NULL
+                ORDER BY id DESC
+                LIMIT $3
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_transactions_in_range",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![from.timestamp(), to.timestamp(), limit], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_transactions_in_range",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_transactions_in_range",
+                })
+            },
+        )
+    }
+
+    fn migrate(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("migrate", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+
+            // Take the write lock up front, so that concurrent callers wait
+            // for us rather than applying the same migrations.
+            let txn = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .context(SqliteError {
+                    operation: "migrate.begin",
+                })?;
+
+            txn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS schema_version ( version BIGINT NOT NULL )",
+            )
+            .context(SqliteError {
+                operation: "migrate.create_schema_version",
+            })?;
+
+            let version: i64 = txn
+                .query_row(
+                    "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+                    params![],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "migrate.get_version",
+                })?;
+
+            for migration in MIGRATIONS.iter().skip(version as usize) {
+                txn.execute_batch(migration).context(SqliteError {
+                    operation: "migrate.apply",
+                })?;
+            }
+
+            if version < MIGRATIONS.len() as i64 {
+                txn.execute("DELETE FROM schema_version", params![])
+                    .context(SqliteError {
+                        operation: "migrate.clear_version",
+                    })?;
+                txn.execute(
+                    "INSERT INTO schema_version (version) VALUES ($1)",
+                    params![MIGRATIONS.len() as i64],
+                )
+                .context(SqliteError {
+                    operation: "migrate.set_version",
+                })?;
+            }
+
+            txn.commit().context(SqliteError {
+                operation: "migrate.commit",
+            })
+        })
+    }
+
+    fn get_summary(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<UserSummary>, DatabaseError>> {
+        if from > to {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: "range start must not be after its end".to_string(),
+            })
+            .boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_summary", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                SELECT user_id, COALESCE(display_name, user_id),
+                    SUM(shafted), SUM(received)
+                FROM (
+                    SELECT shafter AS user_id, amount AS shafted, 0 AS received
+                    FROM transactions
+                    WHERE time_sec BETWEEN $1 AND $2 AND deleted_at IS NULL
+                    UNION ALL
+                    SELECT shaftee AS user_id, 0 AS shafted, amount AS received
+                    FROM transactions
+                    WHERE time_sec BETWEEN $1 AND $2 AND deleted_at IS NULL
+                ) t
+                LEFT JOIN users USING (user_id)
+                GROUP BY user_id
+                ORDER BY user_id
+                "#,
+                )
+                .context(SqliteError {
+                    operation: "get_summary",
+                })?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![from.timestamp(), to.timestamp()], |row| {
+                    let total_shafted: i64 = row.get(2)?;
+                    let total_received: i64 = row.get(3)?;
+                    Ok(UserSummary {
+                        user_id: row.get(0)?,
+                        display_name: row.get(1)?,
+                        total_shafted,
+                        total_received,
+                        net: total_shafted - total_received,
+                    })
+                })
+                .context(SqliteError {
+                    operation: "get_summary",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_summary",
+            })
+        })
+    }
+
+    fn get_user_by_identity(
+        &self,
+        provider: String,
+        provider_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_user_by_identity",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let row = conn
+                    .query_row(
+                        "SELECT user_id FROM identities WHERE provider = $1 AND provider_id = $2",
+                        params![&provider, &provider_id],
+                        |row| row.get(0),
+                    )
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "get_user_by_identity",
+                    })?;
+
+                Ok(row)
+            },
+        )
+    }
+
+    fn link_identity(
+        &self,
+        user_id: UserId,
+        provider: String,
+        provider_id: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("link_identity", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn.transaction().context(SqliteError {
+                operation: "link_identity.begin",
+            })?;
+
+            let exists: bool = txn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM users WHERE user_id = $1)",
+                    &[&user_id],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "link_identity.check_user",
+                })?;
+
+            if !exists {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: user_id.to_string(),
+                });
+            }
+
+            txn.execute(
+                "INSERT INTO identities (provider, provider_id, user_id)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (provider, provider_id) DO NOTHING",
+                params![&provider, &provider_id, &user_id],
+            )
+            .context(SqliteError {
+                operation: "link_identity.insert",
+            })?;
+
+            let linked_user_id: String = txn
+                .query_row(
+                    "SELECT user_id FROM identities WHERE provider = $1 AND provider_id = $2",
+                    params![&provider, &provider_id],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "link_identity.select",
+                })?;
+
+            if linked_user_id != user_id.as_str() {
+                return Err(DatabaseError::Conflict {
+                    constraint: "identities.provider, identities.provider_id".to_string(),
+                });
+            }
+
+            txn.commit().context(SqliteError {
+                operation: "link_identity.commit",
+            })
+        })
+    }
+
+    fn merge_users(
+        &self,
+        from_user_id: UserId,
+        into_user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        if from_user_id == into_user_id {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: "can't merge a user into themselves".to_string(),
+            })
+            .boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn("merge_users", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn.transaction().context(SqliteError {
+                operation: "merge_users.begin",
+            })?;
+
+            for user_id in &[&from_user_id, &into_user_id] {
+                let exists: bool = txn
+                    .query_row(
+                        "SELECT EXISTS(SELECT 1 FROM users WHERE user_id = $1)",
+                        &[user_id],
+                        |row| row.get(0),
+                    )
+                    .context(SqliteError {
+                        operation: "merge_users.check_user",
+                    })?;
+
+                if !exists {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: user_id.to_string(),
+                    });
+                }
+            }
+
+            txn.execute(
+                "UPDATE transactions SET deleted_at = $1
+                WHERE deleted_at IS NULL
+                    AND ((shafter = $2 AND shaftee = $3) OR (shafter = $3 AND shaftee = $2))",
+                params![chrono::Utc::now().timestamp(), &from_user_id, &into_user_id],
+            )
+            .context(SqliteError {
+                operation: "merge_users.delete_between",
+            })?;
+
+            let params = params![&into_user_id, &from_user_id];
+
+            txn.execute(
+                "UPDATE transactions SET shafter = $1 WHERE shafter = $2",
+                params,
+            )
+            .context(SqliteError {
+                operation: "merge_users.update_shafter",
+            })?;
+
+            txn.execute(
+                "UPDATE transactions SET shaftee = $1 WHERE shaftee = $2",
+                params,
+            )
+            .context(SqliteError {
+                operation: "merge_users.update_shaftee",
+            })?;
+
+            txn.execute("UPDATE tokens SET user_id = $1 WHERE user_id = $2", params)
+                .context(SqliteError {
+                    operation: "merge_users.update_tokens",
+                })?;
+
+            txn.execute(
+                "UPDATE identities SET user_id = $1 WHERE user_id = $2",
+                params,
+            )
+            .context(SqliteError {
+                operation: "merge_users.update_identities",
+            })?;
+
+            // Users can only be in one team, so keep `into`'s if it has one.
+            txn.execute(
+                "UPDATE OR IGNORE user_teams SET user_id = $1 WHERE user_id = $2",
+                params,
+            )
+            .context(SqliteError {
+                operation: "merge_users.update_team",
+            })?;
+
+            txn.execute(
+                "DELETE FROM user_teams WHERE user_id = $1",
+                &[&from_user_id],
+            )
+            .context(SqliteError {
+                operation: "merge_users.delete_team",
+            })?;
+
+            txn.execute("DELETE FROM users WHERE user_id = $1", &[&from_user_id])
+                .context(SqliteError {
+                    operation: "merge_users.delete_user",
+                })?;
+
+            txn.commit().context(SqliteError {
+                operation: "merge_users.commit",
+            })?;
+
+            if let Some(cache) = &balance_cache {
+                cache.clear();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn stream_all_transactions(
+        &self,
+    ) -> LocalBoxStream<'static, Result<Transaction, DatabaseError>> {
+        let db = self.clone();
+
+        // Page through by ID rather than offset, so that each chunk is a
+        // cheap index lookup and concurrent deletes don't skip rows. The
+        // state is the ID to continue after, or `None` once we're done.
+        stream::unfold(Some(0), move |after_id| {
+            let db = db.clone();
+
+            async move {
+                let after_id = after_id?;
+
+                match db.get_transactions_after(after_id, STREAM_CHUNK_SIZE).await {
+                    Ok(chunk) => {
+                        let next = if chunk.len() < STREAM_CHUNK_SIZE as usize {
+                            None
+                        } else {
+                            chunk.last().and_then(|transaction| transaction.id)
+                        };
+
+                        Some((stream::iter(chunk.into_iter().map(Ok)).left_stream(), next))
+                    }
+                    Err(err) => Some((stream::once(async { Err(err) }).right_stream(), None)),
+                }
+            }
+        })
+        .flatten()
+        .boxed_local()
+    }
+
+    fn get_latest_transaction_between(
+        &self,
+        shafter: UserId,
+        shaftee: UserId,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_latest_transaction_between",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE shafter = $1 AND shaftee = $2 AND deleted_at IS NULL
+                ORDER BY id DESC
+                LIMIT 1
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_latest_transaction_between",
+                    })?;
+
+                let row = stmt
+                    .query_row(params![shafter, shaftee], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "get_latest_transaction_between",
+                    })?;
+
+                Ok(row)
+            },
+        )
+    }
+
+    fn get_last_transactions_filtered(
+        &self,
+        limit: u32,
+        min_amount: Option<i64>,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let min_amount = match min_amount {
+            Some(min_amount) => min_amount,
+            None => return self.get_last_transactions(limit),
+        };
+
+        if min_amount < 0 {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: format!("min_amount must be non-negative, got {}", min_amount),
+            })
+            .boxed();
+        }
+
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_last_transactions_filtered",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE amount >= $1 AND deleted_at IS NULL
+                ORDER BY id DESC
+                LIMIT $2
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_last_transactions_filtered",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![min_amount, limit], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_last_transactions_filtered",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_last_transactions_filtered",
+                })
+            },
+        )
+    }
+
+    fn settle_between(
+        &self,
+        user_a: UserId,
+        user_b: UserId,
+        reason: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_reason_length = self.max_reason_length;
+
+        self.spawn("settle_between", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+
+            // Take the write lock up front so that nothing can be shafted
+            // between reading the balance and settling it.
+            let txn = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .context(SqliteError {
+                    operation: "settle_between.begin",
+                })?;
+
+            let balance = balance_between(&txn, user_a.as_str(), user_b.as_str())?;
+            if balance == 0 {
+                return Ok(0);
+            }
+
+            let transaction = settlement(user_a.as_str(), user_b.as_str(), balance, reason.clone());
+            validate_shaft(&transaction, max_clock_skew, None, max_reason_length)?;
+            insert_transaction(&txn, transaction)?;
+
+            txn.commit().context(SqliteError {
+                operation: "settle_between.commit",
+            })?;
+
+            if let Some(cache) = &balance_cache {
+                cache.invalidate(&[user_a.as_str(), user_b.as_str()]);
+            }
+
+            Ok(balance)
+        })
+    }
+
+    fn get_total_outstanding(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_total_outstanding",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                total_outstanding(&conn)
+            },
+        )
+    }
+
+    fn list_tokens_for_user(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Vec<TokenInfo>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "list_tokens_for_user",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let now = chrono::Utc::now().timestamp();
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT COALESCE(created_at, 0), last_used_at
+                FROM tokens
+                WHERE user_id = $1 AND (expires_at IS NULL OR expires_at > $2)
+                ORDER BY created_at ASC, rowid ASC
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "list_tokens_for_user",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![user_id, now], |row| {
+                        let last_used_at: Option<i64> = row.get(1)?;
+                        Ok(TokenInfo {
+                            created_at: chrono::Utc.timestamp(row.get(0)?, 0),
+                            last_used_at: last_used_at.map(|time| chrono::Utc.timestamp(time, 0)),
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "list_tokens_for_user",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "list_tokens_for_user",
+                })
+            },
+        )
+    }
+
+    fn shaft_many(
+        &self,
+        shafter: UserId,
+        shaftees: Vec<(UserId, i64)>,
+        reason: String,
+        datetime: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        self.shaft_users(split_shaft(shafter, shaftees, reason, datetime))
+    }
+
+    fn get_stats(&self) -> LocalBoxFuture<'static, Result<SystemStats, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_stats", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+
+            // Read everything from the same snapshot so the numbers agree.
+            let txn = conn.transaction().context(SqliteError {
+                operation: "get_stats.begin",
+            })?;
+
+            let (user_count, transaction_count) = txn
+                .prepare_cached(
+                    r#"SELECT
+                    (SELECT COUNT(*) FROM users),
+                    (SELECT COUNT(*) FROM transactions WHERE deleted_at IS NULL)
+                "#,
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_row(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+                })
+                .context(SqliteError {
+                    operation: "get_stats.count",
+                })?;
+
+            let total_outstanding = total_outstanding(&txn)?;
+
+            Ok(SystemStats {
+                user_count,
+                transaction_count,
+                total_outstanding,
+            })
+        })
+    }
+
+    fn reverse_transaction(
+        &self,
+        id: i64,
+        reason: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_reason_length = self.max_reason_length;
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn(
+            "reverse_transaction",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+
+                // Take the write lock up front so that two concurrent reversals
+                // can't both see the original as unreversed.
+                let txn = conn
+                    .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                    .context(SqliteError {
+                        operation: "reverse_transaction.begin",
+                    })?;
+
+                let original: Option<(String, String, i64, Option<String>, bool)> = txn
+                    .query_row(
+                        r#"SELECT shafter, shaftee, amount, category, EXISTS(
+                        SELECT 1 FROM transactions
+                        WHERE reversed_transaction_id = $1 AND deleted_at IS NULL
+                    )
+                    FROM transactions
+                    WHERE id = $1 AND deleted_at IS NULL"#,
+                        params![id],
+                        |row| {
+                            Ok((
+                                row.get(0)?,
+                                row.get(1)?,
+                                row.get(2)?,
+                                row.get(3)?,
+                                row.get(4)?,
+                            ))
+                        },
+                    )
+                    .map(Some)
+                    .or_else(|err| {
+                        if let rusqlite::Error::QueryReturnedNoRows = err {
+                            Ok(None)
+                        } else {
+                            Err(err)
+                        }
+                    })
+                    .context(SqliteError {
+                        operation: "reverse_transaction.select",
+                    })?;
+
+                let (shafter, shaftee, amount, category, reversed) = match original {
+                    Some(original) => original,
+                    None => return Err(DatabaseError::UnknownTransaction { id }),
+                };
+
+                if reversed {
+                    return Err(DatabaseError::AlreadyReversed { id });
+                }
+
+                let reversal = Transaction {
+                    id: None,
+                    shafter: shaftee,
+                    shaftee: shafter,
+                    amount,
+                    datetime: chrono::Utc::now(),
+                    reason: Some(reason.clone()),
+                    idempotency_key: None,
+                    category,
+                };
+                // The original passed the amount limit when it was made, which
+                // may since have been lowered.
+                validate_shaft(&reversal, max_clock_skew, None, max_reason_length)?;
+
+                txn.execute(
+                    "INSERT INTO transactions
+                (shafter, shaftee, amount, time_sec, reason, category, reversed_transaction_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                    params![
+                        &reversal.shafter,
+                        &reversal.shaftee,
+                        reversal.amount,
+                        reversal.datetime.timestamp(),
+                        &reversal.reason,
+                        &reversal.category,
+                        id,
+                    ],
+                )
+                .context(SqliteError {
+                    operation: "reverse_transaction.insert",
+                })?;
+                let reversal_id = txn.last_insert_rowid();
+
+                txn.commit().context(SqliteError {
+                    operation: "reverse_transaction.commit",
+                })?;
+
+                if let Some(cache) = &balance_cache {
+                    cache.invalidate(&[&reversal.shafter, &reversal.shaftee]);
+                }
+
+                Ok(reversal_id)
+            },
+        )
+    }
+
+    fn set_user_active(
+        &self,
+        user_id: UserId,
+        active: bool,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("set_user_active", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let updated = conn
+                .execute(
+                    "UPDATE users SET active = $1 WHERE user_id = $2",
+                    params![active, user_id],
+                )
+                .context(SqliteError {
+                    operation: "set_user_active",
+                })?;
+
+            if updated == 0 {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: user_id.to_string(),
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_balance_at(
+        &self,
+        user: UserId,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_balance_at", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let row = conn
+                .prepare_cached(
+                    r#"SELECT (
+                    SELECT COALESCE(SUM(amount), 0)
+                    FROM transactions
+                    WHERE shafter = user_id AND deleted_at IS NULL AND time_sec <= $1
+                ) - (
+                    SELECT COALESCE(SUM(amount), 0)
+                    FROM transactions
+                    WHERE shaftee = user_id AND deleted_at IS NULL AND time_sec <= $1
+                )
+                FROM users
+                WHERE user_id = $2"#,
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_row(params![at.timestamp(), user], |row| row.get(0))
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                });
+
+            let row = match row {
+                Err(ref err) if is_integer_overflow(err) => {
+                    return Err(DatabaseError::BalanceOverflow {
+                        user_id: user.to_string(),
+                    })
+                }
+                row => row.context(SqliteError {
+                    operation: "get_balance_at",
+                })?,
+            };
+
+            row.ok_or(DatabaseError::UnknownUser {
+                user_id: user.to_string(),
+            })
+        })
+    }
+
+    fn get_transactions_by_category(
+        &self,
+        category: String,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_transactions_by_category",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE category = $1 AND deleted_at IS NULL
+                ORDER BY id DESC
+                LIMIT $2
+                "#,
+                    )
+                    .context(SqliteError {
+                        operation: "get_transactions_by_category",
+                    })?;
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(params![category, limit], |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_transactions_by_category",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_transactions_by_category",
+                })
+            },
+        )
+    }
+
+    fn get_category_totals(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_category_totals",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                let query = format!(
+                    r#"SELECT COALESCE(category, '') AS category, SUM(amount) AS total
+                FROM transactions
+                WHERE deleted_at IS NULL AND {}
+                GROUP BY COALESCE(category, '')
+                ORDER BY total DESC, category
+                "#,
+                    reversed_filter(true)
+                );
+
+                let mut stmt = conn.prepare_cached(&query).context(SqliteError {
+                    operation: "get_category_totals",
+                })?;
+
+                let rows: Result<LinearMap<String, i64>, _> = stmt
+                    .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .context(SqliteError {
+                        operation: "get_category_totals",
+                    })?
+                    .collect();
+
+                rows.context(SqliteError {
+                    operation: "get_category_totals",
+                })
+            },
+        )
+    }
+
+    fn import_transactions(
+        &self,
+        transactions: Vec<Transaction>,
+        validate: bool,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+
+        self.spawn(
+            "import_transactions",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+                let txn = conn.transaction().context(SqliteError {
+                    operation: "import_transactions.begin",
+                })?;
+
+                if validate {
+                    let user_ids: Result<HashSet<String>, _> = txn
+                        .prepare_cached("SELECT user_id FROM users")
+                        .and_then(|mut stmt| stmt.query_map(params![], |row| row.get(0))?.collect())
+                        .context(SqliteError {
+                            operation: "import_transactions.users",
+                        });
+                    let user_ids = user_ids?;
+
+                    for transaction in &transactions {
+                        validate_shaft(transaction, max_clock_skew, max_amount, max_reason_length)?;
+
+                        for user_id in &[&transaction.shafter, &transaction.shaftee] {
+                            if !user_ids.contains(user_id.as_str()) {
+                                return Err(DatabaseError::UnknownUser {
+                                    user_id: user_id.to_string(),
+                                });
+                            }
+                        }
+                    }
+                }
+
+                let mut inserted = 0;
+                for batch in transactions.chunks(IMPORT_BATCH_SIZE) {
+                    inserted += import_batch(&txn, batch)?;
+                }
+
+                txn.commit().context(SqliteError {
+                    operation: "import_transactions.commit",
+                })?;
+
+                if let Some(cache) = &balance_cache {
+                    cache.clear();
+                }
+
+                Ok(inserted)
+            },
+        )
+    }
+
+    fn get_counterparties(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<Vec<(User, i64)>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_counterparties", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let exists: bool = conn
+                .prepare_cached("SELECT EXISTS(SELECT 1 FROM users WHERE user_id = $1)")
+                .and_then(|mut stmt| stmt.query_row(&[&user_id], |row| row.get(0)))
+                .context(SqliteError {
+                    operation: "get_counterparties.check_user",
+                })?;
+
+            if !exists {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: user_id.to_string(),
+                });
+            }
+
+            let query = format!(
+                r#"
+                SELECT user_id, display_name, COALESCE(balance, 0),
+                    COALESCE(created_at, 0), version, net
+                FROM (
+                    SELECT counterparty, SUM(amount) AS net
+                    FROM (
+                        SELECT shaftee AS counterparty, amount
+                        FROM transactions WHERE shafter = $1 AND deleted_at IS NULL
+                        UNION ALL
+                        SELECT shafter AS counterparty, -amount
+                        FROM transactions WHERE shaftee = $1 AND deleted_at IS NULL
+                    ) GROUP BY counterparty
+                )
+                JOIN users ON user_id = counterparty
+                LEFT JOIN ({}) USING (user_id)
+                WHERE user_id != $1
+                ORDER BY ABS(net) DESC, user_id
+                "#,
+                BALANCES_SQL
+            );
+
+            let mut stmt = conn.prepare_cached(&query).context(SqliteError {
+                operation: "get_counterparties",
+            })?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&user_id], |row| Ok((user_from_row(row, 0)?, row.get(5)?)))
+                .context(SqliteError {
+                    operation: "get_counterparties",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_counterparties",
+            })
+        })
+    }
+
+    fn validate_shaft(
+        &self,
+        transaction: &Transaction,
+    ) -> LocalBoxFuture<'static, Result<ShaftPreview, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let max_clock_skew = self.max_clock_skew;
+        let max_amount = self.max_amount;
+        let max_reason_length = self.max_reason_length;
+        let shaft_rate_limit = self.shaft_rate_limit;
+        let max_debt = self.max_debt;
+        let transaction = transaction.clone();
+
+        self.spawn("validate_shaft", move || -> Result<_, DatabaseError> {
+            validate_shaft(&transaction, max_clock_skew, max_amount, max_reason_length)?;
+
+            let mut conn = db_pool.get()?;
+
+            // Read from one snapshot, as shaft_user would.
+            let txn = conn.transaction().context(SqliteError {
+                operation: "validate_shaft.begin",
+            })?;
+
+            check_shaft_limits(&txn, &transaction, shaft_rate_limit, max_debt)
+        })
+    }
+
+    fn get_transaction(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_transaction", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            conn.prepare_cached(
+                r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE id = $1 AND deleted_at IS NULL
+                "#,
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(&[&id], |row| {
+                    Ok(Transaction {
+                        id: row.get(0)?,
+                        shafter: row.get(1)?,
+                        shaftee: row.get(2)?,
+                        amount: row.get(3)?,
+                        datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                        reason: row.get(5)?,
+                        idempotency_key: row.get(6)?,
+                        category: row.get(7)?,
+                    })
+                })
+            })
+            .map(Some)
+            .or_else(|err| {
+                if let rusqlite::Error::QueryReturnedNoRows = err {
+                    Ok(None)
+                } else {
+                    Err(err)
+                }
+            })
+            .context(SqliteError {
+                operation: "get_transaction",
+            })
+        })
+    }
+
+    fn get_personal_ledger(
+        &self,
+        user_id: UserId,
+    ) -> LocalBoxFuture<'static, Result<PersonalLedger, DatabaseError>> {
+        self.get_counterparties(user_id)
+            .map_ok(personal_ledger)
+            .boxed_local()
+    }
+
+    fn resolve_token(
+        &self,
+        token: Token,
+    ) -> LocalBoxFuture<'static, Result<Option<UserId>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("resolve_token", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let user_id: Option<String> = conn
+                .prepare_cached(
+                    "SELECT user_id FROM tokens
+                    WHERE token = $1 AND (expires_at IS NULL OR expires_at > $2)",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_row(
+                        params![hash_token(token.as_str()), chrono::Utc::now().timestamp()],
+                        |row| row.get(0),
+                    )
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError {
+                    operation: "resolve_token",
+                })?;
+
+            Ok(user_id.map(UserId::from))
+        })
+    }
+
+    fn get_daily_activity(
+        &self,
+        user_id: UserId,
+        days: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<(chrono::NaiveDate, i64)>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let start = activity_window_start(days);
+
+        self.spawn("get_daily_activity", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT time_sec / 86400 AS day, SUM(amount)
+                FROM transactions
+                WHERE shafter = $1 AND time_sec >= $2 AND deleted_at IS NULL
+                GROUP BY day
+                ORDER BY day
+                "#,
+                )
+                .context(SqliteError {
+                    operation: "get_daily_activity",
+                })?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![user_id, start], |row| {
+                    let day: i64 = row.get(0)?;
+                    Ok((day_to_date(day), row.get(1)?))
+                })
+                .context(SqliteError {
+                    operation: "get_daily_activity",
+                })?
+                .collect();
+
+            rows.context(SqliteError {
+                operation: "get_daily_activity",
+            })
+        })
+    }
+
+    fn count_transactions_for_user(
+        &self,
+        user_id: UserId,
+        direction: TransactionDirection,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let condition = direction_filter(direction);
+
+        self.spawn(
+            "count_transactions_for_user",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                conn.prepare_cached(&format!(
+                    "SELECT COUNT(*) FROM transactions WHERE {} AND deleted_at IS NULL",
+                    condition
+                ))
+                .and_then(|mut stmt| stmt.query_row(&[&user_id], |row| row.get(0)))
+                .context(SqliteError {
+                    operation: "count_transactions_for_user",
+                })
+            },
+        )
+    }
+
+    fn rotate_token(
+        &self,
+        old_token: Token,
+    ) -> LocalBoxFuture<'static, Result<Token, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let token_lifetime = self.token_lifetime;
+
+        self.spawn("rotate_token", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .context(SqliteError {
+                    operation: "rotate_token.begin",
+                })?;
+
+            let created_at = chrono::Utc::now();
+            let old_hash = hash_token(old_token.as_str());
+
+            let existing: Option<(String, TokenScope)> = txn
+                .prepare_cached(
+                    "SELECT user_id, scope FROM tokens
+                    WHERE token = $1 AND (expires_at IS NULL OR expires_at > $2)",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_row(params![old_hash, created_at.timestamp()], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError {
+                    operation: "rotate_token.get",
+                })?;
+
+            let (user_id, scope) = match existing {
+                Some(existing) => existing,
+                None => return Err(DatabaseError::InvalidToken),
+            };
+
+            let token: String = OsRng
+                .sample_iter(&Alphanumeric)
+                .take(SqliteDatabase::TOKEN_LENGTH)
+                .collect();
+            let expires_at = created_at + token_lifetime;
+
+            txn.execute(
+                "INSERT INTO tokens (user_id, token, scope, created_at, expires_at)
+                VALUES ($1, $2, $3, $4, $5)",
+                params![
+                    &user_id,
+                    hash_token(&token),
+                    scope,
+                    created_at.timestamp(),
+                    expires_at.timestamp(),
+                ],
+            )
+            .context(SqliteError {
+                operation: "rotate_token.insert",
+            })?;
+
+            txn.execute("DELETE FROM tokens WHERE token = $1", &[&old_hash])
+                .context(SqliteError {
+                    operation: "rotate_token.delete",
+                })?;
+
+            txn.commit().context(SqliteError {
+                operation: "rotate_token.commit",
+            })?;
+
+            Ok(Token(token))
+        })
+    }
+
+    fn get_last_transactions_named(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<NamedTransaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_last_transactions_named",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                named_transactions(&conn, "1", "t.id DESC", limit).context(SqliteError {
+                    operation: "get_last_transactions_named",
+                })
+            },
+        )
+    }
+
+    #[cfg(feature = "serde")]
+    fn export_json(
+        &self,
+        include_tokens: bool,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("export_json", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+
+            // Read everything in one SQL transaction so the tables are
+            // consistent with each other.
+            let txn = conn.transaction().context(SqliteError {
+                operation: "export_json.begin",
+            })?;
+
+            let backup = export_backup(&txn, include_tokens)?;
+
+            backup_to_json(&backup)
+        })
+    }
+
+    #[cfg(feature = "serde")]
+    fn import_json(&self, data: &str) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let backup = match backup_from_json(data) {
+            Ok(backup) => backup,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+        let balance_cache = self.balance_cache.clone();
+
+        self.spawn("import_json", move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get()?;
+            let txn = conn
+                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .context(SqliteError {
+                    operation: "import_json.begin",
+                })?;
+
+            let has_data: bool = txn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM users)
+                        OR EXISTS(SELECT 1 FROM identities)
+                        OR EXISTS(SELECT 1 FROM transactions)
+                        OR EXISTS(SELECT 1 FROM tokens)
+                        OR EXISTS(SELECT 1 FROM user_teams)
+                        OR EXISTS(SELECT 1 FROM attachments)",
+                    params![],
+                    |row| row.get(0),
+                )
+                .context(SqliteError {
+                    operation: "import_json.check",
+                })?;
+
+            if has_data {
+                return Err(DatabaseError::InvalidInput {
+                    message: "can only import a backup into an empty database".to_string(),
+                });
+            }
+
+            import_backup(&txn, &backup)?;
+
+            txn.commit().context(SqliteError {
+                operation: "import_json.commit",
+            })?;
+
+            if let Some(cache) = &balance_cache {
+                cache.clear();
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_currency(&self) -> LocalBoxFuture<'static, Result<Currency, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_currency", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            conn.query_row(
+                "SELECT currency_symbol, decimal_places FROM settings WHERE id = 1",
+                params![],
+                |row| {
+                    Ok(Currency {
+                        symbol: row.get(0)?,
+                        decimal_places: row.get(1)?,
+                    })
+                },
+            )
+            .context(SqliteError {
+                operation: "get_currency",
+            })
+        })
+    }
+
+    fn set_currency(
+        &self,
+        currency: Currency,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        if let Err(err) = validate_currency(&currency) {
+            return futures::future::err(err).boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("set_currency", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            conn.execute(
+                "INSERT OR REPLACE INTO settings (id, currency_symbol, decimal_places)
+                VALUES (1, $1, $2)",
+                params![currency.symbol, currency.decimal_places],
+            )
+            .context(SqliteError {
+                operation: "set_currency",
+            })?;
+
+            Ok(())
+        })
+    }
+
+    fn set_display_name_checked(
+        &self,
+        user_id: UserId,
+        display_name: String,
+        expected_version: i32,
+    ) -> LocalBoxFuture<'static, Result<i32, DatabaseError>> {
+        let display_name = display_name.trim().to_string();
+        if display_name.is_empty() {
+            return futures::future::err(DatabaseError::InvalidInput {
+                message: "display name must not be empty".to_string(),
+            })
+            .boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+        let require_unique_display_name = self.require_unique_display_name;
+
+        self.spawn(
+            "set_display_name_checked",
+            move || -> Result<_, DatabaseError> {
+                let mut conn = db_pool.get()?;
+                let txn = conn.transaction().context(SqliteError {
+                    operation: "set_display_name_checked.begin",
+                })?;
+
+                if require_unique_display_name {
+                    check_display_name_unique(&txn, user_id.as_str(), &display_name)?;
+                }
+
+                let updated = txn
+                    .execute(
+                        "UPDATE users
+                SET display_name = $1, display_name_overridden = 1, version = version + 1
+                WHERE user_id = $2 AND version = $3",
+                        params![display_name, user_id, expected_version],
+                    )
+                    .context(SqliteError {
+                        operation: "set_display_name_checked.update",
+                    })?;
+
+                if updated == 0 {
+                    let exists: bool = txn
+                        .query_row(
+                            "SELECT EXISTS(SELECT 1 FROM users WHERE user_id = $1)",
+                            &[&user_id],
+                            |row| row.get(0),
+                        )
+                        .context(SqliteError {
+                            operation: "set_display_name_checked.exists",
+                        })?;
+
+                    return if exists {
+                        Err(DatabaseError::VersionConflict {
+                            user_id: user_id.to_string(),
+                            expected_version,
+                        })
+                    } else {
+                        Err(DatabaseError::UnknownUser {
+                            user_id: user_id.to_string(),
+                        })
+                    };
+                }
+
+                txn.commit().context(SqliteError {
+                    operation: "set_display_name_checked.commit",
+                })?;
+
+                Ok(expected_version + 1)
+            },
+        )
+    }
+
+    fn get_transactions_for_users(
+        &self,
+        user_ids: Vec<UserId>,
+        before: Option<i64>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Page<Transaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        if user_ids.is_empty() {
+            return futures::future::ok(Page::from_keyset(Vec::new(), limit, None)).boxed();
+        }
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_transactions_for_users",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                // Both `IN` lists share the same parameters.
+                let users = placeholders(1, user_ids.len());
+                let mut stmt = conn
+                    .prepare_cached(&format!(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key,
+                    category
+                FROM transactions
+                WHERE (shafter IN ({users}) OR shaftee IN ({users})) AND deleted_at IS NULL
+                    AND (${before} IS NULL OR id < ${before})
+                ORDER BY id DESC
+                LIMIT ${limit}
+                "#,
+                        users = users,
+                        before = user_ids.len() + 1,
+                        limit = user_ids.len() + 2,
+                    ))
+                    .context(SqliteError {
+                        operation: "get_transactions_for_users",
+                    })?;
+
+                let mut params: Vec<&dyn ToSql> =
+                    user_ids.iter().map(|id| id as &dyn ToSql).collect();
+                params.push(&before);
+                params.push(&limit);
+
+                let rows: Result<Vec<_>, _> = stmt
+                    .query_map(&params, |row| {
+                        Ok(Transaction {
+                            id: row.get(0)?,
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: row.get(3)?,
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            idempotency_key: row.get(6)?,
+                            category: row.get(7)?,
+                        })
+                    })
+                    .context(SqliteError {
+                        operation: "get_transactions_for_users",
+                    })?
+                    .collect();
+
+                let items = rows.context(SqliteError {
+                    operation: "get_transactions_for_users",
+                })?;
+
+                Ok(Page::from_keyset(items, limit, None))
+            },
+        )
+    }
+
+    fn get_largest_transactions(
+        &self,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<NamedTransaction>, DatabaseError>> {
+        let limit = match validate_limit(limit, self.max_limit) {
+            Ok(limit) => limit,
+            Err(err) => return futures::future::err(err).boxed(),
+        };
+
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(
+            "get_largest_transactions",
+            move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get()?;
+
+                named_transactions(
+                    &conn,
+                    reversed_filter(true),
+                    "t.amount DESC, t.id DESC",
+                    limit,
+                )
+                .context(SqliteError {
+                    operation: "get_largest_transactions",
+                })
+            },
+        )
+    }
+
+    fn get_user_ids(&self) -> LocalBoxFuture<'static, Result<Vec<UserId>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("get_user_ids", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            conn.prepare_cached("SELECT user_id FROM users ORDER BY user_id")
+                .and_then(|mut stmt| stmt.query_map(params![], |row| row.get(0))?.collect())
+                .context(SqliteError {
+                    operation: "get_user_ids",
+                })
+        })
+    }
+
+    fn verify_balances(
+        &self,
+    ) -> LocalBoxFuture<'static, Result<Vec<BalanceMismatch>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn("verify_balances", move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get()?;
+
+            let mut stmt = conn
+                .prepare(&format!(
+                    r#"SELECT user_id, balance_a, balance_b
+                    FROM (
+                        SELECT u.user_id, (
+                            SELECT COALESCE(SUM(amount), 0)
+                            FROM transactions
+                            WHERE shafter = u.user_id AND deleted_at IS NULL
+                        ) - (
+                            SELECT COALESCE(SUM(amount), 0)
+                            FROM transactions
+                            WHERE shaftee = u.user_id AND deleted_at IS NULL
+                        ) AS balance_a, COALESCE(b.balance, 0) AS balance_b
+                        FROM users u
+                        LEFT JOIN ({}) b ON b.user_id = u.user_id
+                    )
+                    WHERE balance_a != balance_b
+                    ORDER BY user_id"#,
+                    BALANCES_SQL
+                ))
+                .context(SqliteError {
+                    operation: "verify_balances",
+                })?;
+
+            let rows = stmt
+                .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .context(SqliteError {
+                    operation: "verify_balances",
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context(SqliteError {
+                    operation: "verify_balances",
+                })?;
+
+            Ok(rows)
+        })
+    }
+}
+
+/// Sets the flag when dropped, c.f. [SqliteDatabase::spawn_cancellable].
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Tell the metrics hook about a finished operation, swallowing any panic so
+/// that the operation's result still gets back to the caller.
+fn report_query(
+    metrics: &Arc<dyn DatabaseMetrics>,
+    method: &'static str,
+    duration: Duration,
+    result: Result<(), ()>,
+) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        metrics.on_query(method, duration, result)
+    }));
+}
+
+/// Run `f`, converting a panic into a [DatabaseError::WorkerPanic].
+fn catch_panic<F, T>(f: &F) -> Result<T, DatabaseError>
+where
+    F: Fn() -> Result<T, DatabaseError>,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+
+        Err(DatabaseError::WorkerPanic { message })
+    })
+}
+
+/// A `WHERE` condition on `transactions` that, if `exclude_reversed` is set,
+/// filters out both reversals and the transactions they reverse, for gross
+/// totals that shouldn't double count.
+fn reversed_filter(exclude_reversed: bool) -> &'static str {
+    if exclude_reversed {
+        "reversed_transaction_id IS NULL AND id NOT IN (
+            SELECT reversed_transaction_id FROM transactions
+            WHERE reversed_transaction_id IS NOT NULL AND deleted_at IS NULL
+        )"
+    } else {
+        "1"
+    }
+}
+
+/// A `WHERE` condition on `transactions` matching those in the given
+/// direction for the user bound to `$1`.
+fn direction_filter(direction: TransactionDirection) -> &'static str {
+    match direction {
+        TransactionDirection::Sent => "shafter = $1",
+        TransactionDirection::Received => "shaftee = $1",
+        TransactionDirection::Both => "(shafter = $1 OR shaftee = $1)",
+    }
+}
+
+/// Read every table for [Database::export_json].
+#[cfg(feature = "serde")]
+fn export_backup(
+    conn: &rusqlite::Connection,
+    include_tokens: bool,
+) -> Result<Backup, DatabaseError> {
+    fn query_all<T>(
+        conn: &rusqlite::Connection,
+        sql: &str,
+        f: impl FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+    ) -> Result<Vec<T>, DatabaseError> {
+        conn.prepare_cached(sql)
+            .and_then(|mut stmt| stmt.query_map(params![], f)?.collect())
+            .context(SqliteError {
+                operation: "export_json",
+            })
+    }
+
+    let users = query_all(
+        conn,
+        "SELECT user_id, COALESCE(display_name, user_id), created_at, display_name_overridden,
+            active, version
+        FROM users ORDER BY user_id",
+        |row| {
+            Ok(BackupUser {
+                user_id: row.get(0)?,
+                display_name: row.get(1)?,
+                created_at: row.get(2)?,
+                display_name_overridden: row.get(3)?,
+                active: row.get(4)?,
+                version: row.get(5)?,
+            })
+        },
+    )?;
+
+    let identities = query_all(
+        conn,
+        "SELECT provider, provider_id, user_id FROM identities ORDER BY provider, provider_id",
+        |row| {
+            Ok(BackupIdentity {
+                provider: row.get(0)?,
+                provider_id: row.get(1)?,
+                user_id: row.get(2)?,
+            })
+        },
+    )?;
+
+    let transactions = query_all(
+        conn,
+        "SELECT id, shafter, shaftee, amount, time_sec, reason, idempotency_key, category,
+            deleted_at, reversed_transaction_id
+        FROM transactions ORDER BY id",
+        |row| {
+            Ok(BackupTransaction {
+                id: row.get(0)?,
+                shafter: row.get(1)?,
+                shaftee: row.get(2)?,
+                amount: row.get(3)?,
+                time_sec: row.get(4)?,
+                reason: row.get(5)?,
+                idempotency_key: row.get(6)?,
+                category: row.get(7)?,
+                deleted_at: row.get(8)?,
+                reversed_transaction_id: row.get(9)?,
+            })
+        },
+    )?;
+
+    let tokens = if include_tokens {
+        query_all(
+            conn,
+            "SELECT user_id, token, scope, created_at, last_used_at, expires_at
+            FROM tokens ORDER BY rowid",
+            |row| {
+                Ok(BackupToken {
+                    user_id: row.get(0)?,
+                    token: row.get(1)?,
+                    scope: row.get(2)?,
+                    created_at: row.get(3)?,
+                    last_used_at: row.get(4)?,
+                    expires_at: row.get(5)?,
+                })
+            },
+        )?
+    } else {
+        Vec::new()
+    };
+
+    let teams = query_all(
+        conn,
+        "SELECT user_id, team FROM user_teams ORDER BY user_id",
+        |row| {
+            Ok(BackupTeam {
+                user_id: row.get(0)?,
+                team: row.get(1)?,
+            })
+        },
+    )?;
+
+    let attachments = query_all(
+        conn,
+        "SELECT id, transaction_id, url, uploaded_at FROM attachments ORDER BY id",
+        |row| {
+            Ok(BackupAttachment {
+                id: row.get(0)?,
+                transaction_id: row.get(1)?,
+                url: row.get(2)?,
+                uploaded_at: row.get(3)?,
+            })
+        },
+    )?;
+
+    Ok(Backup {
+        users,
+        identities,
+        transactions,
+        tokens,
+        teams,
+        attachments,
+    })
+}
+
+/// Insert every row of a backup, for [Database::import_json].
+#[cfg(feature = "serde")]
+fn import_backup(conn: &rusqlite::Connection, backup: &Backup) -> Result<(), DatabaseError> {
+    let context = || SqliteError {
+        operation: "import_json.insert",
+    };
+
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO users
+            (user_id, display_name, created_at, display_name_overridden, active, version)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .context(context())?;
+    for user in &backup.users {
+        stmt.execute(params![
+            user.user_id,
+            user.display_name,
+            user.created_at,
+            user.display_name_overridden,
+            user.active,
+            user.version,
+        ])
+        .context(context())?;
+    }
+
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO identities (provider, provider_id, user_id) VALUES ($1, $2, $3)",
+        )
+        .context(context())?;
+    for identity in &backup.identities {
+        stmt.execute(params![
+            identity.provider,
+            identity.provider_id,
+            identity.user_id
+        ])
+        .context(context())?;
+    }
+
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO transactions
+            (id, shafter, shaftee, amount, time_sec, reason, idempotency_key, category,
+                deleted_at, reversed_transaction_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        )
+        .context(context())?;
+    for transaction in &backup.transactions {
+        stmt.execute(params![
+            transaction.id,
+            transaction.shafter,
+            transaction.shaftee,
+            transaction.amount,
+            transaction.time_sec,
+            transaction.reason,
+            transaction.idempotency_key,
+            transaction.category,
+            transaction.deleted_at,
+            transaction.reversed_transaction_id,
+        ])
+        .context(context())?;
+    }
+
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO tokens (user_id, token, scope, created_at, last_used_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .context(context())?;
+    for token in &backup.tokens {
+        stmt.execute(params![
+            token.user_id,
+            token.token,
+            token.scope,
+            token.created_at,
+            token.last_used_at,
+            token.expires_at,
+        ])
+        .context(context())?;
+    }
+
+    let mut stmt = conn
+        .prepare_cached("INSERT INTO user_teams (user_id, team) VALUES ($1, $2)")
+        .context(context())?;
+    for team in &backup.teams {
+        stmt.execute(params![team.user_id, team.team])
+            .context(context())?;
+    }
+
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO attachments (id, transaction_id, url, uploaded_at)
+            VALUES ($1, $2, $3, $4)",
+        )
+        .context(context())?;
+    for attachment in &backup.attachments {
+        stmt.execute(params![
+            attachment.id,
+            attachment.transaction_id,
+            attachment.url,
+            attachment.uploaded_at,
+        ])
+        .context(context())?;
+    }
+
+    Ok(())
+}
+
+/// Get up to `limit` transactions matching `condition`, in the given order,
+/// with the display names of the users involved. The transactions table is
+/// aliased as `t`.
+fn named_transactions(
+    conn: &rusqlite::Connection,
+    condition: &str,
+    order_by: &str,
+    limit: i64,
+) -> rusqlite::Result<Vec<NamedTransaction>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        r#"SELECT t.id, t.shafter, t.shaftee, t.amount, t.time_sec, t.reason,
+            t.idempotency_key, t.category,
+            COALESCE(shafter_user.display_name, t.shafter),
+            COALESCE(shaftee_user.display_name, t.shaftee)
+        FROM transactions AS t
+        LEFT JOIN users AS shafter_user ON shafter_user.user_id = t.shafter
+        LEFT JOIN users AS shaftee_user ON shaftee_user.user_id = t.shaftee
+        WHERE t.deleted_at IS NULL AND {}
+        ORDER BY {}
+        LIMIT $1
+        "#,
+        condition, order_by,
+    ))?;
+
+    let rows = stmt.query_map(params![limit], |row| {
+        Ok(NamedTransaction {
+            transaction: Transaction {
+                id: row.get(0)?,
+                shafter: row.get(1)?,
+                shaftee: row.get(2)?,
+                amount: row.get(3)?,
+                datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                reason: row.get(5)?,
+                idempotency_key: row.get(6)?,
+                category: row.get(7)?,
+            },
+            shafter_display_name: row.get(8)?,
+            shaftee_display_name: row.get(9)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+/// Read a [User] from the `user_id, display_name, balance, created_at,
+/// version` columns starting at index `first`.
+fn user_from_row(row: &rusqlite::Row<'_>, first: usize) -> rusqlite::Result<User> {
+    Ok(User::from(row.get::<_, String>(first)?)
+        .with_display_name(row.get::<_, String>(first + 1)?)
+        .with_balance(row.get(first + 2)?)
+        .with_created_at(chrono::Utc.timestamp(row.get(first + 3)?, 0))
+        .with_version(row.get(first + 4)?))
+}
+
+/// Get the users matching `filter` (c.f. [active_filter]) with their cached
+/// balances, or `None` if any of their balances aren't cached.
+fn cached_users(
+    conn: &rusqlite::Connection,
+    cache: &BalanceCache,
+    filter: &str,
+) -> Result<Option<Vec<User>>, DatabaseError> {
+    let mut stmt = conn
+        .prepare_cached(&format!(
+            "SELECT user_id, display_name, COALESCE(created_at, 0), version FROM users WHERE {}",
+            filter
+        ))
+        .context(SqliteError {
+            operation: "cached_users",
+        })?;
+
+    let rows: Result<Vec<(String, String, i64, i32)>, _> = stmt
+        .query_map(params![], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .context(SqliteError {
+            operation: "cached_users",
+        })?
+        .collect();
+
+    let users = rows
+        .context(SqliteError {
+            operation: "cached_users",
+        })?
+        .into_iter()
+        .map(|(user_id, display_name, created_at, version)| {
+            cache.get(&user_id).map(|balance| {
+                User::from(user_id)
+                    .with_display_name(display_name)
+                    .with_balance(balance)
+                    .with_created_at(chrono::Utc.timestamp(created_at, 0))
+                    .with_version(version)
+            })
+        })
+        .collect();
+
+    Ok(users)
+}
+
+/// A `WHERE` condition on `users` that, unless `include_inactive` is set,
+/// filters out deactivated users.
+fn active_filter(include_inactive: bool) -> &'static str {
+    if include_inactive {
+        "1"
+    } else {
+        "active"
+    }
+}
+
+/// Hash a token for storage, so that a leaked database doesn't leak usable
+/// sessions. Tokens are long and random so a plain SHA-256 is sufficient.
+fn hash_token(token: &str) -> String {
+    sha256(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Generate a comma separated list of `count` numbered parameters, starting at
+/// `$start`, for use in e.g. `IN (...)` clauses.
+fn placeholders(start: usize, count: usize) -> String {
+    (start..start + count).map(|i| format!("${}", i)).join(", ")
+}
+
+/// Check that no user other than `user_id` has the given display name,
+/// ignoring ASCII case, erroring with [DatabaseError::Conflict] otherwise.
+fn check_display_name_unique(
+    conn: &rusqlite::Connection,
+    user_id: &str,
+    display_name: &str,
+) -> Result<(), DatabaseError> {
+    let taken: bool = conn
+        .prepare_cached(
+            "SELECT EXISTS(
+                SELECT 1 FROM users WHERE display_name = $1 COLLATE NOCASE AND user_id != $2
+            )",
+        )
+        .and_then(|mut stmt| stmt.query_row(params![display_name, user_id], |row| row.get(0)))
+        .context(SqliteError {
+            operation: "check_display_name_unique",
+        })?;
+
+    if taken {
+        return Err(DatabaseError::Conflict {
+            constraint: "users.display_name".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Get the net balance between two users, see
+/// [Database::get_balance_between].
+fn balance_between(
+    conn: &rusqlite::Connection,
+    user_a: &str,
+    user_b: &str,
+) -> Result<i64, DatabaseError> {
+    for user_id in &[user_a, user_b] {
+        let exists: bool = conn
+            .prepare_cached("SELECT EXISTS(SELECT 1 FROM users WHERE user_id = $1)")
+            .and_then(|mut stmt| stmt.query_row(&[user_id], |row| row.get(0)))
+            .context(SqliteError {
+                operation: "balance_between.check_user",
+            })?;
+
+        if !exists {
+            return Err(DatabaseError::UnknownUser {
+                user_id: user_id.to_string(),
+            });
+        }
+    }
+
+    conn.prepare_cached(
+        r#"SELECT COALESCE(SUM(
+            CASE WHEN shafter = $1 THEN amount ELSE -amount END
+        ), 0)
+        FROM transactions
+        WHERE deleted_at IS NULL AND (
+            (shafter = $1 AND shaftee = $2) OR (shafter = $2 AND shaftee = $1)
+        )"#,
+    )
+    .and_then(|mut stmt| stmt.query_row(&[user_a, user_b], |row| row.get(0)))
+    .context(SqliteError {
+        operation: "balance_between.sum",
+    })
+}
+
+/// Get the sum of all positive balances, see
+/// [Database::get_total_outstanding].
+fn total_outstanding(conn: &rusqlite::Connection) -> Result<i64, DatabaseError> {
+    conn.prepare_cached(&format!(
+        r#"
+        SELECT COALESCE(SUM(balance), 0)
+        FROM users
+        JOIN ({}) USING (user_id)
+        WHERE balance > 0
+        "#,
+        BALANCES_SQL
+    ))
+    .and_then(|mut stmt| stmt.query_row(params![], |row| row.get(0)))
+    .context(SqliteError {
+        operation: "total_outstanding",
+    })
+}
+
+/// Check that `shafter` hasn't already made the most shafts allowed in the
+/// trailing window, erroring with [DatabaseError::RateLimited] otherwise.
+fn check_rate_limit(
+    conn: &rusqlite::Connection,
+    shafter: &str,
+    rate_limit: ShaftRateLimit,
+) -> Result<(), DatabaseError> {
+    let window = rate_limit.window.as_secs() as i64;
+    let now = chrono::Utc::now().timestamp();
+
+    if rate_limit.max_shafts_per_window == 0 {
+        return Err(DatabaseError::RateLimited {
+            retry_after_secs: window,
+        });
+    }
+
+    // If the shafter has hit the limit then this is the time of the oldest
+    // shaft that counts towards it, i.e. the next one to leave the window.
+    let oldest: Option<i64> = conn
+        .prepare_cached(
+            "SELECT time_sec FROM transactions
+            WHERE shafter = $1 AND time_sec >= $2
+            ORDER BY time_sec DESC
+            LIMIT 1 OFFSET $3",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_row(
+                params![
+                    shafter,
+                    now - window,
+                    i64::from(rate_limit.max_shafts_per_window - 1)
+                ],
+                |row| row.get(0),
+            )
+        })
+        .map(Some)
+        .or_else(|err| {
+            if let rusqlite::Error::QueryReturnedNoRows = err {
+                Ok(None)
+            } else {
+                Err(err)
+            }
+        })
+        .context(SqliteError {
+            operation: "check_rate_limit",
+        })?;
+
+    match oldest {
+        Some(oldest) => Err(DatabaseError::RateLimited {
+            retry_after_secs: (oldest + window - now).max(1),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Get all users who haven't been part of a transaction since `cutoff`, in ID
+/// order. If `count_registration` is set then users with no transactions
+/// count as active from when they registered, otherwise they're always
+/// included.
+fn inactive_users(
+    conn: &rusqlite::Connection,
+    cutoff: chrono::DateTime<chrono::Utc>,
+    count_registration: bool,
+) -> Result<Vec<User>, DatabaseError> {
+    let mut stmt = conn
+        .prepare_cached(&format!(
+            r#"
+        SELECT user_id, display_name, COALESCE(balance, 0),
+            COALESCE(created_at, 0), version
+        FROM users
+        LEFT JOIN ({}) USING (user_id)
+        LEFT JOIN (
+            SELECT user_id, MAX(time_sec) AS last_active
+            FROM (
+                SELECT shafter AS user_id, time_sec FROM transactions
+                WHERE deleted_at IS NULL
+                UNION ALL
+                SELECT shaftee AS user_id, time_sec FROM transactions
+                WHERE deleted_at IS NULL
+            ) t GROUP BY user_id
+        ) USING (user_id)
+        WHERE CASE
+            WHEN last_active IS NOT NULL THEN last_active < $1
+            WHEN $2 THEN COALESCE(created_at, 0) < $1
+            ELSE 1
+        END
+        ORDER BY user_id
+        "#,
+            BALANCES_SQL
+        ))
+        .context(SqliteError {
+            operation: "inactive_users",
+        })?;
+
+    let rows: Result<Vec<User>, _> = stmt
+        .query_map(params![cutoff.timestamp(), count_registration], |row| {
+            user_from_row(row, 0)
+        })
+        .context(SqliteError {
+            operation: "inactive_users",
+        })?
+        .collect();
+
+    rows.context(SqliteError {
+        operation: "inactive_users",
+    })
+}
+
+/// Get a user's current balance, which is 0 for unknown users.
+fn balance_of(conn: &rusqlite::Connection, user_id: &str) -> Result<i64, DatabaseError> {
+    let balance = conn
+        .prepare_cached(
+            r#"SELECT (
+            SELECT COALESCE(SUM(amount), 0)
+            FROM transactions
+            WHERE shafter = $1 AND deleted_at IS NULL
+        ) - (
+            SELECT COALESCE(SUM(amount), 0)
+            FROM transactions
+            WHERE shaftee = $1 AND deleted_at IS NULL
+        )"#,
+        )
+        .and_then(|mut stmt| stmt.query_row(&[user_id], |row| row.get(0)));
+
+    match balance {
+        Err(ref err) if is_integer_overflow(err) => Err(DatabaseError::BalanceOverflow {
+            user_id: user_id.to_string(),
+        }),
+        balance => balance.context(SqliteError {
+            operation: "balance_of",
+        }),
+    }
+}
+
+/// Work out the balances committing the transaction would leave its parties
+/// with, checking that the shaftee exists and, unless it's a resubmission,
+/// that it doesn't breach `max_debt`.
+fn preview_shaft(
+    conn: &rusqlite::Connection,
+    transaction: &Transaction,
+    max_debt: Option<i64>,
+) -> Result<ShaftPreview, DatabaseError> {
+    check_shaftee(conn, &transaction.shaftee)?;
+
+    let resubmitted: bool = conn
+        .prepare_cached("SELECT EXISTS(SELECT 1 FROM transactions WHERE idempotency_key = $1)")
+        .and_then(|mut stmt| stmt.query_row(&[&transaction.idempotency_key], |row| row.get(0)))
+        .context(SqliteError {
+            operation: "preview_shaft.check_idempotency_key",
+        })?;
+    let amount = if resubmitted { 0 } else { transaction.amount };
+
+    let shafter_balance = balance_of(conn, &transaction.shafter)?;
+    let shaftee_balance = balance_of(conn, &transaction.shaftee)?;
+
+    let preview = project_shaft(transaction, shafter_balance, shaftee_balance, amount)?;
+    if !resubmitted {
+        check_debt_limit(transaction, preview.shaftee_balance_after, max_debt)?;
+    }
+
+    Ok(preview)
+}
+
+/// Check a new shaft against the rate limit, debt limit and for overflow,
+/// c.f. [Database::shaft_user], returning the balances it would leave. This
+/// must run in the same SQL transaction as the insert, and after any earlier
+/// shafts in the same batch are inserted so that they count too.
+fn check_shaft_limits(
+    conn: &rusqlite::Connection,
+    transaction: &Transaction,
+    rate_limit: Option<ShaftRateLimit>,
+    max_debt: Option<i64>,
+) -> Result<ShaftPreview, DatabaseError> {
+    if let Some(rate_limit) = rate_limit {
+        check_rate_limit(conn, &transaction.shafter, rate_limit)?;
+    }
+
+    preview_shaft(conn, transaction, max_debt)
+}
+
+/// Check that the shaftee of a new transaction exists.
+fn check_shaftee(conn: &rusqlite::Connection, shaftee: &str) -> Result<(), DatabaseError> {
+    let exists = conn
+        .prepare_cached("SELECT user_id FROM users WHERE user_id = $1")
+        .and_then(|mut stmt| stmt.query_row(&[shaftee], |_row| Ok(())));
+
+    match exists {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(DatabaseError::UnknownUser {
+            user_id: shaftee.to_string(),
+        }),
+        Err(err) => Err(err).context(SqliteError {
+            operation: "insert_transaction.check_shaftee",
+        }),
+    }
+}
+
+/// Insert a new transaction, checking that the shaftee exists. Returns the new
+/// transaction's ID, or `None` if it was a resubmission and so skipped.
+fn insert_transaction(
+    conn: &rusqlite::Connection,
+    transaction: Transaction,
+) -> Result<Option<i64>, DatabaseError> {
+    check_shaftee(conn, &transaction.shaftee)?;
+
+    // If a transaction with the same idempotency key already exists then this
+    // is a resubmission, so we silently skip it. NULL keys never conflict.
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO transactions
+         (shafter, shaftee, amount, time_sec, reason, idempotency_key, category)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         ON CONFLICT (idempotency_key) DO NOTHING",
+        )
+        .context(SqliteError {
+            operation: "insert_transaction.insert",
+        })?;
+
+    let inserted = stmt
+        .execute(params![
+            &transaction.shafter,
+            &transaction.shaftee,
+            &transaction.amount,
+            &transaction.datetime.timestamp(),
+            &transaction.reason,
+            &transaction.idempotency_key,
+            &transaction.category,
+        ])
+        .context(SqliteError {
+            operation: "insert_transaction.insert",
+        })?;
+
+    if inserted == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(conn.last_insert_rowid()))
+}
+
+/// The number of rows inserted per statement by
+/// [import_transactions](Database::import_transactions). Each row binds seven
+/// parameters, which keeps us below SQLite's default limit of 999.
+const IMPORT_BATCH_SIZE: usize = 100;
+
+/// Insert the given transactions with a single multi-row `INSERT`, returning
+/// the number inserted. Like [insert_transaction], duplicate idempotency keys
+/// are skipped, but users aren't checked.
+fn import_batch(
+    conn: &rusqlite::Connection,
+    transactions: &[Transaction],
+) -> Result<u64, DatabaseError> {
+    if transactions.is_empty() {
+        return Ok(0);
+    }
+
+    let query = format!(
+        "INSERT INTO transactions
+         (shafter, shaftee, amount, time_sec, reason, idempotency_key, category)
+         VALUES {}
+         ON CONFLICT (idempotency_key) DO NOTHING",
+        vec!["(?, ?, ?, ?, ?, ?, ?)"; transactions.len()].join(", ")
+    );
+
+    let times: Vec<i64> = transactions
+        .iter()
+        .map(|transaction| transaction.datetime.timestamp())
+        .collect();
+
+    let mut values: Vec<&dyn ToSql> = Vec::with_capacity(transactions.len() * 7);
+    for (transaction, time) in transactions.iter().zip(&times) {
+        values.push(&transaction.shafter);
+        values.push(&transaction.shaftee);
+        values.push(&transaction.amount);
+        values.push(time);
+        values.push(&transaction.reason);
+        values.push(&transaction.idempotency_key);
+        values.push(&transaction.category);
+    }
+
+    let inserted = conn
+        .prepare_cached(&query)
+        .and_then(|mut stmt| stmt.execute(&values))
+        .context(SqliteError {
+            operation: "import_transactions.insert",
+        })?;
+
+    Ok(inserted as u64)
+}
+
+/// Whether a query failed because an integer overflowed. SQLite errors if a
+/// `SUM` overflows, but silently switches to floating point if plain
+/// arithmetic does, so we also treat getting a float back as overflow.
+fn is_integer_overflow(err: &rusqlite::Error) -> bool {
+    match err {
+        rusqlite::Error::SqliteFailure(_, Some(message)) => message == "integer overflow",
+        rusqlite::Error::InvalidColumnType(_, _, rusqlite::types::Type::Real) => true,
+        _ => false,
     }
 }
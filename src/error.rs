@@ -1,4 +1,5 @@
 use actix_web::error::ResponseError;
+use actix_web::http::StatusCode;
 use snafu::{Backtrace, Snafu};
 
 use crate::{db, github};
@@ -19,4 +20,22 @@ pub enum ShaftError {
     },
 }
 
-impl ResponseError for ShaftError {}
+impl ResponseError for ShaftError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ShaftError::DatabaseError {
+                source: db::DatabaseError::Conflict { .. },
+                ..
+            } => StatusCode::CONFLICT,
+            ShaftError::DatabaseError {
+                source: db::DatabaseError::PoolTimeout { .. },
+                ..
+            } => StatusCode::SERVICE_UNAVAILABLE,
+            ShaftError::DatabaseError {
+                source: db::DatabaseError::RateLimited { .. },
+                ..
+            } => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -1,13 +1,21 @@
-#[macro_use]
+// Only the web server logs.
+#[cfg_attr(feature = "serde", macro_use)]
 extern crate slog;
 
+#[cfg(feature = "serde")]
 use hyper_tls::HttpsConnector;
 
 /// Short hand for our HTTPS enabled outbound HTTP client.
+#[cfg(feature = "serde")]
 type HttpClient = hyper::Client<HttpsConnector<hyper::client::HttpConnector>>;
 
 pub mod db;
+// Everything other than the database talks JSON or reads config with serde.
+#[cfg(feature = "serde")]
 pub mod error;
+#[cfg(feature = "serde")]
 pub mod github;
+#[cfg(feature = "serde")]
 pub mod rest;
+#[cfg(feature = "serde")]
 pub mod settings;
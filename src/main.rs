@@ -12,7 +12,7 @@ use std::fs::File;
 use std::io::Read;
 use std::process::exit;
 
-use shaft::db::SqliteDatabase;
+use shaft::db::{Database, SqliteDatabase};
 use shaft::rest::{
     format_pence_as_pounds_helper, register_servlets, AppConfig, AppState, AuthenticateUser,
     MiddlewareLogger,
@@ -81,7 +81,24 @@ fn main() {
     hb.register_helper("pence-as-pounds", Box::new(format_pence_as_pounds_helper));
 
     // Set up the database
-    let database = SqliteDatabase::with_path(settings.database_file);
+    let database = match SqliteDatabase::with_path(&settings.database_file) {
+        Ok(database) => database,
+        Err(err) => {
+            crit!(logger, "Failed to open database: {}", err);
+            exit(1);
+        }
+    };
+
+    // Create or upgrade the schema before anything uses it.
+    if let Err(err) = futures::executor::block_on(database.migrate()) {
+        crit!(logger, "Failed to migrate database: {}", err);
+        exit(1);
+    }
+
+    // Open connections up front so the first requests don't have to.
+    if let Err(err) = futures::executor::block_on(database.warm_pool()) {
+        warn!(logger, "Failed to warm up database pool: {}", err);
+    }
 
     // Sanitize the webroot to not end in a trailing slash.
     let web_root = settings.web_root.trim_end_matches('/').to_string();
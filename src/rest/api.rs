@@ -18,6 +18,7 @@ pub fn register_servlets(config: &mut ServiceConfig) {
     config.route("/api/balances", web::get().to(get_api_balances));
     config.route("/api/transactions", web::get().to(get_api_transactions));
     config.route("/api/shaft", web::post().to(shaft_user));
+    config.route("/api/currency", web::get().to(get_api_currency));
 }
 
 /// Get all user's balances as a map from user ID to [User](crate::db::User)
@@ -27,21 +28,63 @@ async fn get_api_balances(
 ) -> Result<Json<impl Serialize>, Error> {
     state
         .database
-        .get_all_users()
+        .get_all_users(false)
         .await
         .map_err(ErrorInternalServerError)
         .map(Json)
 }
 
+/// A transaction as returned by `/api/transactions`. This predates
+/// [db::Transaction]'s RFC 3339 `datetime`, so keeps giving it as a unix
+/// timestamp for existing clients.
+#[derive(Serialize)]
+struct ApiTransaction {
+    id: Option<i64>,
+    shafter: String,
+    shaftee: String,
+    amount: i64,
+    datetime: i64,
+    reason: Option<String>,
+    idempotency_key: Option<String>,
+    category: Option<String>,
+}
+
+impl From<db::Transaction> for ApiTransaction {
+    fn from(txn: db::Transaction) -> ApiTransaction {
+        ApiTransaction {
+            id: txn.id,
+            shafter: txn.shafter,
+            shaftee: txn.shaftee,
+            amount: txn.amount,
+            datetime: txn.datetime.timestamp(),
+            reason: txn.reason,
+            idempotency_key: txn.idempotency_key,
+            category: txn.category,
+        }
+    }
+}
+
 /// Get most recent transactions
 async fn get_api_transactions(
     (state, _user): (web::Data<AppState>, AuthenticatedUser),
-) -> Result<Json<Vec<db::Transaction>>, Error> {
+) -> Result<Json<Vec<ApiTransaction>>, Error> {
     state
         .database
         .get_last_transactions(20)
         .await
         .map_err(ErrorInternalServerError)
+        .map(|transactions| Json(transactions.into_iter().map(ApiTransaction::from).collect()))
+}
+
+/// Get the [Currency](crate::db::Currency) to render amounts in.
+async fn get_api_currency(
+    (state, _user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<Json<db::Currency>, Error> {
+    state
+        .database
+        .get_currency()
+        .await
+        .map_err(ErrorInternalServerError)
         .map(Json)
 }
 
@@ -66,16 +109,21 @@ async fn shaft_user(
         other_user,
         amount,
         reason,
+        idempotency_key,
+        category,
     } = body.0;
 
     state
         .database
         .shaft_user(db::Transaction {
+            id: None,
             shafter: user.user_id.clone(),
             shaftee: other_user.clone(),
             amount,
             datetime: chrono::Utc::now(),
-            reason,
+            reason: reason.filter(|reason| !reason.trim().is_empty()),
+            idempotency_key,
+            category,
         })
         .await
         .context(DatabaseError)?;
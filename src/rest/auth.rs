@@ -15,7 +15,7 @@ use std::rc::Rc;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use crate::db::Database;
+use crate::db::{Database, Token, TokenScope};
 use crate::rest::AppState;
 
 /// Middleware for annotating requests with valid user authentication.
@@ -66,6 +66,8 @@ pub struct AuthenticateUserService<S> {
 pub struct AuthenticatedUser {
     pub user_id: String,
     pub display_name: String,
+    /// What the session's token is allowed to do.
+    pub scope: TokenScope,
 }
 
 impl<S, B> Service for AuthenticateUserService<S>
@@ -88,30 +90,42 @@ where
         let service = self.service.clone();
 
         let token = if let Some(token) = req.cookie("token") {
-            token.value().to_string()
+            Token::from(token.value())
         } else {
             return service.borrow_mut().call(req).boxed_local();
         };
 
         async move {
             let user_opt = db
-                .get_user_from_token(token)
+                .get_user_from_token(token.clone())
                 .await
                 .map_err(error::ErrorInternalServerError)?;
 
-            if let Some(user) = user_opt {
+            if let Some((user, scope)) = user_opt {
                 let logger = req
                     .extensions()
                     .get::<Logger>()
                     .expect("logger no longer installed in request")
                     .clone();
-                let logger = logger.new(o!("user_id" => user.user_id.clone()));
+                let logger = logger.new(o!("user_id" => user.user_id().to_string()));
                 info!(logger, "Authenticated user");
+
+                // Update the token's last use in the background, so that
+                // authenticating stays a pure read.
+                let touch = db.touch_token(token);
+                let touch_logger = logger.clone();
+                actix_rt::spawn(async move {
+                    if let Err(err) = touch.await {
+                        warn!(touch_logger, "Failed to touch token: {}", err);
+                    }
+                });
+
                 req.extensions_mut().insert(logger);
 
                 req.extensions_mut().insert(AuthenticatedUser {
-                    user_id: user.user_id,
-                    display_name: user.display_name,
+                    user_id: user.user_id().to_string(),
+                    display_name: user.display_name().to_string(),
+                    scope,
                 });
             }
 
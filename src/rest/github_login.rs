@@ -9,6 +9,7 @@ use url::Url;
 
 use std::sync::Arc;
 
+use crate::db::GithubId;
 use crate::github::{GenericHttpClient, GithubApi};
 use crate::rest::{get_expires_string, AppState};
 
@@ -70,16 +71,25 @@ async fn github_callback(
         .await
         .map_err(error::ErrorInternalServerError)?;
 
-    let github_user_id = user.login.clone();
-    let github_name = user.name.clone();
+    let github_user_id = GithubId(user.login);
+    let github_name = user.name;
 
     let user_id_opt = state
         .database
-        .get_user_by_github_id(user.login)
+        .get_user_by_github_id(github_user_id.clone())
         .map_err(error::ErrorInternalServerError)
         .await?;
 
     let user_id = if let Some(user_id) = user_id_opt {
+        // Keep their display name up to date with their Github profile.
+        if let Some(github_name) = github_name {
+            state
+                .database
+                .sync_display_name_from_github(github_user_id, github_name)
+                .map_err(error::ErrorInternalServerError)
+                .await?;
+        }
+
         user_id
     } else {
         let opt = gh_api
@@ -92,7 +102,7 @@ async fn github_callback(
                 .database
                 .add_user_by_github_id(
                     github_user_id.clone(),
-                    github_name.unwrap_or(github_user_id),
+                    github_name.unwrap_or(github_user_id.0),
                 )
                 .map_err(error::ErrorInternalServerError)
                 .await?
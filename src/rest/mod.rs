@@ -139,9 +139,19 @@ pub fn format_pence_as_pounds_helper(
 struct ShaftUserBody {
     /// The other party in the transaction.
     other_user: String,
-    /// The amount in pence owed. Positive means shafter is owed money by other
-    /// user, negative means shafer owes money.
+    /// The amount in pence that the shafter is owed by the other user. Must be
+    /// positive.
     amount: i64,
-    /// The human readable description of the transasction.
-    reason: String,
+    /// The human readable description of the transasction, if any. An empty
+    /// reason, e.g. from a blank form field, counts as none.
+    #[serde(default)]
+    reason: Option<String>,
+    /// Optional key identifying this submission, so that resubmitting it is
+    /// a no-op, c.f.
+    /// [Transaction::idempotency_key](crate::db::Transaction::idempotency_key).
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    /// Optional category to file the transaction under.
+    #[serde(default)]
+    category: Option<String>,
 }
@@ -30,7 +30,7 @@ async fn root((req, state): (HttpRequest, web::Data<AppState>)) -> Result<HttpRe
     if let Some(token) = req.cookie("token") {
         let user_opt = state
             .database
-            .get_user_from_token(token.value().to_string())
+            .get_user_from_token(db::Token::from(token.value()))
             .await
             .map_err(error::ErrorInternalServerError)?;
         if user_opt.is_some() {
@@ -50,12 +50,12 @@ async fn get_balances(
     let hb = state.handlebars.clone();
     let all_users = state
         .database
-        .get_all_users()
+        .get_all_users(false)
         .await
         .map_err(error::ErrorInternalServerError)?;
 
     let mut vec = all_users.values().collect_vec();
-    vec.sort_by_key(|e| e.balance);
+    vec.sort_by_key(|e| e.balance());
 
     let s = hb
         .render(
@@ -81,7 +81,7 @@ async fn get_transactions(
 ) -> Result<HttpResponse, Error> {
     let all_users = state
         .database
-        .get_all_users()
+        .get_all_users(true)
         .await
         .map_err(error::ErrorInternalServerError)?;
 
@@ -103,11 +103,11 @@ async fn get_transactions(
                         "amount": txn.amount,
                         "shafter_id": txn.shafter,
                         "shafter_name": all_users.get(&txn.shafter)
-                            .map(|u| &u.display_name as &str)
+                            .map(|u| u.display_name())
                             .unwrap_or(&txn.shafter),
                         "shaftee_id": txn.shaftee,
                         "shaftee_name": all_users.get(&txn.shaftee)
-                            .map(|u| &u.display_name as &str)
+                            .map(|u| u.display_name())
                             .unwrap_or(&txn.shaftee),
                         "date": format!("{}", txn.datetime.format("%d %b %Y")),
                         "reason": txn.reason,
@@ -142,16 +142,21 @@ async fn shaft_user(
         other_user,
         amount,
         reason,
+        idempotency_key,
+        category,
     } = body.0;
 
     state
         .database
         .shaft_user(db::Transaction {
+            id: None,
             shafter: user.user_id.clone(),
             shaftee: other_user.clone(),
             amount,
             datetime: chrono::Utc::now(),
-            reason,
+            reason: reason.filter(|reason| !reason.trim().is_empty()),
+            idempotency_key,
+            category,
         })
         .await
         .map_err(error::ErrorInternalServerError)?;
@@ -202,7 +207,7 @@ async fn logout((req, state): (HttpRequest, web::Data<AppState>)) -> Result<Http
     info!(logger, "Got logout request");
 
     if let Some(token) = req.cookie("token") {
-        db.delete_token(token.value().to_string())
+        db.delete_token(db::Token::from(token.value()))
             .await
             .map_err(error::ErrorInternalServerError)?;
     }
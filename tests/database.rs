@@ -0,0 +1,3452 @@
+use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::executor::block_on;
+use futures::stream::TryStreamExt;
+use futures_cpupool::CpuPool;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use shaft::db::{
+    Currency, Database, DatabaseError, DatabaseMetrics, PoolConfig, ShaftRateLimit, SortOrder,
+    SqliteDatabase, TokenScope, Transaction, TransactionDirection, User, UserSort, GITHUB_PROVIDER,
+    MAX_AMOUNT, MAX_ATTACHMENTS_PER_TRANSACTION,
+};
+
+/// A database backed by a temporary file, which is deleted on drop.
+///
+/// We can't use `:memory:` here as each pooled connection would get its own
+/// empty database.
+struct TestDatabase {
+    database: SqliteDatabase,
+    path: PathBuf,
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn setup_db() -> TestDatabase {
+    setup_db_with_config(PoolConfig::default())
+}
+
+fn setup_db_with_config(config: PoolConfig) -> TestDatabase {
+    let suffix: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+    let path = std::env::temp_dir().join(format!("shaft-test-{}.db", suffix));
+
+    let database = SqliteDatabase::with_config(&path, config).unwrap();
+    block_on(database.migrate()).unwrap();
+
+    TestDatabase { database, path }
+}
+
+/// Register the given users, with display names matching their IDs.
+fn add_users(db: &SqliteDatabase, user_ids: &[&str]) {
+    for user_id in user_ids {
+        block_on(db.add_user_by_github_id((*user_id).into(), user_id.to_string())).unwrap();
+    }
+}
+
+fn transaction(shafter: &str, shaftee: &str, amount: i64) -> Transaction {
+    Transaction {
+        id: None,
+        shafter: shafter.to_string(),
+        shaftee: shaftee.to_string(),
+        amount,
+        datetime: Utc::now(),
+        reason: Some("test".to_string()),
+        idempotency_key: None,
+        category: None,
+    }
+}
+
+fn shaft(db: &SqliteDatabase, shafter: &str, shaftee: &str, amount: i64) {
+    block_on(db.shaft_user(transaction(shafter, shaftee, amount))).unwrap();
+}
+
+#[test]
+fn test_shaft_counts() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "carol", "bob", 5);
+    shaft(db, "bob", "alice", 1000);
+
+    let counts = block_on(db.get_shaft_counts(false)).unwrap();
+    let counts: Vec<_> = counts.into_iter().collect();
+
+    assert_eq!(
+        counts,
+        vec![("bob".to_string(), 2), ("alice".to_string(), 1)]
+    );
+}
+
+#[test]
+fn test_purge_user_data() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+    block_on(db.add_attachment(1, "https://example.com/alice.jpg".to_string())).unwrap();
+
+    block_on(db.purge_user_data("alice".into())).unwrap();
+
+    // The transaction's attachments are gone along with its reason.
+    assert!(block_on(db.get_attachments(1)).unwrap().is_empty());
+
+    // Bob's balance is unaffected.
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -100
+    );
+
+    // Alice's token and github link are gone.
+    assert!(block_on(db.get_user_from_token(token)).unwrap().is_none());
+    assert!(block_on(db.get_user_by_github_id("alice".into()))
+        .unwrap()
+        .is_none());
+
+    let users = block_on(db.get_all_users(false)).unwrap();
+    assert!(users.get("alice").is_none());
+    assert!(users.values().any(|u| u.display_name() == "Deleted user"));
+}
+
+#[test]
+fn test_purge_user_data_team() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    db.run_statements("INSERT INTO user_teams (user_id, team) VALUES ('alice', 'red')")
+        .unwrap();
+    shaft(db, "alice", "bob", 100);
+
+    block_on(db.purge_user_data("alice".into())).unwrap();
+
+    // The team keeps the purged user's balance, but not their ID.
+    let balances: Vec<_> = block_on(db.get_team_balances())
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(balances, vec![("red".to_string(), 100)]);
+
+    let member: String = rusqlite::Connection::open(&test_db.path)
+        .unwrap()
+        .query_row(
+            "SELECT user_id FROM user_teams",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(member.starts_with("deleted-"), "{}", member);
+}
+
+#[test]
+fn test_shaft_rejects_future_timestamp() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let transaction = Transaction {
+        id: None,
+        shafter: "alice".to_string(),
+        shaftee: "bob".to_string(),
+        amount: 100,
+        datetime: Utc::now() + chrono::Duration::hours(1),
+        reason: Some("test".to_string()),
+        idempotency_key: None,
+        category: None,
+    };
+
+    match block_on(db.shaft_user(transaction.clone())) {
+        Err(DatabaseError::TimestampInFuture { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Admins can still record it.
+    block_on(db.record_historical_transaction(transaction)).unwrap();
+}
+
+#[test]
+fn test_transaction_feed() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    for amount in 1..=5 {
+        shaft(db, "alice", "bob", amount);
+    }
+
+    let page = block_on(db.get_transaction_feed(None, 3, true)).unwrap();
+    let amounts: Vec<_> = page.items.iter().map(|txn| txn.amount).collect();
+    assert_eq!(amounts, vec![5, 4, 3]);
+    assert_eq!(page.total, Some(5));
+    assert!(page.next_cursor.is_some());
+
+    let page = block_on(db.get_transaction_feed(page.next_cursor, 3, false)).unwrap();
+    let amounts: Vec<_> = page.items.iter().map(|txn| txn.amount).collect();
+    assert_eq!(amounts, vec![2, 1]);
+    assert_eq!(page.total, None);
+    assert_eq!(page.next_cursor, None);
+}
+
+#[test]
+fn test_token_scope() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+    let (_, scope) = block_on(db.get_user_from_token(token)).unwrap().unwrap();
+    assert_eq!(scope, TokenScope::Write);
+
+    let token =
+        block_on(db.create_token_for_user_with_scope("alice".into(), TokenScope::Read)).unwrap();
+    let (user, scope) = block_on(db.get_user_from_token(token)).unwrap().unwrap();
+    assert_eq!(user.user_id(), "alice");
+    assert_eq!(scope, TokenScope::Read);
+}
+
+#[test]
+fn test_resolve_token() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+    let user_id = block_on(db.resolve_token(token.clone())).unwrap().unwrap();
+    assert_eq!(user_id.as_str(), "alice");
+
+    block_on(db.delete_token(token.clone())).unwrap();
+    assert!(block_on(db.resolve_token(token)).unwrap().is_none());
+
+    let expired = db
+        .clone()
+        .with_token_lifetime(chrono::Duration::seconds(-1));
+    let token = block_on(expired.create_token_for_user("alice".into())).unwrap();
+    assert!(block_on(db.resolve_token(token)).unwrap().is_none());
+}
+
+#[test]
+fn test_rotate_token() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    let old =
+        block_on(db.create_token_for_user_with_scope("alice".into(), TokenScope::Read)).unwrap();
+    let new = block_on(db.rotate_token(old.clone())).unwrap();
+    assert_ne!(old, new);
+
+    assert!(block_on(db.resolve_token(old.clone())).unwrap().is_none());
+    let user_id = block_on(db.resolve_token(new)).unwrap().unwrap();
+    assert_eq!(user_id.as_str(), "alice");
+
+    let tokens = block_on(db.list_tokens_for_user("alice".into())).unwrap();
+    assert_eq!(tokens.len(), 1);
+
+    match block_on(db.rotate_token(old)) {
+        Err(DatabaseError::InvalidToken) => {}
+        res => panic!("expected InvalidToken, got {:?}", res),
+    }
+}
+
+#[test]
+fn test_balance_cache() {
+    let test_db = setup_db();
+    let db = &test_db
+        .database
+        .clone()
+        .with_balance_cache(chrono::Duration::hours(1));
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    // Populate the cache, then check each write invalidates it.
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        0
+    );
+    block_on(db.get_all_users(true)).unwrap();
+
+    shaft(db, "alice", "bob", 10);
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        10
+    );
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -10
+    );
+
+    let id = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+    let reversal_id = block_on(db.reverse_transaction(id, "oops".into())).unwrap();
+    let users = block_on(db.get_all_users(true)).unwrap();
+    assert_eq!(users["alice"].balance(), 0);
+    assert_eq!(users["bob"].balance(), 0);
+
+    block_on(db.delete_transaction(reversal_id)).unwrap();
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -10
+    );
+
+    shaft(db, "carol", "alice", 5);
+    let users = block_on(db.get_all_users_sorted(UserSort::BalanceDesc, true)).unwrap();
+    let balances: Vec<_> = users
+        .iter()
+        .map(|user| (user.user_id(), user.balance()))
+        .collect();
+    assert_eq!(balances, vec![("alice", 5), ("carol", 5), ("bob", -10)]);
+
+    // Writes through other databases aren't seen until the cache expires.
+    shaft(&test_db.database, "bob", "carol", 10);
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -10
+    );
+    assert_eq!(
+        block_on(test_db.database.get_balance_for_user("bob".into())).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_read_replica() {
+    let primary = setup_db();
+    let replica = setup_db();
+
+    add_users(&primary.database, &["alice", "bob"]);
+    add_users(&replica.database, &["alice", "bob"]);
+
+    let db = &primary
+        .database
+        .clone()
+        .with_read_replica(&replica.path, PoolConfig::default())
+        .unwrap();
+
+    // Writes go to the primary, while reads that may use the replica only
+    // see it once it has caught up.
+    shaft(db, "alice", "bob", 10);
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        0
+    );
+    assert_eq!(
+        block_on(db.get_all_users(true)).unwrap()["bob"].balance(),
+        0
+    );
+    assert_eq!(block_on(db.get_last_transactions(10)).unwrap().len(), 1);
+
+    shaft(&replica.database, "alice", "bob", 10);
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        10
+    );
+    let leaderboard = block_on(db.get_leaderboard(1, false, true)).unwrap();
+    assert_eq!(leaderboard[0].user_id(), "alice");
+}
+
+#[test]
+fn test_set_display_name_checked() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    let version = |db: &SqliteDatabase| {
+        block_on(db.get_user("alice".into()))
+            .unwrap()
+            .unwrap()
+            .version()
+    };
+    assert_eq!(version(db), 0);
+
+    // Logging in again with an unchanged name doesn't bump the version.
+    block_on(db.add_user_by_github_id("alice".into(), "alice".to_string())).unwrap();
+    assert_eq!(version(db), 0);
+
+    let read = version(db);
+    let new_version =
+        block_on(db.set_display_name_checked("alice".into(), "Alice".to_string(), read)).unwrap();
+    assert_eq!(new_version, read + 1);
+    assert_eq!(version(db), new_version);
+
+    // A second edit based on the same read loses.
+    match block_on(db.set_display_name_checked("alice".into(), "Al".to_string(), read)) {
+        Err(DatabaseError::VersionConflict {
+            expected_version, ..
+        }) => assert_eq!(expected_version, read),
+        res => panic!("expected VersionConflict, got {:?}", res),
+    }
+    let user = block_on(db.get_user("alice".into())).unwrap().unwrap();
+    assert_eq!(user.display_name(), "Alice");
+
+    // Unchecked renames bump the version too.
+    block_on(db.set_display_name("alice".into(), "Ally".to_string())).unwrap();
+    assert_eq!(version(db), new_version + 1);
+
+    match block_on(db.set_display_name_checked("bob".into(), "Bob".to_string(), 0)) {
+        Err(DatabaseError::UnknownUser { .. }) => {}
+        res => panic!("expected UnknownUser, got {:?}", res),
+    }
+}
+
+#[test]
+fn test_get_transactions_for_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+    shaft(db, "alice", "bob", 1);
+    shaft(db, "carol", "dave", 2);
+    shaft(db, "dave", "alice", 3);
+    shaft(db, "bob", "alice", 4);
+
+    let amounts = |user_ids: &[&str], limit| -> Vec<i64> {
+        let user_ids = user_ids.iter().map(|&user_id| user_id.into()).collect();
+        block_on(db.get_transactions_for_users(user_ids, None, limit))
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|txn| txn.amount)
+            .collect()
+    };
+
+    assert_eq!(amounts(&["bob"], 10), vec![4, 1]);
+    assert_eq!(amounts(&["bob", "carol"], 10), vec![4, 2, 1]);
+    assert_eq!(amounts(&["alice", "dave"], 2), vec![4, 3]);
+    assert_eq!(amounts(&["erin"], 10), Vec::<i64>::new());
+    assert_eq!(amounts(&[], 10), Vec::<i64>::new());
+
+    let user_ids = || vec!["alice".into(), "dave".into()];
+    let page = block_on(db.get_transactions_for_users(user_ids(), None, 2)).unwrap();
+    let page = block_on(db.get_transactions_for_users(user_ids(), page.next_cursor, 2)).unwrap();
+    let amounts: Vec<_> = page.items.iter().map(|txn| txn.amount).collect();
+    assert_eq!(amounts, vec![2, 1]);
+}
+
+#[test]
+fn test_get_user_ids() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    assert!(block_on(db.get_user_ids()).unwrap().is_empty());
+
+    add_users(db, &["carol", "alice", "bob"]);
+    block_on(db.set_user_active("bob".into(), false)).unwrap();
+
+    let user_ids = block_on(db.get_user_ids()).unwrap();
+    let user_ids: Vec<_> = user_ids.iter().map(|user_id| user_id.as_str()).collect();
+    assert_eq!(user_ids, vec!["alice", "bob", "carol"]);
+}
+
+#[test]
+fn test_metrics() {
+    struct Recorder(Mutex<Vec<(&'static str, Result<(), ()>)>>);
+
+    impl DatabaseMetrics for Recorder {
+        fn on_query(&self, method: &'static str, _duration: Duration, result: Result<(), ()>) {
+            self.0.lock().unwrap().push((method, result));
+        }
+    }
+
+    struct Panicker;
+
+    impl DatabaseMetrics for Panicker {
+        fn on_query(&self, _method: &'static str, _duration: Duration, _result: Result<(), ()>) {
+            panic!("metrics panicked");
+        }
+    }
+
+    let test_db = setup_db();
+    add_users(&test_db.database, &["alice"]);
+
+    let recorder = Arc::new(Recorder(Mutex::new(Vec::new())));
+    let db = test_db.database.clone().with_metrics(recorder.clone());
+
+    block_on(db.get_balance_for_user("alice".into())).unwrap();
+    block_on(db.set_display_name("dave".into(), "Dave".into())).unwrap_err();
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![
+            ("get_balance_for_user", Ok(())),
+            ("set_display_name", Err(()))
+        ]
+    );
+
+    // A panicking hook doesn't fail the query.
+    let db = db.with_metrics(Arc::new(Panicker));
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_balance_cache_other_writes() {
+    let test_db = setup_db();
+    let db = &test_db
+        .database
+        .clone()
+        .with_balance_cache(chrono::Duration::hours(1));
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    let balance = |user_id: &str| block_on(db.get_balance_for_user(user_id.into())).unwrap();
+    // Read alice's balance into the cache, do the write, then check it's
+    // seen straight away.
+    let check = |expected: i64, write: &dyn Fn()| {
+        balance("alice");
+        write();
+        assert_eq!(balance("alice"), expected);
+    };
+
+    check(10, &|| {
+        block_on(db.record_historical_transaction(transaction("alice", "bob", 10))).unwrap()
+    });
+
+    let id = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+    block_on(db.delete_transaction(id)).unwrap();
+    check(10, &|| block_on(db.restore_transaction(id)).unwrap());
+
+    check(15, &|| {
+        block_on(db.shaft_users(vec![transaction("alice", "carol", 5)])).unwrap()
+    });
+    check(20, &|| {
+        block_on(db.try_shaft_users(vec![
+            transaction("alice", "carol", 5),
+            transaction("alice", "dave", 5),
+        ]))
+        .unwrap();
+    });
+    check(10, &|| {
+        block_on(db.settle_between("alice".into(), "carol".into(), "cash".into())).unwrap();
+    });
+    check(13, &|| {
+        block_on(db.import_transactions(vec![transaction("alice", "bob", 3)], true)).unwrap();
+    });
+    assert_eq!(balance("bob"), -13);
+    block_on(db.merge_users("alice".into(), "bob".into())).unwrap();
+    assert_eq!(balance("bob"), 0);
+}
+
+#[test]
+fn test_user_rank() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+
+    let rank = |user_id: &str| block_on(db.get_user_rank(user_id.into())).unwrap();
+
+    assert_eq!(rank("bob"), Some(1));
+    assert_eq!(rank("carol"), Some(2));
+    assert_eq!(rank("alice"), Some(3));
+    assert_eq!(rank("dave"), None);
+}
+
+#[test]
+fn test_balances_for_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+
+    let balances =
+        block_on(db.get_balances_for_users(vec!["alice".into(), "carol".into(), "dave".into()]))
+            .unwrap();
+
+    assert_eq!(balances.len(), 2);
+    assert_eq!(balances.get("alice"), Some(&100));
+    assert_eq!(balances.get("carol"), Some(&0));
+}
+
+#[test]
+fn test_large_limit() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    let transactions = block_on(db.get_last_transactions(u32::MAX)).unwrap();
+    assert_eq!(transactions.len(), 1);
+
+    let page = block_on(db.get_transaction_feed(None, u32::MAX - 1, false)).unwrap();
+    assert_eq!(page.items.len(), 1);
+}
+
+#[test]
+fn test_inactive_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    let cutoff = Utc::now() + chrono::Duration::seconds(10);
+
+    block_on(db.record_historical_transaction(Transaction {
+        id: None,
+        shafter: "alice".to_string(),
+        shaftee: "bob".to_string(),
+        amount: 100,
+        datetime: cutoff + chrono::Duration::seconds(10),
+        reason: Some("test".to_string()),
+        idempotency_key: None,
+        category: None,
+    }))
+    .unwrap();
+
+    let inactive = block_on(db.get_inactive_users_since(cutoff)).unwrap();
+    let inactive: Vec<_> = inactive.iter().map(|u| u.user_id()).collect();
+    assert_eq!(inactive, vec!["carol".to_string()]);
+}
+
+#[test]
+fn test_get_inactive_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+
+    let since = Utc::now() - chrono::Duration::days(1);
+    block_on(db.record_historical_transaction(Transaction {
+        datetime: since - chrono::Duration::days(1),
+        ..transaction("alice", "bob", 100)
+    }))
+    .unwrap();
+    shaft(db, "carol", "bob", 5);
+
+    // Dave registered after the cutoff but has never shafted, so only counts
+    // as inactive here.
+    let inactive = block_on(db.get_inactive_users(since)).unwrap();
+    let inactive: Vec<_> = inactive
+        .iter()
+        .map(|u| (u.user_id(), u.balance()))
+        .collect();
+    assert_eq!(inactive, vec![("alice", 100), ("dave", 0)]);
+
+    let inactive = block_on(db.get_inactive_users_since(since)).unwrap();
+    let inactive: Vec<_> = inactive.iter().map(|u| u.user_id()).collect();
+    assert_eq!(inactive, vec!["alice"]);
+}
+
+#[test]
+fn test_add_user_is_atomic() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    // Make the second insert in add_user_by_github_id fail.
+    db.run_statements("DROP TABLE users").unwrap();
+
+    assert!(block_on(db.add_user_by_github_id("alice".into(), "Alice".to_string())).is_err());
+
+    // The identity insert should have been rolled back.
+    assert!(block_on(db.get_user_by_github_id("alice".into()))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_link_identity() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let lookup = |provider: &str, provider_id: &str| {
+        block_on(db.get_user_by_identity(provider.to_string(), provider_id.to_string()))
+            .unwrap()
+            .map(|user_id| user_id.to_string())
+    };
+
+    // Github logins are identities too.
+    assert_eq!(lookup(GITHUB_PROVIDER, "alice").as_deref(), Some("alice"));
+    assert_eq!(lookup("google", "1234"), None);
+
+    let link = |user_id: &str, provider: &str, provider_id: &str| {
+        block_on(db.link_identity(
+            user_id.into(),
+            provider.to_string(),
+            provider_id.to_string(),
+        ))
+    };
+
+    link("alice", "google", "1234").unwrap();
+    link("alice", "google", "1234").unwrap();
+    assert_eq!(lookup("google", "1234").as_deref(), Some("alice"));
+
+    match link("bob", "google", "1234") {
+        Err(DatabaseError::Conflict { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match link("dave", "google", "5678") {
+        Err(DatabaseError::UnknownUser { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Logging in with a linked Github account finds the existing user.
+    link("alice", GITHUB_PROVIDER, "alice-alt").unwrap();
+    let user_id =
+        block_on(db.add_user_by_github_id("alice-alt".into(), "Alice".to_string())).unwrap();
+    assert_eq!(user_id.to_string(), "alice");
+    assert_eq!(block_on(db.get_all_users(false)).unwrap().len(), 2);
+}
+
+#[test]
+fn test_merge_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "alice2", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "alice2", 30);
+    shaft(db, "alice2", "alice", 5);
+
+    let balance = |user_id: &str| block_on(db.get_balance_for_user(user_id.into())).unwrap();
+    let expected = balance("alice") + balance("alice2");
+
+    let token = block_on(db.create_token_for_user("alice2".into())).unwrap();
+
+    block_on(db.merge_users("alice2".into(), "alice".into())).unwrap();
+
+    assert_eq!(balance("alice"), expected);
+    assert_eq!(balance("bob"), -70);
+    assert!(block_on(db.get_user("alice2".into())).unwrap().is_none());
+
+    // alice2 shafting alice would now be alice shafting themselves.
+    let transactions = block_on(db.get_last_transactions(10)).unwrap();
+    assert_eq!(transactions.len(), 2);
+    assert!(transactions
+        .iter()
+        .all(|transaction| transaction.shafter != transaction.shaftee));
+
+    let (user, _) = block_on(db.get_user_from_token(token)).unwrap().unwrap();
+    assert_eq!(user.user_id(), "alice");
+    assert_eq!(
+        block_on(db.get_user_by_github_id("alice2".into()))
+            .unwrap()
+            .map(|user_id| user_id.to_string())
+            .as_deref(),
+        Some("alice")
+    );
+
+    match block_on(db.merge_users("alice2".into(), "alice".into())) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "alice2"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.merge_users("alice".into(), "alice".into())) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_sync_display_name_from_github() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    db.run_statements("UPDATE users SET display_name_overridden = 1 WHERE user_id = 'bob'")
+        .unwrap();
+
+    block_on(db.sync_display_name_from_github("alice".into(), "Alice".to_string())).unwrap();
+    block_on(db.sync_display_name_from_github("bob".into(), "Bob".to_string())).unwrap();
+
+    let users = block_on(db.get_all_users(false)).unwrap();
+    assert_eq!(users["alice"].display_name(), "Alice");
+    assert_eq!(users["bob"].display_name(), "bob");
+
+    match block_on(db.sync_display_name_from_github("carol".into(), "Carol".to_string())) {
+        Err(DatabaseError::UnknownUser { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_format_amount() {
+    let pounds = Currency::default();
+    assert_eq!(pounds.format_amount(-450), "-£4.50");
+    assert_eq!(pounds.format_amount(5), "£0.05");
+    assert_eq!(pounds.format_amount(12345), "£123.45");
+
+    let beers = Currency {
+        symbol: "🍺".to_string(),
+        decimal_places: 0,
+    };
+    assert_eq!(beers.format_amount(-3), "-🍺3");
+}
+
+#[test]
+fn test_stored_currency() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    assert_eq!(block_on(db.get_currency()).unwrap(), Currency::default());
+
+    let beers = Currency {
+        symbol: "🍺".to_string(),
+        decimal_places: 0,
+    };
+    block_on(db.set_currency(beers.clone())).unwrap();
+    assert_eq!(block_on(db.get_currency()).unwrap(), beers);
+
+    let too_precise = Currency {
+        symbol: "".to_string(),
+        decimal_places: Currency::MAX_DECIMAL_PLACES + 1,
+    };
+    match block_on(db.set_currency(too_precise)) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("expected InvalidInput, got {:?}", res),
+    }
+    assert_eq!(block_on(db.get_currency()).unwrap(), beers);
+}
+
+#[test]
+fn test_transaction_detail() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "alice", 30);
+
+    let transactions = block_on(db.get_last_transactions(1)).unwrap();
+    let id = transactions[0].id.unwrap();
+
+    let detail = block_on(db.get_transaction_detail(id)).unwrap().unwrap();
+    assert_eq!(detail.transaction.amount, 30);
+    assert_eq!(detail.shafter_display_name, "bob");
+    assert_eq!(detail.shaftee_display_name, "alice");
+    assert_eq!(detail.shafter_balance, -70);
+    assert_eq!(detail.shaftee_balance, 70);
+
+    assert!(block_on(db.get_transaction_detail(id + 100))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_get_last_transactions_named() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    block_on(db.set_display_name("alice".into(), "Alice".to_string())).unwrap();
+    shaft(db, "alice", "bob", 10);
+    shaft(db, "carol", "alice", 20);
+
+    rusqlite::Connection::open(&test_db.path)
+        .unwrap()
+        .execute(
+            "DELETE FROM users WHERE user_id = 'carol'",
+            rusqlite::NO_PARAMS,
+        )
+        .unwrap();
+
+    let named = block_on(db.get_last_transactions_named(10)).unwrap();
+    let names: Vec<_> = named
+        .iter()
+        .map(|named| {
+            (
+                named.transaction.amount,
+                named.shafter_display_name.as_str(),
+                named.shaftee_display_name.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(names, vec![(20, "carol", "Alice"), (10, "Alice", "bob")]);
+
+    let named = block_on(db.get_last_transactions_named(1)).unwrap();
+    assert_eq!(named.len(), 1);
+    assert_eq!(named[0].transaction.shafter, "carol");
+}
+
+#[test]
+fn test_get_largest_transactions() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    block_on(db.set_display_name("bob".into(), "Bob".to_string())).unwrap();
+    shaft(db, "alice", "bob", 5);
+    shaft(db, "alice", "bob", 50);
+    shaft(db, "bob", "alice", 20);
+    shaft(db, "bob", "alice", 50);
+    shaft(db, "alice", "bob", 1000);
+
+    let typo = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+    block_on(db.reverse_transaction(typo, "typo".to_string())).unwrap();
+
+    let largest = block_on(db.get_largest_transactions(3)).unwrap();
+    let largest: Vec<_> = largest
+        .iter()
+        .map(|named| {
+            (
+                named.transaction.amount,
+                named.shafter_display_name.as_str(),
+                named.shaftee_display_name.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        largest,
+        vec![
+            (50, "Bob", "alice"),
+            (50, "alice", "Bob"),
+            (20, "Bob", "alice")
+        ]
+    );
+}
+
+#[test]
+fn test_verify_balances() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "carol", 30);
+    shaft(db, "carol", "alice", 5);
+    shaft(db, "alice", "carol", 12);
+
+    let ids: Vec<i64> = block_on(db.get_last_transactions(4))
+        .unwrap()
+        .iter()
+        .map(|txn| txn.id.unwrap())
+        .collect();
+    block_on(db.soft_delete_transaction(ids[0])).unwrap();
+    block_on(db.reverse_transaction(ids[1], "typo".to_string())).unwrap();
+
+    assert_eq!(block_on(db.verify_balances()).unwrap(), vec![]);
+}
+
+#[test]
+fn test_soft_delete_transaction() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "alice", "bob", 30);
+
+    let id = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+    block_on(db.soft_delete_transaction(id)).unwrap();
+
+    let balance = |user_id: &str| block_on(db.get_balance_for_user(user_id.into())).unwrap();
+    assert_eq!(balance("alice"), 100);
+    assert_eq!(
+        block_on(db.get_all_users(false)).unwrap()["bob"].balance(),
+        -100
+    );
+
+    let page = block_on(db.get_transaction_feed(None, 10, true)).unwrap();
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.total, Some(1));
+
+    // Deleting twice is an error, as is deleting something that never existed.
+    match block_on(db.soft_delete_transaction(id)) {
+        Err(DatabaseError::UnknownTransaction { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    block_on(db.restore_transaction(id)).unwrap();
+    assert_eq!(balance("alice"), 130);
+
+    match block_on(db.restore_transaction(id)) {
+        Err(DatabaseError::UnknownTransaction { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_month_end_balances() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    // Either side of midnight at the end of January.
+    for &(month, day, hour, amount) in &[(1, 31, 23, 100), (2, 1, 0, 30)] {
+        block_on(db.record_historical_transaction(Transaction {
+            id: None,
+            shafter: "alice".to_string(),
+            shaftee: "bob".to_string(),
+            amount,
+            datetime: Utc.ymd(2020, month, day).and_hms(hour, 59, 59),
+            reason: Some("test".to_string()),
+            idempotency_key: None,
+            category: None,
+        }))
+        .unwrap();
+    }
+
+    let balances = block_on(db.get_month_end_balances(2020, 1, Tz::UTC)).unwrap();
+    assert_eq!(balances.get("alice"), Some(&100));
+    assert_eq!(balances.get("bob"), Some(&-100));
+    assert_eq!(balances.get("carol"), Some(&0));
+
+    let balances = block_on(db.get_month_end_balances(2020, 2, Tz::UTC)).unwrap();
+    assert_eq!(balances.get("alice"), Some(&130));
+
+    // January in London ends at the same time as in UTC, but in Sydney it
+    // ended before either transaction.
+    let balances = block_on(db.get_month_end_balances(2020, 1, Tz::Europe__London)).unwrap();
+    assert_eq!(balances.get("alice"), Some(&100));
+    let balances = block_on(db.get_month_end_balances(2020, 1, Tz::Australia__Sydney)).unwrap();
+    assert_eq!(balances.get("alice"), Some(&0));
+
+    match block_on(db.get_month_end_balances(2020, 13, Tz::UTC)) {
+        Err(DatabaseError::InvalidMonth { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_get_all_users_ordered() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+
+    let order = |order| -> Vec<String> {
+        block_on(db.get_all_users_ordered(order, false))
+            .unwrap()
+            .into_iter()
+            .map(|(user_id, _)| user_id)
+            .collect()
+    };
+
+    assert_eq!(order(SortOrder::Asc), vec!["bob", "carol", "alice"]);
+    assert_eq!(order(SortOrder::Desc), vec!["alice", "carol", "bob"]);
+}
+
+#[test]
+fn test_touch_token() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+
+    let last_used_at = || -> Option<i64> {
+        rusqlite::Connection::open(&test_db.path)
+            .unwrap()
+            .query_row(
+                "SELECT last_used_at FROM tokens",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+
+    assert_eq!(last_used_at(), None);
+
+    block_on(db.touch_token(token.clone())).unwrap();
+    let first = last_used_at().unwrap();
+
+    // Touching again within the interval is a no-op.
+    db.run_statements("UPDATE tokens SET last_used_at = last_used_at - 30")
+        .unwrap();
+    block_on(db.touch_token(token.clone())).unwrap();
+    assert_eq!(last_used_at(), Some(first - 30));
+
+    db.run_statements("UPDATE tokens SET last_used_at = last_used_at - 60")
+        .unwrap();
+    block_on(db.touch_token(token)).unwrap();
+    assert!(last_used_at().unwrap() >= first);
+}
+
+#[test]
+fn test_list_tokens_for_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+    block_on(db.create_token_for_user("alice".into())).unwrap();
+    block_on(db.create_token_for_user("bob".into())).unwrap();
+
+    let list = || block_on(db.list_tokens_for_user("alice".into())).unwrap();
+
+    let tokens = list();
+    assert_eq!(tokens.len(), 2);
+    assert!(tokens.iter().all(|info| info.last_used_at.is_none()));
+
+    block_on(db.touch_token(token)).unwrap();
+    assert_eq!(
+        list()
+            .iter()
+            .filter(|info| info.last_used_at.is_some())
+            .count(),
+        1
+    );
+
+    // Expired tokens aren't active sessions.
+    db.run_statements("UPDATE tokens SET expires_at = 0")
+        .unwrap();
+    assert!(list().is_empty());
+}
+
+#[test]
+fn test_tokens_stored_hashed() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+
+    let stored: String = rusqlite::Connection::open(&test_db.path)
+        .unwrap()
+        .query_row("SELECT token FROM tokens", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_ne!(stored, token.as_str());
+
+    let (user, _) = block_on(db.get_user_from_token(token)).unwrap().unwrap();
+    assert_eq!(user.user_id(), "alice");
+
+    // Tokens stored in plaintext before hashing was introduced no longer work.
+    db.run_statements("INSERT INTO tokens (user_id, token) VALUES ('alice', 'rawtoken')")
+        .unwrap();
+    assert!(block_on(db.get_user_from_token("rawtoken".into()))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_token_expiry() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+    let expired = block_on(db.create_token_for_user("alice".into())).unwrap();
+    let valid = block_on(db.create_token_for_user("alice".into())).unwrap();
+
+    assert!(block_on(db.get_user_from_token(expired.clone()))
+        .unwrap()
+        .is_some());
+
+    // Expire the first token.
+    db.run_statements("UPDATE tokens SET expires_at = 0 WHERE rowid = 1")
+        .unwrap();
+
+    assert!(block_on(db.get_user_from_token(expired)).unwrap().is_none());
+    assert!(block_on(db.get_user_from_token(valid.clone()))
+        .unwrap()
+        .is_some());
+
+    assert_eq!(block_on(db.purge_expired_tokens()).unwrap(), 1);
+    assert_eq!(block_on(db.purge_expired_tokens()).unwrap(), 0);
+    assert!(block_on(db.get_user_from_token(valid)).unwrap().is_some());
+}
+
+#[test]
+fn test_delete_all_tokens_for_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    let alice_tokens: Vec<_> = (0..2)
+        .map(|_| block_on(db.create_token_for_user("alice".into())).unwrap())
+        .collect();
+    let bob_token = block_on(db.create_token_for_user("bob".into())).unwrap();
+
+    assert_eq!(
+        block_on(db.delete_all_tokens_for_user("alice".into())).unwrap(),
+        2
+    );
+
+    for token in alice_tokens {
+        assert!(block_on(db.get_user_from_token(token)).unwrap().is_none());
+    }
+    assert!(block_on(db.get_user_from_token(bob_token))
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn test_with_config() {
+    let config = PoolConfig {
+        max_pool_size: 2,
+        min_idle: Some(1),
+        cpu_pool_threads: Some(1),
+        ..PoolConfig::default()
+    };
+    let db = SqliteDatabase::with_config(":memory:", config).unwrap();
+
+    assert_eq!(db.pool_state().connections, 1);
+
+    // The pool may open another connection in the background to replace the
+    // idle one we checked out, but never more than the maximum.
+    block_on(db.warm_pool()).unwrap();
+    assert!(db.pool_state().connections <= 2);
+}
+
+#[test]
+fn test_without_test_on_check_out() {
+    assert!(PoolConfig::default().test_on_check_out);
+
+    let test_db = setup_db_with_config(PoolConfig {
+        test_on_check_out: false,
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 10);
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -10
+    );
+}
+
+#[test]
+fn test_balance_for_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        0
+    );
+
+    match block_on(db.get_balance_for_user("bob".into())) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "bob"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_user_created_at() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    let before = Utc::now().timestamp();
+    add_users(db, &["alice"]);
+    let after = Utc::now().timestamp();
+
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+    let (user, _) = block_on(db.get_user_from_token(token)).unwrap().unwrap();
+    let created_at = user.created_at().timestamp();
+    assert!(before <= created_at && created_at <= after);
+
+    let users = block_on(db.get_all_users(false)).unwrap();
+    assert_eq!(users["alice"].created_at(), user.created_at());
+}
+
+#[test]
+fn test_get_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    let user = block_on(db.get_user("alice".into())).unwrap().unwrap();
+    assert_eq!(user.user_id(), "alice");
+    assert_eq!(user.display_name(), "alice");
+    assert_eq!(user.balance(), 100);
+
+    assert!(block_on(db.get_user("dave".into())).unwrap().is_none());
+}
+
+#[test]
+fn test_search_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "malice", "al_ex", "alfie"]);
+
+    let user_ids = |users: Vec<shaft::db::User>| -> Vec<String> {
+        users
+            .into_iter()
+            .map(|user| user.user_id().to_string())
+            .collect()
+    };
+
+    assert_eq!(
+        user_ids(block_on(db.search_users("ALI".into(), 10)).unwrap()),
+        vec!["alice", "malice"]
+    );
+    assert_eq!(
+        user_ids(block_on(db.search_users("al".into(), 2)).unwrap()),
+        vec!["al_ex", "alfie"]
+    );
+
+    // Wildcards match literally.
+    assert_eq!(
+        user_ids(block_on(db.search_users("l_".into(), 10)).unwrap()),
+        vec!["al_ex"]
+    );
+    assert!(block_on(db.search_users("%".into(), 10))
+        .unwrap()
+        .is_empty());
+
+    // Accents and non-ASCII case are ignored on both sides.
+    block_on(db.add_user_by_github_id("jose".into(), "José".to_string())).unwrap();
+    block_on(db.add_user_by_github_id("zoe".into(), "ZOË".to_string())).unwrap();
+    assert_eq!(
+        user_ids(block_on(db.search_users("jose".into(), 10)).unwrap()),
+        vec!["jose"]
+    );
+    assert_eq!(
+        user_ids(block_on(db.search_users("Zoë".into(), 10)).unwrap()),
+        vec!["zoe"]
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    let txn = block_on(db.get_last_transactions(1)).unwrap().remove(0);
+    let json = serde_json::to_value(&txn).unwrap();
+    assert_eq!(json["datetime"], txn.datetime.to_rfc3339());
+
+    let parsed: Transaction = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.id, txn.id);
+    assert_eq!(parsed.amount, 100);
+    assert_eq!(parsed.datetime, txn.datetime);
+
+    let user = block_on(db.get_user("alice".into())).unwrap().unwrap();
+    let json = serde_json::to_string(&user).unwrap();
+    let parsed: shaft::db::User = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.user_id(), "alice");
+    assert_eq!(parsed.balance(), 100);
+    assert_eq!(parsed.created_at(), user.created_at());
+}
+
+#[test]
+fn test_user_builder() {
+    let user = User::from("alice".to_string());
+    assert_eq!(user.user_id(), "alice");
+    assert_eq!(user.display_name(), "alice");
+    assert_eq!(user.balance(), 0);
+    assert_eq!(user.created_at().timestamp(), 0);
+
+    let created_at = Utc.timestamp(1_500_000_000, 0);
+    let user = user
+        .with_display_name("Alice")
+        .with_balance(-250)
+        .with_created_at(created_at);
+    assert_eq!(user.user_id(), "alice");
+    assert_eq!(user.display_name(), "Alice");
+    assert_eq!(user.balance(), -250);
+    assert_eq!(user.created_at(), created_at);
+}
+
+#[test]
+fn test_export_transactions_csv() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let datetime = Utc.ymd(2020, 1, 2).and_hms(3, 4, 5);
+    for reason in &["lunch", "beer, \"the good stuff\""] {
+        let txn = Transaction {
+            datetime,
+            reason: Some(reason.to_string()),
+            ..transaction("alice", "bob", 100)
+        };
+        block_on(db.shaft_user(txn)).unwrap();
+    }
+
+    assert_eq!(
+        block_on(db.export_transactions_csv()).unwrap(),
+        "id,shafter,shaftee,amount,datetime,reason\n\
+         1,alice,bob,100,2020-01-02T03:04:05+00:00,lunch\n\
+         2,alice,bob,100,2020-01-02T03:04:05+00:00,\"beer, \"\"the good stuff\"\"\"\n"
+    );
+}
+
+#[test]
+fn test_count_transactions_between() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "alice", "bob", 50);
+    shaft(db, "bob", "alice", 10);
+
+    let count = |shafter: &str, shaftee: &str| {
+        block_on(db.count_transactions_between(shafter.into(), shaftee.into())).unwrap()
+    };
+
+    assert_eq!(count("alice", "bob"), 2);
+    assert_eq!(count("bob", "alice"), 1);
+    assert_eq!(count("alice", "dave"), 0);
+}
+
+#[test]
+fn test_transactions_in_range() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    for day in 1..=4 {
+        let txn = Transaction {
+            datetime: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+            ..transaction("alice", "bob", i64::from(day))
+        };
+        block_on(db.shaft_user(txn)).unwrap();
+    }
+
+    let amounts = |from, to, limit| -> Vec<i64> {
+        block_on(db.get_transactions_in_range(from, to, limit))
+            .unwrap()
+            .into_iter()
+            .map(|txn| txn.amount)
+            .collect()
+    };
+
+    let from = Utc.ymd(2020, 1, 2).and_hms(12, 0, 0);
+    let to = Utc.ymd(2020, 1, 3).and_hms(12, 0, 0);
+    assert_eq!(amounts(from, to, 10), vec![3, 2]);
+    assert_eq!(amounts(from, to, 1), vec![3]);
+
+    match block_on(db.get_transactions_in_range(to, from, 10)) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_migrate_is_idempotent() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    block_on(db.migrate()).unwrap();
+
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        100
+    );
+}
+
+#[test]
+fn test_migrate_from_original_schema() {
+    let suffix: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+    let path = std::env::temp_dir().join(format!("shaft-test-{}.db", suffix));
+    let database = SqliteDatabase::with_path(&path).unwrap();
+    let test_db = TestDatabase { database, path };
+    let db = &test_db.database;
+
+    db.run_statements(
+        r#"
+        CREATE TABLE tokens ( user_id TEXT NOT NULL, token TEXT NOT NULL );
+        CREATE TABLE github_users (user_id text primary key not null, github_id text not null);
+        CREATE TABLE users ( user_id TEXT NOT NULL UNIQUE, display_name TEXT );
+        CREATE TABLE IF NOT EXISTS "transactions" (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT NOT NULL);
+        INSERT INTO users VALUES ('alice', 'Alice'), ('bob', 'Bob');
+        INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason)
+            VALUES ('alice', 'bob', 100, 1577836800, 'lunch'),
+                ('alice', 'bob', 50, 1577836800, ''),
+                ('alice', 'bob', 1, 1577836800, 'deleted');
+        DELETE FROM transactions WHERE id = 3;
+        "#,
+    )
+    .unwrap();
+
+    block_on(db.migrate()).unwrap();
+
+    let user = block_on(db.get_user("alice".into())).unwrap().unwrap();
+    assert_eq!(user.display_name(), "Alice");
+    assert_eq!(user.balance(), 150);
+    assert!(user.created_at().timestamp() > 0);
+
+    // Empty reasons are now missing ones, and IDs aren't reused.
+    let transactions = block_on(db.get_last_transactions(10)).unwrap();
+    assert_eq!(
+        transactions
+            .iter()
+            .map(|t| t.reason.as_deref())
+            .collect::<Vec<_>>(),
+        vec![None, Some("lunch")]
+    );
+    let mut t = transaction("alice", "bob", 1);
+    t.reason = None;
+    block_on(db.shaft_user(t)).unwrap();
+    let t = block_on(db.get_last_transactions(1)).unwrap().remove(0);
+    assert_eq!(t.id, Some(4));
+    assert_eq!(t.reason, None);
+
+    // The new tables exist too.
+    block_on(db.add_attachment(1, "https://example.com/receipt".to_string())).unwrap();
+}
+
+#[test]
+fn test_summary() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+
+    for &(shafter, shaftee, amount, day) in &[
+        ("alice", "bob", 100, 1),
+        ("bob", "alice", 30, 2),
+        ("carol", "bob", 5, 2),
+        ("dave", "alice", 1000, 5),
+    ] {
+        let txn = Transaction {
+            datetime: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+            ..transaction(shafter, shaftee, amount)
+        };
+        block_on(db.shaft_user(txn)).unwrap();
+    }
+
+    let summary = block_on(db.get_summary(
+        Utc.ymd(2020, 1, 1).and_hms(0, 0, 0),
+        Utc.ymd(2020, 1, 3).and_hms(0, 0, 0),
+    ))
+    .unwrap();
+
+    let totals: Vec<_> = summary
+        .iter()
+        .map(|s| (s.user_id.as_str(), s.total_shafted, s.total_received, s.net))
+        .collect();
+    assert_eq!(
+        totals,
+        vec![
+            ("alice", 100, 30, 70),
+            ("bob", 30, 105, -75),
+            ("carol", 5, 0, 5),
+        ]
+    );
+    assert_eq!(summary[0].display_name, "alice");
+}
+
+#[test]
+fn test_stream_all_transactions() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    // Enough to need more than one chunk.
+    let transactions = (1..=2500).map(|amount| transaction("alice", "bob", amount));
+    block_on(db.shaft_users(transactions.collect())).unwrap();
+    block_on(db.soft_delete_transaction(2)).unwrap();
+
+    let streamed: Vec<_> = block_on(db.stream_all_transactions().try_collect()).unwrap();
+    let ids: Vec<i64> = streamed.iter().filter_map(|txn| txn.id).collect();
+
+    assert_eq!(ids.len(), 2499);
+    assert_eq!(&ids[..3], &[1, 3, 4]);
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(ids.last(), Some(&2500));
+}
+
+#[test]
+fn test_limits_are_validated_and_clamped() {
+    let test_db = setup_db_with_config(PoolConfig {
+        max_limit: 2,
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    for amount in 1..=3 {
+        shaft(db, "alice", "bob", amount);
+    }
+
+    assert_eq!(
+        block_on(db.get_last_transactions(u32::MAX)).unwrap().len(),
+        2
+    );
+    assert_eq!(
+        block_on(db.get_leaderboard(u32::MAX, true, false))
+            .unwrap()
+            .len(),
+        2
+    );
+
+    match block_on(db.get_last_transactions(0)) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_latest_transaction_between() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "alice", "bob", 5);
+    shaft(db, "bob", "alice", 10);
+
+    let latest = |shafter: &str, shaftee: &str| {
+        block_on(db.get_latest_transaction_between(shafter.into(), shaftee.into())).unwrap()
+    };
+
+    let txn = latest("alice", "bob").unwrap();
+    assert_eq!((txn.id, txn.amount), (Some(2), 5));
+    assert_eq!(latest("bob", "alice").unwrap().amount, 10);
+    assert!(latest("alice", "dave").is_none());
+}
+
+#[test]
+fn test_last_transactions_filtered() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "alice", "bob", 5);
+    shaft(db, "bob", "alice", 50);
+
+    let amounts = |min_amount| -> Vec<i64> {
+        block_on(db.get_last_transactions_filtered(10, min_amount))
+            .unwrap()
+            .into_iter()
+            .map(|txn| txn.amount)
+            .collect()
+    };
+
+    assert_eq!(amounts(None), vec![50, 5, 100]);
+    assert_eq!(amounts(Some(50)), vec![50, 100]);
+    assert_eq!(amounts(Some(1000)), Vec::<i64>::new());
+
+    let err = block_on(db.get_last_transactions_filtered(10, Some(-1))).unwrap_err();
+    assert!(matches!(err, DatabaseError::InvalidInput { .. }));
+}
+
+#[test]
+fn test_settle_between() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "alice", 30);
+
+    let settle = |user_a: &str, user_b: &str| {
+        block_on(db.settle_between(user_a.into(), user_b.into(), "Settle up".into())).unwrap()
+    };
+    let balance = || block_on(db.get_balance_between("alice".into(), "bob".into())).unwrap();
+
+    assert_eq!(settle("bob", "alice"), -70);
+    assert_eq!(balance(), 0);
+
+    let txn = block_on(db.get_last_transactions(1)).unwrap().remove(0);
+    assert_eq!(
+        (txn.shafter.as_str(), txn.shaftee.as_str()),
+        ("bob", "alice")
+    );
+    assert_eq!(txn.amount, 70);
+    assert_eq!(txn.reason.as_deref(), Some("Settle up"));
+
+    // Nothing left to settle, so nothing is recorded.
+    assert_eq!(settle("alice", "bob"), 0);
+    assert_eq!(block_on(db.get_last_transactions(10)).unwrap().len(), 3);
+
+    shaft(db, "bob", "alice", 20);
+    assert_eq!(settle("alice", "bob"), -20);
+    assert_eq!(balance(), 0);
+
+    let err = block_on(db.settle_between("alice".into(), "dave".into(), "".into())).unwrap_err();
+    assert!(matches!(err, DatabaseError::UnknownUser { .. }));
+}
+
+#[test]
+fn test_total_outstanding() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    assert_eq!(block_on(db.get_total_outstanding()).unwrap(), 0);
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "carol", "bob", 50);
+    shaft(db, "bob", "alice", 30);
+
+    // alice +70, carol +50, bob -120.
+    assert_eq!(block_on(db.get_total_outstanding()).unwrap(), 120);
+}
+
+#[test]
+fn test_shaft_many() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    let shaft_many = |shaftees: Vec<(&str, i64)>| {
+        let shaftees = shaftees
+            .into_iter()
+            .map(|(user_id, amount)| (user_id.into(), amount))
+            .collect();
+        block_on(db.shaft_many("alice".into(), shaftees, "Pizza".into(), Utc::now()))
+    };
+
+    shaft_many(vec![("bob", 1200), ("carol", 800)]).unwrap();
+
+    let txns = block_on(db.get_last_transactions(10)).unwrap();
+    assert_eq!(txns.len(), 2);
+    assert!(txns
+        .iter()
+        .all(|txn| txn.shafter == "alice" && txn.reason.as_deref() == Some("Pizza")));
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        2000
+    );
+
+    // Any bad shaftee or amount rolls back the whole batch.
+    match shaft_many(vec![("bob", 100), ("dave", 100)]) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "dave"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match shaft_many(vec![("bob", 100), ("carol", 0)]) {
+        Err(DatabaseError::InvalidAmount { amount }) => assert_eq!(amount, 0),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(block_on(db.get_last_transactions(10)).unwrap().len(), 2);
+}
+
+#[test]
+fn test_stats() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    let stats = block_on(db.get_stats()).unwrap();
+    assert_eq!(
+        (
+            stats.user_count,
+            stats.transaction_count,
+            stats.total_outstanding
+        ),
+        (0, 0, 0)
+    );
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "carol", "bob", 50);
+
+    let stats = block_on(db.get_stats()).unwrap();
+    assert_eq!(
+        (
+            stats.user_count,
+            stats.transaction_count,
+            stats.total_outstanding
+        ),
+        (3, 2, 150)
+    );
+}
+
+#[test]
+fn test_reverse_transaction() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    let original = block_on(db.get_last_transactions(1)).unwrap().remove(0);
+    let original_id = original.id.unwrap();
+
+    let reversal_id = block_on(db.reverse_transaction(original_id, "Oops".into())).unwrap();
+
+    let reversal = block_on(db.get_last_transactions(1)).unwrap().remove(0);
+    assert_eq!(reversal.id, Some(reversal_id));
+    assert_eq!(
+        (reversal.shafter.as_str(), reversal.shaftee.as_str()),
+        ("bob", "alice")
+    );
+    assert_eq!(reversal.amount, 100);
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        0
+    );
+
+    // Both halves are excluded from counts that ignore reversals.
+    assert_eq!(block_on(db.get_total_shafted(true)).unwrap(), 0);
+
+    match block_on(db.reverse_transaction(original_id, "Again".into())) {
+        Err(DatabaseError::AlreadyReversed { id }) => assert_eq!(id, original_id),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.reverse_transaction(1000, "Nope".into())) {
+        Err(DatabaseError::UnknownTransaction { id }) => assert_eq!(id, 1000),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_set_user_active() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+
+    block_on(db.set_user_active("bob".into(), false)).unwrap();
+
+    let users = block_on(db.get_all_users(false)).unwrap();
+    assert_eq!(users.keys().collect::<Vec<_>>(), vec!["carol", "alice"]);
+    assert_eq!(block_on(db.get_all_users(true)).unwrap().len(), 3);
+
+    let leaderboard = block_on(db.get_leaderboard(10, true, false)).unwrap();
+    assert!(leaderboard.iter().all(|user| user.user_id() != "bob"));
+    assert_eq!(
+        block_on(db.get_leaderboard(10, true, true)).unwrap()[0].user_id(),
+        "bob"
+    );
+
+    // Their shafts still count for the other party.
+    assert_eq!(users["alice"].balance(), 100);
+
+    block_on(db.set_user_active("bob".into(), true)).unwrap();
+    assert_eq!(block_on(db.get_all_users(false)).unwrap().len(), 3);
+
+    match block_on(db.set_user_active("dave".into(), false)) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "dave"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_balance_at() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let now = Utc::now();
+    let at = |time| Transaction {
+        datetime: time,
+        ..transaction("alice", "bob", 100)
+    };
+    block_on(db.shaft_user(at(now - chrono::Duration::days(2)))).unwrap();
+    block_on(db.shaft_user(at(now - chrono::Duration::days(1)))).unwrap();
+    shaft(db, "bob", "alice", 30);
+
+    let balance_at = |user: &str, time| block_on(db.get_balance_at(user.into(), time));
+
+    assert_eq!(
+        balance_at("alice", now - chrono::Duration::days(3)).unwrap(),
+        0
+    );
+    assert_eq!(
+        balance_at("alice", now - chrono::Duration::days(2)).unwrap(),
+        100
+    );
+    assert_eq!(
+        balance_at("bob", now - chrono::Duration::hours(1)).unwrap(),
+        -200
+    );
+    assert_eq!(
+        balance_at("alice", now + chrono::Duration::minutes(1)).unwrap(),
+        170
+    );
+
+    match balance_at("dave", now) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "dave"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_shaft_listener() {
+    let test_db = setup_db();
+    let shafted = Arc::new(Mutex::new(Vec::new()));
+    let listener_shafted = shafted.clone();
+    let db = test_db
+        .database
+        .clone()
+        .with_shaft_listener(Arc::new(move |txn: &Transaction| {
+            listener_shafted.lock().unwrap().push(txn.clone())
+        }));
+
+    add_users(&db, &["alice", "bob"]);
+
+    let txn = Transaction {
+        idempotency_key: Some("pizza".to_string()),
+        ..transaction("alice", "bob", 100)
+    };
+    block_on(db.shaft_user(txn.clone())).unwrap();
+    // Neither resubmissions nor failed shafts are announced.
+    block_on(db.shaft_user(txn)).unwrap();
+    block_on(db.shaft_user(transaction("alice", "dave", 100))).unwrap_err();
+
+    {
+        let shafted = shafted.lock().unwrap();
+        assert_eq!(shafted.len(), 1);
+        assert_eq!(shafted[0].id, Some(1));
+        assert_eq!(shafted[0].amount, 100);
+    }
+
+    // A panicking listener doesn't fail the shaft.
+    let db = db.with_shaft_listener(Arc::new(|_: &Transaction| panic!("listener panicked")));
+    block_on(db.shaft_user(transaction("alice", "bob", 5))).unwrap();
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        105
+    );
+}
+
+#[test]
+fn test_leaderboard() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "carol", "dave", 30);
+
+    let user_ids = |users: Vec<shaft::db::User>| -> Vec<String> {
+        users
+            .into_iter()
+            .map(|user| user.user_id().to_string())
+            .collect()
+    };
+
+    assert_eq!(
+        user_ids(block_on(db.get_leaderboard(2, true, false)).unwrap()),
+        vec!["bob", "dave"]
+    );
+    assert_eq!(
+        user_ids(block_on(db.get_leaderboard(3, false, false)).unwrap()),
+        vec!["alice", "carol", "dave"]
+    );
+}
+
+#[test]
+fn test_get_all_users_sorted() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    block_on(db.add_user_by_github_id("alice".into(), "Zed".to_string())).unwrap();
+    block_on(db.add_user_by_github_id("bob".into(), "amy".to_string())).unwrap();
+    block_on(db.add_user_by_github_id("carol".into(), "Max".to_string())).unwrap();
+    shaft(db, "bob", "alice", 10);
+
+    let sorted = |sort| -> Vec<String> {
+        block_on(db.get_all_users_sorted(sort, false))
+            .unwrap()
+            .into_iter()
+            .map(|user| user.user_id().to_string())
+            .collect()
+    };
+
+    assert_eq!(sorted(UserSort::NameAsc), vec!["bob", "carol", "alice"]);
+    assert_eq!(sorted(UserSort::NameDesc), vec!["alice", "carol", "bob"]);
+    assert_eq!(sorted(UserSort::BalanceAsc), vec!["alice", "carol", "bob"]);
+    assert_eq!(sorted(UserSort::BalanceDesc), vec!["bob", "carol", "alice"]);
+}
+
+#[test]
+fn test_balance_between() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "alice", 30);
+    shaft(db, "carol", "alice", 1000);
+
+    let between = |a: &str, b: &str| block_on(db.get_balance_between(a.into(), b.into()));
+
+    assert_eq!(between("alice", "bob").unwrap(), 70);
+    assert_eq!(between("bob", "alice").unwrap(), -70);
+    assert_eq!(between("bob", "carol").unwrap(), 0);
+
+    match between("dave", "erin") {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "dave"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_retries_transient_errors() {
+    // A directory can't be opened as a database, so every connection attempt
+    // fails.
+    let dir = std::env::temp_dir();
+    let config = PoolConfig {
+        min_idle: Some(0),
+        connection_timeout: std::time::Duration::from_millis(10),
+        max_retries: 2,
+        ..PoolConfig::default()
+    };
+    let db = SqliteDatabase::with_config(&dir, config).unwrap();
+
+    let start = std::time::Instant::now();
+    match block_on(db.get_all_users(false)) {
+        Err(DatabaseError::ConnectionPoolError { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Two retries, backing off 50ms then 100ms.
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+}
+
+#[test]
+fn test_pool_timeout() {
+    let config = PoolConfig {
+        max_pool_size: 1,
+        connection_timeout: std::time::Duration::from_millis(50),
+        cpu_pool_threads: Some(2),
+        ..PoolConfig::default()
+    };
+    let db = SqliteDatabase::with_config(":memory:", config).unwrap();
+
+    // Hold the only connection long enough for another operation to give up.
+    let slow = db.in_transaction(|_conn| {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        Ok(())
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    match block_on(db.in_transaction(|_conn| Ok(()))) {
+        Err(DatabaseError::PoolTimeout { timeout, .. }) => {
+            assert_eq!(timeout, std::time::Duration::from_millis(50))
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    block_on(slow).unwrap();
+    assert_eq!(db.pool_state().timeout_count, 1);
+}
+
+#[test]
+fn test_unique_violation_is_conflict() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    db.run_statements("CREATE UNIQUE INDEX attachments_url ON attachments (url)")
+        .unwrap();
+
+    block_on(db.add_attachment(1, "https://example.com/receipt".to_string())).unwrap();
+    match block_on(db.add_attachment(1, "https://example.com/receipt".to_string())) {
+        Err(DatabaseError::Conflict { constraint }) => assert_eq!(constraint, "attachments.url"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_error_includes_operation() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    db.run_statements("DROP TABLE transactions").unwrap();
+
+    let err = block_on(db.get_total_shafted(false)).unwrap_err();
+    assert!(err.to_string().contains("get_total_shafted"), "{}", err);
+}
+
+#[test]
+fn test_first_transaction() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    assert!(block_on(db.get_first_transaction()).unwrap().is_none());
+
+    shaft(db, "alice", "bob", 1);
+    shaft(db, "alice", "bob", 2);
+
+    let first = block_on(db.get_first_transaction()).unwrap().unwrap();
+    assert_eq!(first.amount, 1);
+}
+
+#[test]
+fn test_attachments() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    let id = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+
+    for i in 0..MAX_ATTACHMENTS_PER_TRANSACTION {
+        block_on(db.add_attachment(id, format!("https://example.com/{}.jpg", i))).unwrap();
+    }
+
+    match block_on(db.add_attachment(id, "https://example.com/extra.jpg".to_string())) {
+        Err(DatabaseError::TooManyAttachments { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    match block_on(db.add_attachment(id, " ".to_string())) {
+        Err(DatabaseError::EmptyAttachmentUrl) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    match block_on(db.add_attachment(id + 1, "https://example.com/0.jpg".to_string())) {
+        Err(DatabaseError::UnknownTransaction { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let attachments = block_on(db.get_attachments(id)).unwrap();
+    assert_eq!(attachments.len(), MAX_ATTACHMENTS_PER_TRANSACTION);
+    assert_eq!(attachments[0].url, "https://example.com/0.jpg");
+}
+
+#[test]
+fn test_most_active_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "carol", "bob", 5);
+    shaft(db, "bob", "alice", 10);
+
+    let active = block_on(db.get_most_active_users(2, false)).unwrap();
+    let active: Vec<_> = active.into_iter().collect();
+
+    assert_eq!(
+        active,
+        vec![("bob".to_string(), 3), ("alice".to_string(), 2)]
+    );
+}
+
+#[test]
+fn test_worker_panic() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    // A timestamp that chrono can't represent, so reading it back panics.
+    db.run_statements(
+        "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason)
+        VALUES ('alice', 'bob', 1, 9223372036854775807, 'bad')",
+    )
+    .unwrap();
+
+    match block_on(db.get_last_transactions(1)) {
+        Err(DatabaseError::WorkerPanic { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Later queries are unaffected.
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 1);
+}
+
+#[test]
+fn test_user_by_display_name() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    let user = block_on(db.get_user_by_display_name("ALICE".to_string()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.user_id(), "alice");
+    assert_eq!(user.balance(), 100);
+
+    assert!(block_on(db.get_user_by_display_name("ali".to_string()))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_gross_totals_exclude_reversed() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "alice", 5);
+
+    // A reversal of the first transaction.
+    let id = block_on(db.get_first_transaction())
+        .unwrap()
+        .unwrap()
+        .id
+        .unwrap();
+    db.run_statements(&format!(
+        "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason, reversed_transaction_id)
+        VALUES ('bob', 'alice', 100, 0, 'oops', {})",
+        id
+    ))
+    .unwrap();
+
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 205);
+    assert_eq!(block_on(db.get_total_shafted(true)).unwrap(), 5);
+
+    let counts = block_on(db.get_shaft_counts(true)).unwrap();
+    let counts: Vec<_> = counts.into_iter().collect();
+    assert_eq!(counts, vec![("alice".to_string(), 1)]);
+
+    let active = block_on(db.get_most_active_users(10, true)).unwrap();
+    assert_eq!(active.get("alice"), Some(&1));
+    assert_eq!(active.get("bob"), Some(&1));
+
+    // Net balances still include both.
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        -5
+    );
+}
+
+#[test]
+fn test_team_balances() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+    db.run_statements(
+        "INSERT INTO user_teams (user_id, team)
+        VALUES ('alice', 'red'), ('bob', 'red'), ('carol', 'blue')",
+    )
+    .unwrap();
+
+    shaft(db, "alice", "carol", 100);
+    shaft(db, "bob", "dave", 20);
+
+    let balances = block_on(db.get_team_balances()).unwrap();
+    let balances: Vec<_> = balances.into_iter().collect();
+
+    assert_eq!(
+        balances,
+        vec![("blue".to_string(), -100), ("red".to_string(), 120)]
+    );
+}
+
+#[test]
+fn test_shaft_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    // One unknown shaftee means nothing is committed.
+    let res = block_on(db.shaft_users(vec![
+        transaction("alice", "bob", 10),
+        transaction("alice", "dave", 20),
+    ]));
+    match res {
+        Err(DatabaseError::UnknownUser { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 0);
+
+    block_on(db.shaft_users(vec![
+        transaction("alice", "bob", 10),
+        transaction("alice", "carol", 20),
+    ]))
+    .unwrap();
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 30);
+}
+
+#[test]
+fn test_try_shaft_users() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let results = block_on(db.try_shaft_users(vec![
+        transaction("alice", "bob", 10),
+        transaction("alice", "dave", 20),
+        transaction("bob", "alice", 5),
+    ]))
+    .unwrap();
+
+    assert!(results[0].is_ok());
+    match &results[1] {
+        Err(DatabaseError::UnknownUser { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(results[2].is_ok());
+
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 15);
+}
+
+#[test]
+fn test_nettable_pairs() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "bob", "alice", 100);
+    shaft(db, "alice", "bob", 30);
+    shaft(db, "alice", "bob", 5);
+    shaft(db, "alice", "carol", 10);
+
+    let pairs = block_on(db.get_nettable_pairs()).unwrap();
+    assert_eq!(
+        pairs,
+        vec![("alice".to_string(), "bob".to_string(), 35, 100)]
+    );
+}
+
+#[test]
+fn test_shaft_user_in_transaction() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    // The second step fails, so the shaft is rolled back too.
+    let db_clone = db.clone();
+    let res = block_on(db.in_transaction(move |conn| {
+        db_clone.shaft_user_in(conn, transaction("alice", "bob", 100))?;
+        db_clone.shaft_user_in(conn, transaction("alice", "dave", 100))
+    }));
+    assert!(res.is_err());
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 0);
+
+    let db_clone = db.clone();
+    block_on(db.in_transaction(move |conn| {
+        db_clone.shaft_user_in(conn, transaction("alice", "bob", 100))
+    }))
+    .unwrap();
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 100);
+}
+
+#[test]
+fn test_balance_extremes() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    let (max, min) = block_on(db.get_balance_extremes()).unwrap();
+    assert!(max.is_none());
+    assert!(min.is_none());
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "carol", 30);
+
+    let (max, min) = block_on(db.get_balance_extremes()).unwrap();
+    assert_eq!(max.unwrap().user_id(), "alice");
+    assert_eq!(min.unwrap().user_id(), "bob");
+}
+
+#[test]
+fn test_add_user_is_idempotent() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    // Logging in again updates the display name rather than erroring.
+    let user_id = block_on(db.add_user_by_github_id("alice".into(), "Alice".to_string())).unwrap();
+    assert_eq!(user_id.as_str(), "alice");
+
+    let users = block_on(db.get_all_users(false)).unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users["alice"].display_name(), "Alice");
+}
+
+#[test]
+fn test_concurrent_first_logins() {
+    let test_db = setup_db_with_config(PoolConfig {
+        cpu_pool_threads: Some(4),
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    let logins = (0..8).map(|_| db.add_user_by_github_id("alice".into(), "Alice".to_string()));
+    for user_id in block_on(futures::future::join_all(logins)) {
+        assert_eq!(user_id.unwrap().as_str(), "alice");
+    }
+
+    assert_eq!(block_on(db.get_all_users(false)).unwrap().len(), 1);
+}
+
+#[test]
+fn test_delete_transaction() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+    let id = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+
+    block_on(db.delete_transaction(id)).unwrap();
+    assert!(block_on(db.get_last_transactions(1)).unwrap().is_empty());
+
+    match block_on(db.delete_transaction(id)) {
+        Err(DatabaseError::UnknownTransaction { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_set_display_name() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    block_on(db.set_display_name("alice".into(), "  Al  ".to_string())).unwrap();
+
+    // Github no longer overwrites it.
+    block_on(db.sync_display_name_from_github("alice".into(), "Alice".to_string())).unwrap();
+    assert_eq!(
+        block_on(db.get_all_users(false)).unwrap()["alice"].display_name(),
+        "Al"
+    );
+
+    match block_on(db.set_display_name("alice".into(), " ".to_string())) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    match block_on(db.set_display_name("bob".into(), "Bob".to_string())) {
+        Err(DatabaseError::UnknownUser { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_transactions_page() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    for amount in 1..=5 {
+        shaft(db, "alice", "bob", amount);
+    }
+
+    let page = block_on(db.get_transactions_page(2, 2)).unwrap();
+    let amounts: Vec<_> = page.iter().map(|txn| txn.amount).collect();
+    assert_eq!(amounts, vec![3, 2]);
+
+    let page = block_on(db.get_transactions_page(10, 4)).unwrap();
+    assert_eq!(page.len(), 1);
+}
+
+#[test]
+fn test_transactions_for_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 1);
+    shaft(db, "bob", "alice", 2);
+    shaft(db, "bob", "carol", 3);
+
+    let amounts = |direction| -> Vec<i64> {
+        block_on(db.get_transactions_for_user("alice".into(), direction, None, 10))
+            .unwrap()
+            .items
+            .iter()
+            .map(|txn| txn.amount)
+            .collect()
+    };
+
+    assert_eq!(amounts(TransactionDirection::Sent), vec![1]);
+    assert_eq!(amounts(TransactionDirection::Received), vec![2]);
+    assert_eq!(amounts(TransactionDirection::Both), vec![2, 1]);
+
+    // Page through one at a time.
+    let page =
+        block_on(db.get_transactions_for_user("alice".into(), TransactionDirection::Both, None, 1))
+            .unwrap();
+    assert_eq!(page.items[0].amount, 2);
+    assert!(page.total.is_none());
+
+    let page = block_on(db.get_transactions_for_user(
+        "alice".into(),
+        TransactionDirection::Both,
+        page.next_cursor,
+        1,
+    ))
+    .unwrap();
+    assert_eq!(page.items[0].amount, 1);
+
+    let page = block_on(db.get_transactions_for_user(
+        "alice".into(),
+        TransactionDirection::Both,
+        page.next_cursor,
+        1,
+    ))
+    .unwrap();
+    assert!(page.items.is_empty());
+    assert_eq!(page.next_cursor, None);
+}
+
+#[test]
+fn test_shaft_rejects_non_positive_amount() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    for &amount in &[0, -100] {
+        match block_on(db.shaft_user(transaction("alice", "bob", amount))) {
+            Err(DatabaseError::InvalidAmount { .. }) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    // The amount is checked before the shaftee.
+    match block_on(db.shaft_user(transaction("alice", "dave", 0))) {
+        Err(DatabaseError::InvalidAmount { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 0);
+}
+
+#[test]
+fn test_shaft_rejects_amount_over_max() {
+    let test_db = setup_db_with_config(PoolConfig {
+        max_amount: Some(10000),
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    block_on(db.shaft_user(transaction("alice", "bob", 10000))).unwrap();
+
+    match block_on(db.shaft_user(transaction("alice", "bob", 10001))) {
+        Err(DatabaseError::AmountTooLarge { amount, max }) => {
+            assert_eq!((amount, max), (10001, 10000))
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    assert_eq!(block_on(db.get_total_shafted(false)).unwrap(), 10000);
+}
+
+#[test]
+fn test_require_unique_display_name() {
+    fn is_conflict<T>(res: Result<T, DatabaseError>) -> bool {
+        matches!(res, Err(DatabaseError::Conflict { .. }))
+    }
+
+    let test_db = setup_db_with_config(PoolConfig {
+        require_unique_display_name: true,
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    let add = |github_id: &str, name: &str| {
+        block_on(db.add_user_by_github_id(github_id.into(), name.to_string()))
+    };
+
+    add("alice", "Alice").unwrap();
+    add("bob", "Bob").unwrap();
+    assert!(is_conflict(add("bob2", "BOB")));
+    assert!(block_on(db.get_user("bob2".into())).unwrap().is_none());
+
+    // Logging in again with the same name is fine.
+    add("alice", "Alice").unwrap();
+
+    assert!(is_conflict(block_on(
+        db.set_display_name("alice".into(), "bob".to_string())
+    )));
+    block_on(db.set_display_name("alice".into(), "ALICE".to_string())).unwrap();
+
+    // Without the option, duplicates are allowed.
+    let test_db = setup_db();
+    add_users(&test_db.database, &["bob"]);
+    block_on(
+        test_db
+            .database
+            .add_user_by_github_id("bob2".into(), "bob".to_string()),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_shaft_rate_limit() {
+    let test_db = setup_db_with_config(PoolConfig {
+        shaft_rate_limit: Some(ShaftRateLimit {
+            max_shafts_per_window: 2,
+            window: std::time::Duration::from_secs(60),
+        }),
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    // Shafts from before the window don't count.
+    let old = Transaction {
+        datetime: Utc::now() - chrono::Duration::minutes(5),
+        ..transaction("alice", "bob", 1)
+    };
+    block_on(db.shaft_user(old)).unwrap();
+
+    shaft(db, "alice", "bob", 1);
+    shaft(db, "alice", "bob", 1);
+
+    match block_on(db.shaft_user(transaction("alice", "bob", 1))) {
+        Err(DatabaseError::RateLimited { retry_after_secs }) => {
+            assert!(retry_after_secs > 0 && retry_after_secs <= 60)
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // The limit is per shafter.
+    shaft(db, "bob", "alice", 1);
+
+    // Batches are limited too, with each shaft counting those before it.
+    // Nothing from a failed batch is committed.
+    let bob = || transaction("bob", "alice", 1);
+    match block_on(db.shaft_users(vec![bob(), bob()])) {
+        Err(DatabaseError::RateLimited { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.shaft_users(vec![transaction("alice", "bob", 1)])) {
+        Err(DatabaseError::RateLimited { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let results = block_on(db.try_shaft_users(vec![bob(), bob()])).unwrap();
+    assert!(results[0].is_ok());
+    match &results[1] {
+        Err(DatabaseError::RateLimited { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_shaft_rejects_long_reason() {
+    let test_db = setup_db_with_config(PoolConfig {
+        max_reason_length: 5,
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    // Characters are counted rather than bytes, and trailing whitespace is
+    // ignored.
+    for reason in &["🍺🍺🍺🍺🍺", "beer   \n"] {
+        let txn = Transaction {
+            reason: Some(reason.to_string()),
+            ..transaction("alice", "bob", 100)
+        };
+        block_on(db.shaft_user(txn)).unwrap();
+    }
+
+    let txn = Transaction {
+        reason: Some("beers!".to_string()),
+        ..transaction("alice", "bob", 100)
+    };
+    match block_on(db.shaft_user(txn)) {
+        Err(DatabaseError::ReasonTooLong { len, max }) => assert_eq!((len, max), (6, 5)),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_shaft_idempotency_key() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let txn = Transaction {
+        idempotency_key: Some("abc".to_string()),
+        ..transaction("alice", "bob", 100)
+    };
+    block_on(db.shaft_user(txn.clone())).unwrap();
+    block_on(db.shaft_user(txn)).unwrap();
+
+    // Transactions without keys are never deduplicated.
+    shaft(db, "alice", "bob", 10);
+    shaft(db, "alice", "bob", 10);
+
+    let transactions = block_on(db.get_last_transactions(10)).unwrap();
+    assert_eq!(transactions.len(), 3);
+    assert_eq!(transactions[2].idempotency_key.as_deref(), Some("abc"));
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        120
+    );
+}
+
+#[test]
+fn test_shaft_rejects_self_shaft() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    match block_on(db.shaft_user(transaction("alice", "alice", 100))) {
+        Err(DatabaseError::SelfShaft { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    assert!(block_on(db.get_last_transactions(10)).unwrap().is_empty());
+}
+
+#[test]
+fn test_token_format() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice"]);
+
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+    assert_eq!(token.as_str().len(), SqliteDatabase::TOKEN_LENGTH);
+    assert!(token.as_str().chars().all(|c| c.is_ascii_alphanumeric()));
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn test_in_memory_balances_match_sqlite() {
+    use shaft::db::InMemoryDatabase;
+
+    let test_db = setup_db();
+    let memory = InMemoryDatabase::new();
+
+    let databases: [&dyn Database; 2] = [&test_db.database, &memory];
+    for db in &databases {
+        for user_id in &["alice", "bob", "carol"] {
+            block_on(db.add_user_by_github_id((*user_id).into(), user_id.to_string())).unwrap();
+        }
+
+        block_on(db.shaft_user(transaction("alice", "bob", 100))).unwrap();
+        block_on(db.shaft_user(transaction("bob", "carol", 30))).unwrap();
+        block_on(db.shaft_user(transaction("carol", "alice", 45))).unwrap();
+        block_on(db.shaft_user(transaction("alice", "carol", 7))).unwrap();
+        block_on(db.soft_delete_transaction(4)).unwrap();
+
+        match block_on(db.shaft_user(transaction("alice", "dave", 1))) {
+            Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "dave"),
+            res => panic!("expected UnknownUser, got {:?}", res),
+        }
+    }
+
+    let balances = |db: &dyn Database| -> Vec<(String, i64)> {
+        block_on(db.get_all_users(false))
+            .unwrap()
+            .into_iter()
+            .map(|(user_id, user)| (user_id, user.balance()))
+            .collect()
+    };
+
+    assert_eq!(
+        balances(&memory),
+        vec![
+            ("bob".to_string(), -70),
+            ("carol".to_string(), 15),
+            ("alice".to_string(), 55),
+        ]
+    );
+    assert_eq!(balances(&memory), balances(&test_db.database));
+
+    let token = block_on(memory.create_token_for_user("alice".into())).unwrap();
+    let (user, _) = block_on(memory.get_user_from_token(token))
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.balance(), 55);
+}
+
+/// Times `shaft_user` in a tight loop, to check the effect of changes like
+/// statement caching. Run with
+/// `cargo test --release --test database bench_shaft_user -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_shaft_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    const ITERATIONS: u32 = 5000;
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        shaft(db, "alice", "bob", 1);
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "shaft_user: {:?} per call over {} calls",
+        elapsed / ITERATIONS,
+        ITERATIONS
+    );
+}
+
+#[test]
+fn test_balance_overflow() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    match block_on(db.shaft_user(transaction("alice", "bob", i64::MAX))) {
+        Err(DatabaseError::AmountTooLarge { max, .. }) => assert_eq!(max, MAX_AMOUNT),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Only unvalidated imports can get amounts this large in.
+    let transactions = vec![
+        transaction("alice", "bob", i64::MAX - 1),
+        transaction("alice", "bob", i64::MAX - 1),
+    ];
+    block_on(db.import_transactions(transactions, false)).unwrap();
+
+    match block_on(db.get_balance_for_user("alice".into())) {
+        Err(DatabaseError::BalanceOverflow { user_id }) => assert_eq!(user_id, "alice"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.get_balance_at("bob".into(), chrono::Utc::now())) {
+        Err(DatabaseError::BalanceOverflow { user_id }) => assert_eq!(user_id, "bob"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Shafting refuses to make things worse, and validating agrees.
+    let t = transaction("alice", "bob", 1);
+    match block_on(db.validate_shaft(&t)) {
+        Err(DatabaseError::BalanceOverflow { user_id }) => assert_eq!(user_id, "alice"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.shaft_user(t)) {
+        Err(DatabaseError::BalanceOverflow { user_id }) => assert_eq!(user_id, "alice"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_counterparties() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "alice", 30);
+    shaft(db, "carol", "alice", 200);
+    shaft(db, "bob", "carol", 50);
+
+    let counterparties = block_on(db.get_counterparties("alice".into())).unwrap();
+    assert_eq!(
+        counterparties
+            .iter()
+            .map(|(user, net)| (user.user_id(), *net))
+            .collect::<Vec<_>>(),
+        vec![("carol", -200), ("bob", 70)]
+    );
+    assert_eq!(counterparties[1].0.balance(), -20);
+
+    assert!(block_on(db.get_counterparties("dave".into()))
+        .unwrap()
+        .is_empty());
+    match block_on(db.get_counterparties("erin".into())) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "erin"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_validate_shaft() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    shaft(db, "alice", "bob", 100);
+
+    let preview = block_on(db.validate_shaft(&transaction("bob", "alice", 30))).unwrap();
+    assert_eq!(preview.shafter_balance_after, -70);
+    assert_eq!(preview.shaftee_balance_after, 70);
+
+    // Nothing was committed.
+    assert_eq!(block_on(db.get_last_transactions(10)).unwrap().len(), 1);
+
+    // Resubmissions don't change anything.
+    let mut t = transaction("alice", "bob", 50);
+    t.idempotency_key = Some("key".into());
+    block_on(db.shaft_user(t.clone())).unwrap();
+    let preview = block_on(db.validate_shaft(&t)).unwrap();
+    assert_eq!(preview.shafter_balance_after, 150);
+
+    match block_on(db.validate_shaft(&transaction("alice", "carol", 10))) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "carol"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.validate_shaft(&transaction("alice", "bob", -10))) {
+        Err(DatabaseError::InvalidAmount { amount }) => assert_eq!(amount, -10),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_shared_cpu_pool() {
+    let cpu_pool = CpuPool::new(1);
+
+    let test_dbs: Vec<_> = (0..2)
+        .map(|_| {
+            let suffix: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+            let path = std::env::temp_dir().join(format!("shaft-test-{}.db", suffix));
+
+            let database = SqliteDatabase::with_config_and_cpu_pool(
+                &path,
+                PoolConfig::default(),
+                cpu_pool.clone(),
+            )
+            .unwrap();
+            block_on(database.migrate()).unwrap();
+
+            TestDatabase { database, path }
+        })
+        .collect();
+
+    // The databases share threads but not data.
+    for (i, test_db) in test_dbs.iter().enumerate() {
+        add_users(&test_db.database, &["alice", "bob"]);
+        shaft(&test_db.database, "alice", "bob", 10 * (i as i64 + 1));
+    }
+    for (i, test_db) in test_dbs.iter().enumerate() {
+        assert_eq!(
+            block_on(test_db.database.get_balance_for_user("alice".into())).unwrap(),
+            10 * (i as i64 + 1)
+        );
+    }
+}
+
+#[test]
+fn test_get_transaction() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    let mut t = transaction("alice", "bob", 100);
+    t.category = Some("food".into());
+    block_on(db.shaft_user(t)).unwrap();
+    let id = block_on(db.get_last_transactions(1)).unwrap()[0]
+        .id
+        .unwrap();
+
+    let fetched = block_on(db.get_transaction(id)).unwrap().unwrap();
+    assert_eq!(fetched.id, Some(id));
+    assert_eq!(fetched.amount, 100);
+    assert_eq!(fetched.category.as_deref(), Some("food"));
+
+    assert!(block_on(db.get_transaction(id + 1)).unwrap().is_none());
+}
+
+#[test]
+fn test_get_all_users_tiebreak() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    for (user_id, display_name) in &[
+        ("u1", "carol"),
+        ("u2", "Bob"),
+        ("u3", "alice"),
+        ("u4", "bob"),
+    ] {
+        block_on(db.add_user_by_github_id((*user_id).into(), display_name.to_string())).unwrap();
+    }
+
+    let users = block_on(db.get_all_users(false)).unwrap();
+    assert_eq!(
+        users.keys().collect::<Vec<_>>(),
+        vec!["u3", "u2", "u4", "u1"]
+    );
+
+    let users = block_on(db.get_all_users_sorted(UserSort::BalanceDesc, false)).unwrap();
+    assert_eq!(
+        users.iter().map(|user| user.user_id()).collect::<Vec<_>>(),
+        vec!["u3", "u2", "u4", "u1"]
+    );
+}
+
+#[test]
+fn test_personal_ledger() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol", "dave"]);
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "carol", "alice", 200);
+    shaft(db, "alice", "dave", 10);
+    shaft(db, "dave", "alice", 10);
+
+    let ledger = block_on(db.get_personal_ledger("alice".into())).unwrap();
+    let ids = |list: &[(User, i64)]| {
+        list.iter()
+            .map(|(user, net)| (user.user_id().to_string(), *net))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&ledger.owed_to_me), vec![("bob".to_string(), 100)]);
+    assert_eq!(ids(&ledger.i_owe), vec![("carol".to_string(), -200)]);
+
+    match block_on(db.get_personal_ledger("erin".into())) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "erin"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_max_debt() {
+    let test_db = setup_db_with_config(PoolConfig {
+        max_debt: Some(100),
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let mut t = transaction("alice", "bob", 100);
+    t.idempotency_key = Some("key".into());
+    block_on(db.shaft_user(t.clone())).unwrap();
+
+    // Resubmitting is still a no-op, even though bob is at the limit.
+    block_on(db.shaft_user(t)).unwrap();
+
+    match block_on(db.shaft_user(transaction("alice", "bob", 1))) {
+        Err(DatabaseError::DebtLimitExceeded {
+            user_id,
+            balance_would_be,
+            limit,
+        }) => {
+            assert_eq!(user_id, "bob");
+            assert_eq!(balance_would_be, -101);
+            assert_eq!(limit, 100);
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match block_on(db.validate_shaft(&transaction("alice", "bob", 1))) {
+        Err(DatabaseError::DebtLimitExceeded { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Paying back is fine.
+    shaft(db, "bob", "alice", 50);
+    shaft(db, "alice", "bob", 50);
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -100
+    );
+}
+
+#[test]
+fn test_max_debt_batch() {
+    let test_db = setup_db_with_config(PoolConfig {
+        max_debt: Some(100),
+        ..PoolConfig::default()
+    });
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+
+    // Each shaft in a batch counts the ones before it, and a failure rolls
+    // back the whole batch.
+    match block_on(db.shaft_users(vec![
+        transaction("alice", "bob", 60),
+        transaction("carol", "bob", 60),
+    ])) {
+        Err(DatabaseError::DebtLimitExceeded {
+            user_id,
+            balance_would_be,
+            ..
+        }) => {
+            assert_eq!(user_id, "bob");
+            assert_eq!(balance_would_be, -120);
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(block_on(db.get_last_transactions(10)).unwrap().is_empty());
+
+    match block_on(db.shaft_many(
+        "alice".into(),
+        vec![("bob".into(), 101), ("carol".into(), 1)],
+        "pizza".into(),
+        Utc::now(),
+    )) {
+        Err(DatabaseError::DebtLimitExceeded { user_id, .. }) => assert_eq!(user_id, "bob"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let results = block_on(db.try_shaft_users(vec![
+        transaction("alice", "bob", 60),
+        transaction("carol", "bob", 60),
+        transaction("carol", "bob", 40),
+    ]))
+    .unwrap();
+    assert!(results[0].is_ok());
+    match &results[1] {
+        Err(DatabaseError::DebtLimitExceeded { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(results[2].is_ok());
+    assert_eq!(
+        block_on(db.get_balance_for_user("bob".into())).unwrap(),
+        -100
+    );
+
+    // Composed shafts are checked too.
+    let shafter = db.clone();
+    match block_on(
+        db.in_transaction(move |conn| shafter.shaft_user_in(conn, transaction("alice", "bob", 1))),
+    ) {
+        Err(DatabaseError::DebtLimitExceeded { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_dropped_shaft_does_not_commit() {
+    let test_db = setup_db();
+    let cpu_pool = CpuPool::new(1);
+    let db = SqliteDatabase::with_config_and_cpu_pool(
+        &test_db.path,
+        PoolConfig::default(),
+        cpu_pool.clone(),
+    )
+    .unwrap();
+
+    add_users(&db, &["alice", "bob"]);
+
+    // Hold the only thread until the shaft's future has been dropped.
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let _blocker = cpu_pool.spawn_fn(move || {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+        Ok::<(), ()>(())
+    });
+    started_rx.recv().unwrap();
+
+    drop(db.shaft_user(transaction("alice", "bob", 100)));
+    release_tx.send(()).unwrap();
+
+    assert!(block_on(db.get_last_transactions(10)).unwrap().is_empty());
+}
+
+#[test]
+fn test_daily_activity() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let now = Utc::now();
+    let yesterday = now - chrono::Duration::days(1);
+    for (datetime, amount) in &[
+        (now, 10),
+        (now, 5),
+        (yesterday, 20),
+        (now - chrono::Duration::days(40), 100),
+    ] {
+        let mut t = transaction("alice", "bob", *amount);
+        t.datetime = *datetime;
+        block_on(db.shaft_user(t)).unwrap();
+    }
+    shaft(db, "bob", "alice", 1000);
+
+    let activity = block_on(db.get_daily_activity("alice".into(), 30)).unwrap();
+    assert_eq!(
+        activity,
+        vec![
+            (yesterday.naive_utc().date(), 20),
+            (now.naive_utc().date(), 15)
+        ]
+    );
+
+    let activity = block_on(db.get_daily_activity("alice".into(), 1)).unwrap();
+    assert_eq!(activity, vec![(now.naive_utc().date(), 15)]);
+
+    assert!(block_on(db.get_daily_activity("alice".into(), 0))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_count_transactions_for_user() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    shaft(db, "alice", "bob", 1);
+    shaft(db, "alice", "carol", 1);
+    shaft(db, "bob", "alice", 1);
+    shaft(db, "bob", "carol", 1);
+
+    for &(direction, count) in &[
+        (TransactionDirection::Sent, 2),
+        (TransactionDirection::Received, 1),
+        (TransactionDirection::Both, 3),
+    ] {
+        assert_eq!(
+            block_on(db.count_transactions_for_user("alice".into(), direction)).unwrap(),
+            count
+        );
+    }
+    assert_eq!(
+        block_on(db.count_transactions_for_user("erin".into(), TransactionDirection::Both))
+            .unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_shutdown() {
+    let mut test_db = setup_db();
+    add_users(&test_db.database, &["alice", "bob"]);
+
+    let clone = test_db.database.clone();
+    match block_on(clone.shutdown()) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // The shaft has started, so shutting down waits for it.
+    let db = std::mem::replace(
+        &mut test_db.database,
+        SqliteDatabase::with_path(&test_db.path).unwrap(),
+    );
+    let shafted = db.shaft_user(transaction("alice", "bob", 10));
+    block_on(db.shutdown()).unwrap();
+    block_on(shafted).unwrap();
+
+    assert_eq!(
+        block_on(test_db.database.get_balance_for_user("alice".into())).unwrap(),
+        10
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_export_import_json() {
+    let source = setup_db();
+    let db = &source.database;
+
+    add_users(db, &["alice", "bob", "carol"]);
+    db.run_statements("INSERT INTO user_teams (user_id, team) VALUES ('alice', 'red')")
+        .unwrap();
+    shaft(db, "alice", "bob", 100);
+    shaft(db, "bob", "carol", 30);
+    shaft(db, "carol", "alice", 5);
+    let ids: Vec<_> = block_on(db.get_last_transactions(10))
+        .unwrap()
+        .into_iter()
+        .map(|txn| txn.id.unwrap())
+        .collect();
+    block_on(db.delete_transaction(ids[0])).unwrap();
+    block_on(db.reverse_transaction(ids[1], "oops".to_string())).unwrap();
+    block_on(db.add_attachment(ids[2], "https://example.com/receipt.png".to_string())).unwrap();
+    let token = block_on(db.create_token_for_user("alice".into())).unwrap();
+
+    let without_tokens: serde_json::Value =
+        serde_json::from_str(&block_on(db.export_json(false)).unwrap()).unwrap();
+    assert_eq!(without_tokens["tokens"], serde_json::json!([]));
+    assert_eq!(without_tokens["users"].as_array().unwrap().len(), 3);
+    assert_eq!(without_tokens["transactions"].as_array().unwrap().len(), 4);
+
+    let backup = block_on(db.export_json(true)).unwrap();
+
+    let target = setup_db();
+    let restored = &target.database;
+    block_on(restored.import_json(&backup)).unwrap();
+
+    for user_id in &["alice", "bob", "carol"] {
+        assert_eq!(
+            block_on(restored.get_balance_for_user((*user_id).into())).unwrap(),
+            block_on(db.get_balance_for_user((*user_id).into())).unwrap(),
+        );
+    }
+    assert_eq!(
+        block_on(restored.get_team_balances())
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec![("red".to_string(), 100)]
+    );
+    assert_eq!(block_on(restored.get_attachments(ids[2])).unwrap().len(), 1);
+    assert_eq!(
+        block_on(restored.resolve_token(token)).unwrap(),
+        Some("alice".into())
+    );
+    // The deleted shaft and the reversal came across too, so the backups
+    // match and new IDs carry on from the restored ones.
+    assert_eq!(block_on(restored.export_json(true)).unwrap(), backup);
+    shaft(restored, "alice", "bob", 1);
+    let txn = block_on(restored.get_last_transactions(1))
+        .unwrap()
+        .remove(0);
+    assert_eq!(txn.id, Some(ids[0] + 2));
+
+    match block_on(restored.import_json(&backup)) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("expected InvalidInput, got {:?}", res),
+    }
+
+    let mut dangling: serde_json::Value = serde_json::from_str(&backup).unwrap();
+    dangling["users"].as_array_mut().unwrap().remove(0);
+    let empty = setup_db();
+    match block_on(empty.database.import_json(&dangling.to_string())) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("expected InvalidInput, got {:?}", res),
+    }
+    assert!(block_on(empty.database.get_all_users(true))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_import_transactions() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+
+    let mut transactions: Vec<_> = (1..=250).map(|_| transaction("alice", "bob", 2)).collect();
+    transactions[0].idempotency_key = Some("row-1".into());
+    transactions[1].idempotency_key = Some("row-1".into());
+
+    let inserted = block_on(db.import_transactions(transactions, true)).unwrap();
+    assert_eq!(inserted, 249);
+    assert_eq!(
+        block_on(db.get_balance_for_user("alice".into())).unwrap(),
+        498
+    );
+
+    // Validation rejects the whole import.
+    let transactions = vec![
+        transaction("alice", "bob", 1),
+        transaction("alice", "carol", 1),
+    ];
+    match block_on(db.import_transactions(transactions.clone(), true)) {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "carol"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // ... unless it's trusted.
+    assert_eq!(
+        block_on(db.import_transactions(transactions, false)).unwrap(),
+        2
+    );
+    assert_eq!(block_on(db.import_transactions(vec![], true)).unwrap(), 0);
+}
+
+#[test]
+fn test_transaction_categories() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    add_users(db, &["alice", "bob"]);
+    for (amount, category) in &[
+        (100, Some("food")),
+        (50, Some("drinks")),
+        (20, Some("food")),
+    ] {
+        let mut t = transaction("alice", "bob", *amount);
+        t.category = category.map(String::from);
+        block_on(db.shaft_user(t)).unwrap();
+    }
+    shaft(db, "bob", "alice", 10);
+
+    let food = block_on(db.get_transactions_by_category("food".into(), 10)).unwrap();
+    assert_eq!(
+        food.iter().map(|t| t.amount).collect::<Vec<_>>(),
+        vec![20, 100]
+    );
+    assert_eq!(food[0].category.as_deref(), Some("food"));
+
+    let drinks_id = block_on(db.get_transactions_by_category("drinks".into(), 10)).unwrap()[0]
+        .id
+        .unwrap();
+    block_on(db.reverse_transaction(drinks_id, "Oops".into())).unwrap();
+
+    let totals = block_on(db.get_category_totals()).unwrap();
+    assert_eq!(
+        totals.into_iter().collect::<Vec<_>>(),
+        vec![("food".to_string(), 120), ("".to_string(), 10)]
+    );
+
+    let mut t = transaction("alice", "bob", 1);
+    t.category = Some(" ".into());
+    match block_on(db.shaft_user(t)) {
+        Err(DatabaseError::InvalidInput { .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+/// Checks the balance and token queries use their indexes on a large
+/// table, and times the balance query. Run with
+/// `cargo test --release --test database bench_balance_query -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_balance_query() {
+    let test_db = setup_db();
+    let db = &test_db.database;
+
+    let user_ids: Vec<String> = (0..100).map(|i| format!("user{}", i)).collect();
+    add_users(db, &user_ids.iter().map(String::as_str).collect::<Vec<_>>());
+
+    let transactions = (0..100_000)
+        .map(|i| transaction(&user_ids[i % 100], &user_ids[(i * 7 + 1) % 100], 1))
+        .collect();
+    block_on(db.import_transactions(transactions, false)).unwrap();
+
+    let plans: HashMap<_, _> = db.query_plans().unwrap().into_iter().collect();
+    let uses_index = |query: &str, index: &str| {
+        let plan = &plans[query];
+        assert!(
+            plan.iter().any(|step| step.starts_with("SEARCH")
+                && step.contains(&format!("USING INDEX {}", index))),
+            "Unexpected plan for {}: {:?}",
+            query,
+            plan
+        );
+    };
+    uses_index("get_balance_for_user", "transactions_shafter");
+    uses_index("get_balance_for_user", "transactions_shaftee");
+    uses_index("get_user_from_token", "tokens_token");
+
+    const ITERATIONS: u32 = 100;
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        block_on(db.get_balance_for_user("user0".into())).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "get_balance_for_user: {:?} per call over {} calls",
+        elapsed / ITERATIONS,
+        ITERATIONS
+    );
+}
@@ -16,10 +16,15 @@ use shaft::github::{HttpError, MockGenericHttpClient};
 use shaft::rest::{register_servlets, AppConfig, AppState, AuthenticateUser, MiddlewareLogger};
 
 const SCHEMA: &str = r#"
-    CREATE TABLE tokens ( user_id TEXT NOT NULL, token TEXT NOT NULL );
-    CREATE TABLE github_users (user_id text primary key not null, github_id text not null);
-    CREATE TABLE users ( user_id TEXT NOT NULL UNIQUE, display_name TEXT );
-    CREATE TABLE IF NOT EXISTS "transactions" (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT NOT NULL);
+    CREATE TABLE tokens ( user_id TEXT NOT NULL, token TEXT NOT NULL, scope TEXT NOT NULL DEFAULT 'write', last_used_at BIGINT, created_at BIGINT, expires_at BIGINT );
+    CREATE TABLE identities ( provider TEXT NOT NULL, provider_id TEXT NOT NULL, user_id TEXT NOT NULL, PRIMARY KEY (provider, provider_id) );
+    CREATE TABLE users ( user_id TEXT NOT NULL UNIQUE, display_name TEXT, created_at BIGINT, display_name_overridden BOOLEAN NOT NULL DEFAULT 0, active BOOLEAN NOT NULL DEFAULT 1, version INTEGER NOT NULL DEFAULT 0 );
+    CREATE TABLE IF NOT EXISTS "transactions" (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT, deleted_at BIGINT, reversed_transaction_id BIGINT, idempotency_key TEXT UNIQUE, category TEXT);
+    CREATE TABLE user_teams ( user_id TEXT NOT NULL UNIQUE, team TEXT NOT NULL );
+    CREATE TABLE attachments ( id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, transaction_id BIGINT NOT NULL, url TEXT NOT NULL, uploaded_at BIGINT NOT NULL );
+    CREATE INDEX users_display_name ON users (display_name COLLATE NOCASE);
+    CREATE TABLE settings ( id INTEGER PRIMARY KEY CHECK (id = 1), currency_symbol TEXT NOT NULL, decimal_places INTEGER NOT NULL );
+    INSERT INTO settings (id, currency_symbol, decimal_places) VALUES (1, '£', 2);
 "#;
 
 fn setup_app(http_client: Option<MockGenericHttpClient>) -> (test::TestServer, AppState) {
@@ -32,7 +37,7 @@ fn setup_app(http_client: Option<MockGenericHttpClient>) -> (test::TestServer, A
         resource_dir: "res".to_owned(),
     };
 
-    let database = SqliteDatabase::with_path(":memory:");
+    let database = SqliteDatabase::with_path(":memory:").unwrap();
     database.run_statements(SCHEMA).unwrap();
 
     let mock_http_client = http_client.unwrap_or_default();